@@ -0,0 +1,230 @@
+//! Parser for ANSI/VT100 escape sequences.
+//!
+//! Bytes are fed in one at a time and come out as [`Action`]s for the console to
+//! carry out. Only the subset of ECMA-48 we actually interpret is recognised:
+//! C0 controls, two byte `ESC x` sequences and CSI sequences with numeric
+//! parameters. Anything else is swallowed.
+
+/// Most CSI parameters we keep; further ones are dropped.
+pub const MAX_PARAMS: usize = 16;
+
+const ESC: u8 = 0x1b;
+/// CAN and SUB abort an escape sequence in progress.
+const CAN: u8 = 0x18;
+const SUB: u8 = 0x1a;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Csi {
+    params: [u16; MAX_PARAMS],
+    len: usize,
+    /// Whether the sequence had a private marker such as `?`.
+    pub private: bool,
+    pub final_byte: u8,
+}
+
+impl Csi {
+    /// All parameters that were present. Omitted parameters read as 0.
+    pub fn params(&self) -> &[u16] {
+        &self.params[..self.len]
+    }
+
+    /// Parameter `i`, or `default` if it was omitted or 0.
+    pub fn param(&self, i: usize, default: u16) -> u16 {
+        match self.params().get(i) {
+            Some(&p) if p != 0 => p,
+            _ => default,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    /// A byte to display.
+    Print(u8),
+    /// A C0 control character such as `\n` or backspace.
+    Control(u8),
+    /// A two byte `ESC x` sequence, e.g. `ESC 7` to save the cursor.
+    Esc(u8),
+    Csi(Csi),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Ground,
+    Escape,
+    /// Skipping the intermediate bytes of an escape sequence we don't support.
+    EscapeIntermediate,
+    CsiParam,
+    /// Skipping the rest of a malformed CSI sequence.
+    CsiIgnore,
+}
+
+pub struct Parser {
+    state: State,
+    csi: Csi,
+}
+
+impl Default for Parser {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Parser {
+    pub const fn new() -> Self {
+        Self {
+            state: State::Ground,
+            csi: Csi {
+                params: [0; MAX_PARAMS],
+                len: 0,
+                private: false,
+                final_byte: 0,
+            },
+        }
+    }
+
+    /// Feed the next byte of output through the parser.
+    pub fn advance(&mut self, byte: u8) -> Option<Action> {
+        match (self.state, byte) {
+            (_, ESC) => {
+                self.state = State::Escape;
+                None
+            }
+            (State::Ground, _) => match byte {
+                0x00..=0x1f | 0x7f => Some(Action::Control(byte)),
+                _ => Some(Action::Print(byte)),
+            },
+            (_, CAN) | (_, SUB) => {
+                self.state = State::Ground;
+                None
+            }
+            // Other C0 controls are carried out even in the middle of a sequence
+            (_, 0x00..=0x1f) => Some(Action::Control(byte)),
+            (State::Escape, b'[') => {
+                self.state = State::CsiParam;
+                self.csi.params = [0; MAX_PARAMS];
+                self.csi.len = 0;
+                self.csi.private = false;
+                None
+            }
+            (State::Escape, 0x20..=0x2f) => {
+                self.state = State::EscapeIntermediate;
+                None
+            }
+            (State::Escape, 0x30..=0x7e) => {
+                self.state = State::Ground;
+                Some(Action::Esc(byte))
+            }
+            (State::EscapeIntermediate, 0x30..=0x7e) => {
+                self.state = State::Ground;
+                None
+            }
+            (State::CsiParam, _) => self.csi_param(byte),
+            (State::CsiIgnore, 0x40..=0x7e) => {
+                self.state = State::Ground;
+                None
+            }
+            _ => None,
+        }
+    }
+
+    fn csi_param(&mut self, byte: u8) -> Option<Action> {
+        match byte {
+            b'0'..=b'9' => {
+                if self.csi.len == 0 {
+                    self.csi.len = 1;
+                }
+                if let Some(p) = self.csi.params.get_mut(self.csi.len - 1) {
+                    *p = p.saturating_mul(10).saturating_add((byte - b'0') as u16);
+                }
+                None
+            }
+            b';' => {
+                // An empty leading parameter still counts
+                self.csi.len = (self.csi.len.max(1) + 1).min(MAX_PARAMS + 1);
+                None
+            }
+            b'<'..=b'?' if self.csi.len == 0 && !self.csi.private => {
+                self.csi.private = true;
+                None
+            }
+            0x40..=0x7e => {
+                self.state = State::Ground;
+                let mut csi = self.csi;
+                csi.len = csi.len.min(MAX_PARAMS);
+                csi.final_byte = byte;
+                Some(Action::Csi(csi))
+            }
+            _ => {
+                self.state = State::CsiIgnore;
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+fn parse_all(bytes: &[u8], out: &mut [Option<Action>]) -> usize {
+    let mut parser = Parser::new();
+    let mut n = 0;
+    for &b in bytes {
+        if let Some(action) = parser.advance(b) {
+            out[n] = Some(action);
+            n += 1;
+        }
+    }
+    n
+}
+
+#[test]
+fn test_plain_text_and_controls() {
+    let mut out = [None; 4];
+    assert_eq!(parse_all(b"a\nb", &mut out), 3);
+    assert_eq!(out[0], Some(Action::Print(b'a')));
+    assert_eq!(out[1], Some(Action::Control(b'\n')));
+    assert_eq!(out[2], Some(Action::Print(b'b')));
+}
+
+#[test]
+fn test_csi_params() {
+    let mut out = [None; 2];
+    assert_eq!(parse_all(b"\x1b[1;31mx", &mut out), 2);
+    match out[0] {
+        Some(Action::Csi(csi)) => {
+            assert_eq!(csi.final_byte, b'm');
+            assert_eq!(csi.params(), &[1, 31]);
+            assert!(!csi.private);
+        }
+        other => panic!("expected CSI, got {:?}", other),
+    }
+    assert_eq!(out[1], Some(Action::Print(b'x')));
+}
+
+#[test]
+fn test_csi_defaults_and_private() {
+    let mut out = [None; 2];
+    assert_eq!(parse_all(b"\x1b[;5H\x1b[?25l", &mut out), 2);
+    match out[0] {
+        Some(Action::Csi(csi)) => {
+            assert_eq!(csi.params(), &[0, 5]);
+            assert_eq!(csi.param(0, 1), 1);
+            assert_eq!(csi.param(1, 1), 5);
+        }
+        other => panic!("expected CSI, got {:?}", other),
+    }
+    match out[1] {
+        Some(Action::Csi(csi)) => {
+            assert!(csi.private);
+            assert_eq!(csi.final_byte, b'l');
+        }
+        other => panic!("expected CSI, got {:?}", other),
+    }
+}
+
+#[test]
+fn test_esc_sequences_and_abort() {
+    let mut out = [None; 2];
+    assert_eq!(parse_all(b"\x1b7\x1b[12\x18z", &mut out), 2);
+    assert_eq!(out[0], Some(Action::Esc(b'7')));
+    assert_eq!(out[1], Some(Action::Print(b'z')));
+}
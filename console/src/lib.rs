@@ -0,0 +1,12 @@
+//! Decoding of the bytes written to the console.
+//!
+//! The escape sequence parser and UTF-8 decoder only look at the bytes they're fed,
+//! so they're kept out of the kernel crate to be tested on the host and fuzzed:
+//!
+//! ```text
+//! cargo test-console
+//! ```
+#![cfg_attr(not(test), no_std)]
+
+pub mod ansi;
+pub mod utf8;
@@ -0,0 +1,112 @@
+//! Incremental UTF-8 decoder for byte-at-a-time console output.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InvalidSequence;
+
+pub struct Decoder {
+    code_point: u32,
+    /// Continuation bytes still expected.
+    remaining: u8,
+    /// Smallest code point the current sequence length may encode, to reject
+    /// overlong encodings.
+    min: u32,
+}
+
+impl Default for Decoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Decoder {
+    pub const fn new() -> Self {
+        Self {
+            code_point: 0,
+            remaining: 0,
+            min: 0,
+        }
+    }
+
+    /// Whether we're partway through a multi-byte sequence.
+    pub fn pending(&self) -> bool {
+        self.remaining > 0
+    }
+
+    /// Abandon any partially decoded sequence.
+    pub fn reset(&mut self) {
+        self.remaining = 0;
+    }
+
+    /// Feed the next byte, returning a result once a sequence is complete.
+    pub fn push(&mut self, byte: u8) -> Option<Result<char, InvalidSequence>> {
+        if self.remaining == 0 {
+            let (bits, remaining, min) = match byte {
+                0x00..=0x7f => return Some(Ok(byte as char)),
+                0xc2..=0xdf => (byte & 0x1f, 1, 0x80),
+                0xe0..=0xef => (byte & 0x0f, 2, 0x800),
+                0xf0..=0xf4 => (byte & 0x07, 3, 0x10000),
+                _ => return Some(Err(InvalidSequence)),
+            };
+            self.code_point = bits as u32;
+            self.remaining = remaining;
+            self.min = min;
+            return None;
+        }
+
+        if byte & 0xc0 != 0x80 {
+            self.remaining = 0;
+            return Some(Err(InvalidSequence));
+        }
+        self.code_point = self.code_point << 6 | (byte & 0x3f) as u32;
+        self.remaining -= 1;
+        if self.remaining > 0 {
+            return None;
+        }
+        if self.code_point < self.min {
+            return Some(Err(InvalidSequence));
+        }
+        // Rejects surrogates and anything past U+10FFFF
+        Some(core::char::from_u32(self.code_point).ok_or(InvalidSequence))
+    }
+}
+
+#[cfg(test)]
+fn decode_one(bytes: &[u8]) -> Option<Result<char, InvalidSequence>> {
+    let mut decoder = Decoder::new();
+    let mut result = None;
+    for &b in bytes {
+        result = decoder.push(b);
+    }
+    result
+}
+
+#[test]
+fn test_decode_valid() {
+    assert_eq!(decode_one(b"a"), Some(Ok('a')));
+    assert_eq!(decode_one("é".as_bytes()), Some(Ok('é')));
+    assert_eq!(decode_one("│".as_bytes()), Some(Ok('│')));
+    assert_eq!(decode_one("😀".as_bytes()), Some(Ok('😀')));
+}
+
+#[test]
+fn test_decode_pending() {
+    let mut decoder = Decoder::new();
+    assert_eq!(decoder.push(0xe2), None);
+    assert!(decoder.pending());
+    assert_eq!(decoder.push(0x94), None);
+    assert_eq!(decoder.push(0x82), Some(Ok('│')));
+    assert!(!decoder.pending());
+}
+
+#[test]
+fn test_decode_invalid() {
+    // Overlong encoding of '/'
+    assert_eq!(decode_one(&[0xc0, 0xaf]), Some(Err(InvalidSequence)));
+    assert_eq!(decode_one(&[0xe0, 0x80, 0xaf]), Some(Err(InvalidSequence)));
+    // UTF-16 surrogate
+    assert_eq!(decode_one(&[0xed, 0xa0, 0x80]), Some(Err(InvalidSequence)));
+    // Stray continuation byte
+    assert_eq!(decode_one(&[0x80]), Some(Err(InvalidSequence)));
+    // Truncated sequence
+    assert_eq!(decode_one(&[0xe2, 0x41]), Some(Err(InvalidSequence)));
+}
@@ -0,0 +1,20 @@
+#![no_main]
+
+//! Feed console output through the escape sequence parser.
+
+use firstos_console::ansi::{Action, Parser, MAX_PARAMS};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|bytes: &[u8]| {
+    let mut parser = Parser::new();
+    for &byte in bytes {
+        match parser.advance(byte) {
+            Some(Action::Csi(csi)) => {
+                assert!(csi.params().len() <= MAX_PARAMS);
+                assert!((0x40..=0x7e).contains(&csi.final_byte));
+            }
+            Some(Action::Print(b)) => assert!(b >= 0x20 && b != 0x7f),
+            _ => {}
+        }
+    }
+});
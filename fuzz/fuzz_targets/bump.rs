@@ -0,0 +1,16 @@
+#![no_main]
+
+use core::alloc::{GlobalAlloc, Layout};
+use firstos_fuzz::{Op, HEAP_SIZE};
+use firstos_heap::{bump::Allocator, testing::Arena, Locked};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|ops: Vec<Op>| {
+    let arena = Arena::new(HEAP_SIZE);
+    let allocator = Locked::new(Allocator::empty());
+    unsafe { allocator.lock().init(arena.start(), arena.size()) };
+    firstos_fuzz::run(&allocator, &arena, &ops, |_, _| {});
+    // With everything freed the whole heap is available again
+    let whole = Layout::from_size_align(arena.size(), 4096).unwrap();
+    assert_eq!(unsafe { allocator.alloc(whole) } as usize, arena.start());
+});
@@ -0,0 +1,82 @@
+#![no_main]
+
+//! Mount a disk image and read everything on it.
+//!
+//! The driver is built straight from the kernel's source, as it only needs `alloc`,
+//! along with the block device layer it's written against.
+
+extern crate alloc;
+
+#[path = "../../src/block/mod.rs"]
+#[allow(dead_code)]
+mod block;
+#[path = "../../src/fs/ext2.rs"]
+#[allow(dead_code)]
+mod ext2;
+
+use block::{BlockDevice, RamDisk};
+use ext2::{Ext2, FileType, Inode};
+use libfuzzer_sys::fuzz_target;
+
+const SECTOR_SIZE: usize = 512;
+/// Directory entries looked at per image, to keep runs short.
+const MAX_ENTRIES: usize = 256;
+const MAX_DEPTH: usize = 8;
+
+fuzz_target!(|image: &[u8]| {
+    let sectors = (image.len() + SECTOR_SIZE - 1) / SECTOR_SIZE;
+    let mut disk = RamDisk::new(SECTOR_SIZE, sectors);
+    for (i, chunk) in image.chunks(SECTOR_SIZE).enumerate() {
+        let mut sector = [0; SECTOR_SIZE];
+        sector[..chunk.len()].copy_from_slice(chunk);
+        disk.write_block(i as u64, &sector).unwrap();
+    }
+
+    let mut fs = match Ext2::mount(disk) {
+        Ok(fs) => fs,
+        Err(_) => return,
+    };
+    if let Ok(root) = fs.read_inode(ext2::ROOT_INODE) {
+        let mut budget = MAX_ENTRIES;
+        walk(&mut fs, &root, "", 0, &mut budget);
+    }
+});
+
+fn walk(fs: &mut Ext2<RamDisk>, dir: &Inode, path: &str, depth: usize, budget: &mut usize) {
+    if depth > MAX_DEPTH {
+        return;
+    }
+    let entries = match fs.read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+    for entry in entries {
+        if *budget == 0 {
+            return;
+        }
+        *budget -= 1;
+        if entry.name == "." || entry.name == ".." {
+            continue;
+        }
+        let path = format!("{}/{}", path, entry.name);
+        let inode = match fs.read_inode(entry.inode) {
+            Ok(inode) => inode,
+            Err(_) => continue,
+        };
+        // Through the path too, which follows symlinks
+        let _ = fs.open(&path);
+        let _ = fs.open_nofollow(&path);
+        match inode.file_type() {
+            FileType::Directory => walk(fs, &inode, &path, depth + 1, budget),
+            FileType::Symlink => {
+                let _ = fs.read_link(&inode);
+            }
+            _ => {
+                // The start and end of the file, which might be holes
+                let mut buf = [0; 4096];
+                let _ = fs.read(&inode, 0, &mut buf);
+                let _ = fs.read(&inode, inode.size.saturating_sub(10), &mut buf);
+            }
+        }
+    }
+}
@@ -0,0 +1,17 @@
+#![no_main]
+
+use firstos_fuzz::{Op, HEAP_SIZE};
+use firstos_heap::{fixed_size_block::Allocator, testing::Arena, Locked};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|ops: Vec<Op>| {
+    let arena = Arena::new(HEAP_SIZE);
+    let allocator = Locked::new(Allocator::empty());
+    unsafe { allocator.lock().init(arena.start(), arena.size()) };
+    firstos_fuzz::run(&allocator, &arena, &ops, |_, _| {});
+    // With everything freed every page should have gone back and merged
+    let mut allocator = allocator.lock();
+    allocator.trim();
+    let regions: Vec<_> = allocator.fallback().free_regions().collect();
+    assert_eq!(regions, [(arena.start(), arena.size())]);
+});
@@ -0,0 +1,17 @@
+#![no_main]
+
+use firstos_fuzz::{Op, HEAP_SIZE};
+use firstos_heap::{linked_list::Allocator, testing::Arena, Locked};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|ops: Vec<Op>| {
+    let arena = Arena::new(HEAP_SIZE);
+    let allocator = Locked::new(Allocator::empty());
+    unsafe { allocator.lock().init(arena.start(), arena.size()) };
+    firstos_fuzz::run(&allocator, &arena, &ops, |allocator, model| {
+        model.check_free_regions(allocator.lock().free_regions());
+    });
+    // With everything freed it should all have merged back together
+    let regions: Vec<_> = allocator.lock().free_regions().collect();
+    assert_eq!(regions, [(arena.start(), arena.size())]);
+});
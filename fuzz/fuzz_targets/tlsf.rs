@@ -0,0 +1,21 @@
+#![no_main]
+
+use firstos_fuzz::{Op, HEAP_SIZE};
+use firstos_heap::{testing::Arena, tlsf::Allocator, Locked};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|ops: Vec<Op>| {
+    let arena = Arena::new(HEAP_SIZE);
+    let allocator = Locked::new(Allocator::empty());
+    unsafe { allocator.lock().init(arena.start(), arena.size()) };
+    firstos_fuzz::run(&allocator, &arena, &ops, |allocator, model| {
+        model.check_free_regions(allocator.lock().free_regions());
+    });
+    // With everything freed it should all have merged back into one block, short of
+    // the headers at either end
+    let regions: Vec<_> = allocator.lock().free_regions().collect();
+    assert_eq!(regions.len(), 1);
+    let (start, size) = regions[0];
+    assert!(start > arena.start() && start + size < arena.end());
+    assert!(size >= arena.size() - 64);
+});
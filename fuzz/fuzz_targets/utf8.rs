@@ -0,0 +1,28 @@
+#![no_main]
+
+//! Check the console's incremental UTF-8 decoder against `core::str::from_utf8`.
+
+use firstos_console::utf8::Decoder;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|bytes: &[u8]| {
+    let mut decoder = Decoder::new();
+    let mut decoded = String::new();
+    let mut valid = true;
+    for &byte in bytes {
+        match decoder.push(byte) {
+            Some(Ok(c)) => decoded.push(c),
+            Some(Err(_)) => valid = false,
+            None => {}
+        }
+    }
+    valid &= !decoder.pending();
+
+    match core::str::from_utf8(bytes) {
+        Ok(s) => {
+            assert!(valid, "rejected valid UTF-8");
+            assert_eq!(decoded, s);
+        }
+        Err(_) => assert!(!valid, "accepted invalid UTF-8"),
+    }
+});
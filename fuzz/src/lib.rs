@@ -0,0 +1,68 @@
+//! Fuzz targets for the heap allocators and the kernel's parsers of untrusted bytes.
+//!
+//! Run them from this directory with [cargo-fuzz], so that `.cargo/config.toml` here
+//! points the build at the host instead of the kernel's target:
+//!
+//! ```text
+//! cargo fuzz run --build-std linked_list
+//! ```
+//!
+//! This library holds what the allocator targets share: they all carry out a sequence
+//! of [`Op`]s, checked against a `firstos_heap::testing::Model`.
+//!
+//! [cargo-fuzz]: https://github.com/rust-fuzz/cargo-fuzz
+
+use arbitrary::Arbitrary;
+use core::alloc::{GlobalAlloc, Layout};
+use firstos_heap::testing::{Arena, Model};
+
+/// Small enough to run out of now and then.
+pub const HEAP_SIZE: usize = 64 * 1024;
+
+#[derive(Arbitrary, Debug)]
+pub enum Op {
+    Alloc {
+        size: u16,
+        align: u8,
+    },
+    /// Free a live allocation, picked by index modulo how many there are.
+    Free {
+        index: u8,
+    },
+    Realloc {
+        index: u8,
+        size: u16,
+    },
+}
+
+fn layout(size: u16, align: u8) -> Layout {
+    // Up to 4 KiB alignment, as for page tables
+    Layout::from_size_align(size.max(1) as usize, 1 << (align % 13)).unwrap()
+}
+
+/// Carry out `ops` on `allocator` and then free whatever's left, checking every
+/// allocation and calling `check` after each step.
+pub fn run<A: GlobalAlloc>(allocator: &A, arena: &Arena, ops: &[Op], check: impl Fn(&A, &Model)) {
+    let mut model = Model::new(arena);
+    for op in ops {
+        match *op {
+            Op::Alloc { size, align } => {
+                model.alloc(allocator, layout(size, align));
+            }
+            Op::Free { index } if !model.is_empty() => {
+                model.free(allocator, index as usize % model.len());
+            }
+            Op::Realloc { index, size } if !model.is_empty() => {
+                model.realloc(
+                    allocator,
+                    index as usize % model.len(),
+                    size.max(1) as usize,
+                );
+            }
+            _ => {}
+        }
+        check(allocator, &model);
+    }
+    model.free_all(allocator);
+    check(allocator, &model);
+}
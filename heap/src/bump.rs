@@ -1,7 +1,10 @@
 use super::locked::Locked;
-use alloc::alloc::{GlobalAlloc, Layout};
+use core::alloc::{GlobalAlloc, Layout};
 use core::ptr;
 
+#[cfg(test)]
+use crate::testing::{arena_allocator, check_random_workload, Arena};
+
 pub struct Allocator {
     heap_start: usize,
     heap_end: usize,
@@ -11,7 +14,6 @@ pub struct Allocator {
 
 impl Allocator {
     /// Create a new empty bump allocator
-    #[allow(dead_code)]
     pub const fn empty() -> Self {
         Allocator {
             heap_start: 0,
@@ -26,7 +28,6 @@ impl Allocator {
     /// # Safety
     ///
     /// The caller must ensure that the given memory range is unused.
-    #[allow(dead_code)]
     pub unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
         self.heap_start = heap_start;
         self.heap_end = heap_start + heap_size;
@@ -56,11 +57,42 @@ unsafe impl GlobalAlloc for Locked<Allocator> {
         let mut bump = self.lock();
         bump.allocations -= 1;
         let alloc_start = ptr as usize;
-        if bump.next == alloc_start {
-            // If this was the last allocation we can reuse it immediately
-            bump.next = bump.next + layout.size();
-        } else if bump.allocations == 0 {
+        if bump.allocations == 0 {
             bump.next = bump.heap_end;
+        } else if bump.next == alloc_start {
+            // If this was the last allocation we can reuse it immediately
+            bump.next += layout.size();
         }
     }
 }
+
+#[test]
+fn test_bump_reuses_last_allocation() {
+    let arena = Arena::new(4096);
+    let alloc = arena_allocator::<Allocator>(&arena);
+    let l = Layout::from_size_align(24, 8).unwrap();
+    unsafe {
+        let p1 = alloc.alloc(l);
+        let p2 = alloc.alloc(l);
+        assert_eq!(p2 as usize + 24, p1 as usize);
+        alloc.dealloc(p2, l);
+        assert_eq!(alloc.alloc(l), p2);
+    }
+}
+
+#[test]
+fn test_bump_random_workload() {
+    check_random_workload(
+        64,
+        500,
+        8192,
+        arena_allocator::<Allocator>,
+        |_, _| {},
+        |alloc, arena, seed| {
+            // With everything freed, all of it can be used again
+            let whole = Layout::from_size_align(arena.size(), 4096).unwrap();
+            let p = unsafe { alloc.alloc(whole) };
+            assert_eq!(p as usize, arena.start(), "seed {}", seed);
+        },
+    );
+}
@@ -0,0 +1,548 @@
+//! A wrapper catching heap misuse, at the cost of memory and speed.
+//!
+//! Every allocation gets a header and a red zone either side:
+//!
+//! ```text
+//! | padding | Header | red zone | allocation | red zone |
+//! ```
+//!
+//! New allocations are filled with [`ALLOC_FILL`], so reading memory before writing it
+//! stands out, and red zones with [`RED_ZONE_FILL`], checked when the allocation is
+//! freed. Freed allocations are filled with [`FREE_FILL`] and held back from the
+//! inner allocator for a while, so freeing them again is caught, as is writing to them
+//! before they're handed back. Once they have been, neither can be told apart from
+//! using a new allocation.
+//!
+//! Problems panic, with where the allocation was made from if the [`Backtrace`] can
+//! tell.
+
+use super::align_up;
+use core::alloc::{GlobalAlloc, Layout};
+use core::fmt;
+use core::marker::PhantomData;
+use core::{mem, ptr, slice};
+use spin::Mutex;
+
+#[cfg(test)]
+use crate::{
+    bump, linked_list,
+    testing::{arena_allocator, check_random_workload, Arena},
+    Locked,
+};
+
+/// Return addresses recorded for each allocation.
+pub const SITE_DEPTH: usize = 6;
+
+/// Fills new allocations.
+pub const ALLOC_FILL: u8 = 0xcd;
+/// Fills freed allocations.
+pub const FREE_FILL: u8 = 0xdd;
+/// Fills the red zones.
+pub const RED_ZONE_FILL: u8 = 0xfd;
+
+const RED_ZONE: usize = 16;
+/// Most freed allocations held back at once, by count and by size.
+const QUARANTINE_LEN: usize = 32;
+const QUARANTINE_BYTES: usize = 16 * 1024;
+
+const LIVE: usize = 0xa110_ca7e_d000_0001;
+const FREED: usize = 0xf4ee_d000_0000_0002;
+
+/// Finds where an allocation is being made from.
+pub trait Backtrace {
+    /// Fill `sites` with return addresses, innermost first, leaving the rest 0.
+    fn capture(sites: &mut [usize; SITE_DEPTH]);
+}
+
+/// Records no sites.
+pub struct NoBacktrace;
+
+impl Backtrace for NoBacktrace {
+    fn capture(_sites: &mut [usize; SITE_DEPTH]) {}
+}
+
+#[repr(C, align(16))]
+struct Header {
+    magic: usize,
+    size: usize,
+    seq: u64,
+    /// Neighbours in the list of live allocations, newer and older.
+    newer: *mut Header,
+    older: *mut Header,
+    sites: [usize; SITE_DEPTH],
+}
+
+/// A live allocation, as listed by [`Checking::for_each_live`].
+#[derive(Debug, Clone, Copy)]
+pub struct Allocation {
+    pub addr: usize,
+    pub size: usize,
+    /// Allocations are numbered from 0 in the order they're made.
+    pub seq: u64,
+    pub sites: [usize; SITE_DEPTH],
+}
+
+impl fmt::Display for Allocation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} bytes at {:#x}", self.size, self.addr)?;
+        write_sites(f, &self.sites)
+    }
+}
+
+fn write_sites(f: &mut fmt::Formatter, sites: &[usize; SITE_DEPTH]) -> fmt::Result {
+    let mut sites = sites.iter().take_while(|&&site| site != 0);
+    if let Some(site) = sites.next() {
+        write!(f, ", allocated from {:#x}", site)?;
+        for site in sites {
+            write!(f, " <- {:#x}", site)?;
+        }
+    }
+    Ok(())
+}
+
+struct State {
+    /// Newest live allocation.
+    newest: *mut Header,
+    next_seq: u64,
+    /// Freed allocations not yet handed back, as `(addr, size, align)`, oldest first
+    /// from `quarantine_start`.
+    quarantine: [(usize, usize, usize); QUARANTINE_LEN],
+    quarantine_start: usize,
+    quarantine_len: usize,
+    quarantine_bytes: usize,
+}
+
+// The headers are only touched with the lock held.
+unsafe impl Send for State {}
+
+/// Wraps an allocator to check for misuse of the heap.
+pub struct Checking<A, B = NoBacktrace> {
+    inner: A,
+    state: Mutex<State>,
+    backtrace: PhantomData<B>,
+}
+
+impl<A, B> Checking<A, B> {
+    pub const fn new(inner: A) -> Self {
+        Self {
+            inner,
+            state: Mutex::new(State {
+                newest: ptr::null_mut(),
+                next_seq: 0,
+                quarantine: [(0, 0, 0); QUARANTINE_LEN],
+                quarantine_start: 0,
+                quarantine_len: 0,
+                quarantine_bytes: 0,
+            }),
+            backtrace: PhantomData,
+        }
+    }
+
+    pub fn inner(&self) -> &A {
+        &self.inner
+    }
+
+    /// Sequence number of the next allocation, to list the ones made after now.
+    pub fn mark(&self) -> u64 {
+        self.state.lock().next_seq
+    }
+
+    /// Call `f` with every live allocation numbered `since` or later, newest first.
+    ///
+    /// The heap is locked meanwhile, so `f` mustn't allocate.
+    pub fn for_each_live(&self, since: u64, mut f: impl FnMut(&Allocation)) {
+        let state = self.state.lock();
+        let mut header = state.newest;
+        while let Some(h) = unsafe { header.as_ref() } {
+            if h.seq < since {
+                break;
+            }
+            f(&Allocation {
+                addr: header as usize + mem::size_of::<Header>() + RED_ZONE,
+                size: h.size,
+                seq: h.seq,
+                sites: h.sites,
+            });
+            header = h.older;
+        }
+    }
+}
+
+/// The layout asked of the inner allocator for `layout`, and the offset of the
+/// allocation in it.
+fn outer_layout(layout: Layout) -> Option<(Layout, usize)> {
+    let align = layout.align().max(mem::align_of::<Header>());
+    let front = align_up(mem::size_of::<Header>() + RED_ZONE, align);
+    let size = front.checked_add(layout.size())?.checked_add(RED_ZONE)?;
+    Some((Layout::from_size_align(size, align).ok()?, front))
+}
+
+fn header_of(ptr: *mut u8) -> *mut Header {
+    (ptr as usize - RED_ZONE - mem::size_of::<Header>()) as *mut Header
+}
+
+/// Whether `len` bytes from `addr` are all `fill`.
+unsafe fn filled(addr: usize, len: usize, fill: u8) -> bool {
+    slice::from_raw_parts(addr as *const u8, len)
+        .iter()
+        .all(|&b| b == fill)
+}
+
+/// Something done wrong with an allocation.
+struct Problem {
+    kind: &'static str,
+    addr: usize,
+    size: usize,
+    sites: [usize; SITE_DEPTH],
+}
+
+impl Problem {
+    fn new(kind: &'static str, addr: usize, header: &Header) -> Self {
+        Problem {
+            kind,
+            addr,
+            size: header.size,
+            sites: header.sites,
+        }
+    }
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "heap {}: {} bytes at {:#x}",
+            self.kind, self.size, self.addr
+        )?;
+        write_sites(f, &self.sites)
+    }
+}
+
+/// Check the allocation at `ptr` is live and intact.
+unsafe fn check_live<'a>(ptr: *mut u8, layout: Layout) -> Result<&'a mut Header, Problem> {
+    let addr = ptr as usize;
+    let header = &mut *header_of(ptr);
+    match header.magic {
+        LIVE => {}
+        FREED => return Err(Problem::new("double free", addr, header)),
+        _ => {
+            return Err(Problem {
+                kind: "free of memory that isn't allocated, or whose header was overwritten",
+                addr,
+                size: layout.size(),
+                sites: [0; SITE_DEPTH],
+            })
+        }
+    }
+    if header.size != layout.size() {
+        return Err(Problem::new("free with the wrong size", addr, header));
+    }
+    if !filled(addr - RED_ZONE, RED_ZONE, RED_ZONE_FILL) {
+        return Err(Problem::new("underflow", addr, header));
+    }
+    if !filled(addr + header.size, RED_ZONE, RED_ZONE_FILL) {
+        return Err(Problem::new("overflow", addr, header));
+    }
+    Ok(header)
+}
+
+impl<A: GlobalAlloc, B: Backtrace> Checking<A, B> {
+    /// Check the allocation at `ptr` is live and intact, and take it off the list.
+    unsafe fn release(&self, ptr: *mut u8, layout: Layout) -> Result<(), Problem> {
+        let addr = ptr as usize;
+        let mut state = self.state.lock();
+        let header = check_live(ptr, layout)?;
+
+        match header.newer.as_mut() {
+            Some(newer) => newer.older = header.older,
+            None => state.newest = header.older,
+        }
+        if let Some(older) = header.older.as_mut() {
+            older.newer = header.newer;
+        }
+        header.magic = FREED;
+        ptr.write_bytes(FREE_FILL, header.size);
+
+        let i = (state.quarantine_start + state.quarantine_len) % QUARANTINE_LEN;
+        state.quarantine[i] = (addr, layout.size(), layout.align());
+        state.quarantine_len += 1;
+        state.quarantine_bytes += layout.size();
+        Ok(())
+    }
+
+    /// Hand allocations back to the inner allocator until the quarantine is small
+    /// enough again, or empty if `all`, checking they weren't written to.
+    unsafe fn drain_quarantine(&self, all: bool) -> Result<(), Problem> {
+        let mut state = self.state.lock();
+        while state.quarantine_len > 0
+            && (all
+                || state.quarantine_len == QUARANTINE_LEN
+                || state.quarantine_bytes > QUARANTINE_BYTES)
+        {
+            let (addr, size, align) = state.quarantine[state.quarantine_start];
+            state.quarantine_start = (state.quarantine_start + 1) % QUARANTINE_LEN;
+            state.quarantine_len -= 1;
+            state.quarantine_bytes -= size;
+
+            let header = &*header_of(addr as *mut u8);
+            if header.magic != FREED || !filled(addr, size, FREE_FILL) {
+                return Err(Problem::new("use after free", addr, header));
+            }
+            let layout = Layout::from_size_align_unchecked(size, align);
+            let (outer, front) = outer_layout(layout).unwrap();
+            self.inner.dealloc((addr - front) as *mut u8, outer);
+        }
+        Ok(())
+    }
+}
+
+unsafe impl<A: GlobalAlloc, B: Backtrace> GlobalAlloc for Checking<A, B> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let (outer, front) = match outer_layout(layout) {
+            Some(outer) => outer,
+            None => return ptr::null_mut(),
+        };
+        let mut base = self.inner.alloc(outer);
+        if base.is_null() {
+            // Maybe the quarantine is in the way
+            if let Err(problem) = self.drain_quarantine(true) {
+                panic!("{}", problem);
+            }
+            base = self.inner.alloc(outer);
+            if base.is_null() {
+                return base;
+            }
+        }
+        let ptr = base.add(front);
+        ptr.sub(RED_ZONE).write_bytes(RED_ZONE_FILL, RED_ZONE);
+        ptr.write_bytes(ALLOC_FILL, layout.size());
+        ptr.add(layout.size()).write_bytes(RED_ZONE_FILL, RED_ZONE);
+        let mut sites = [0; SITE_DEPTH];
+        B::capture(&mut sites);
+
+        let header = header_of(ptr);
+        let mut state = self.state.lock();
+        header.write(Header {
+            magic: LIVE,
+            size: layout.size(),
+            seq: state.next_seq,
+            newer: ptr::null_mut(),
+            older: state.newest,
+            sites,
+        });
+        if let Some(newest) = state.newest.as_mut() {
+            newest.newer = header;
+        }
+        state.newest = header;
+        state.next_seq += 1;
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        // Not from under the lock, which would stay locked
+        if let Err(problem) = self.release(ptr, layout) {
+            panic!("{}", problem);
+        }
+        if let Err(problem) = self.drain_quarantine(false) {
+            panic!("{}", problem);
+        }
+    }
+
+    /// Resize through the inner allocator, so it can do so in place, moving the
+    /// trailing red zone to the new end.
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_layout = Layout::from_size_align_unchecked(new_size, layout.align());
+        let (outer, front) = outer_layout(layout).unwrap();
+        let new_outer = match outer_layout(new_layout) {
+            Some((new_outer, _)) => new_outer,
+            None => return ptr::null_mut(),
+        };
+        let base = ptr.sub(front);
+        let mut state = self.state.lock();
+        if let Err(problem) = check_live(ptr, layout) {
+            drop(state);
+            panic!("{}", problem);
+        }
+        let mut new_base = self.inner.realloc(base, outer, new_outer.size());
+        if new_base.is_null() {
+            // Maybe the quarantine is in the way
+            drop(state);
+            if let Err(problem) = self.drain_quarantine(true) {
+                panic!("{}", problem);
+            }
+            state = self.state.lock();
+            new_base = self.inner.realloc(base, outer, new_outer.size());
+            if new_base.is_null() {
+                return new_base;
+            }
+        }
+
+        let new_ptr = new_base.add(front);
+        let header = header_of(new_ptr);
+        // If it moved the header went with it, so point its neighbours at it again
+        match (*header).newer.as_mut() {
+            Some(newer) => newer.older = header,
+            None => state.newest = header,
+        }
+        if let Some(older) = (*header).older.as_mut() {
+            older.newer = header;
+        }
+        (*header).size = new_size;
+        if new_size > layout.size() {
+            new_ptr
+                .add(layout.size())
+                .write_bytes(ALLOC_FILL, new_size - layout.size());
+        }
+        new_ptr.add(new_size).write_bytes(RED_ZONE_FILL, RED_ZONE);
+        new_ptr
+    }
+}
+
+#[cfg(test)]
+type Checked = Checking<Locked<linked_list::Allocator>>;
+
+#[test]
+fn test_checking_fills() {
+    let arena = Arena::new(4096);
+    let alloc = Checked::new(arena_allocator(&arena));
+    let l = Layout::from_size_align(24, 8).unwrap();
+    unsafe {
+        let p = alloc.alloc(l);
+        assert!(filled(p as usize, 24, ALLOC_FILL));
+        assert!(filled(p as usize + 24, RED_ZONE, RED_ZONE_FILL));
+        alloc.dealloc(p, l);
+        // Still in quarantine
+        assert!(filled(p as usize, 24, FREE_FILL));
+    }
+}
+
+#[test]
+#[should_panic(expected = "heap overflow: 24 bytes")]
+fn test_checking_overflow() {
+    let arena = Arena::new(4096);
+    let alloc = Checked::new(arena_allocator(&arena));
+    let l = Layout::from_size_align(24, 8).unwrap();
+    unsafe {
+        let p = alloc.alloc(l);
+        p.add(24).write(0);
+        alloc.dealloc(p, l);
+    }
+}
+
+#[test]
+#[should_panic(expected = "heap double free")]
+fn test_checking_double_free() {
+    let arena = Arena::new(4096);
+    let alloc = Checked::new(arena_allocator(&arena));
+    let l = Layout::from_size_align(24, 8).unwrap();
+    unsafe {
+        let p = alloc.alloc(l);
+        alloc.dealloc(p, l);
+        alloc.dealloc(p, l);
+    }
+}
+
+#[test]
+#[should_panic(expected = "heap use after free")]
+fn test_checking_use_after_free() {
+    let arena = Arena::new(64 * 1024);
+    let alloc = Checked::new(arena_allocator(&arena));
+    let l = Layout::from_size_align(24, 8).unwrap();
+    unsafe {
+        let p = alloc.alloc(l);
+        alloc.dealloc(p, l);
+        p.write(1);
+        // Push it out of quarantine
+        for _ in 0..QUARANTINE_LEN {
+            let q = alloc.alloc(l);
+            alloc.dealloc(q, l);
+        }
+    }
+}
+
+#[test]
+fn test_checking_realloc() {
+    let arena = Arena::new(4096);
+    let alloc = Checked::new(arena_allocator(&arena));
+    let l = Layout::from_size_align(24, 8).unwrap();
+    unsafe {
+        let p1 = alloc.alloc(l);
+        p1.write_bytes(1, 24);
+        let p2 = alloc.alloc(l);
+        // Grown in place, with the red zone moved along
+        assert_eq!(alloc.realloc(p2, l, 200), p2);
+        assert!(filled(p2 as usize + 24, 176, ALLOC_FILL));
+        assert!(filled(p2 as usize + 200, RED_ZONE, RED_ZONE_FILL));
+        // Moved, as p2 is in the way, keeping the contents and its place in the list
+        let p1 = alloc.realloc(p1, l, 100);
+        assert!(filled(p1 as usize, 24, 1));
+        let mut live = Vec::new();
+        alloc.for_each_live(0, |a| live.push((a.addr, a.size, a.seq)));
+        assert_eq!(live, [(p2 as usize, 200, 1), (p1 as usize, 100, 0)]);
+        alloc.dealloc(p1, Layout::from_size_align(100, 8).unwrap());
+        alloc.dealloc(p2, Layout::from_size_align(200, 8).unwrap());
+    }
+}
+
+#[test]
+#[should_panic(expected = "heap overflow: 200 bytes")]
+fn test_checking_overflow_after_realloc() {
+    let arena = Arena::new(4096);
+    let alloc = Checked::new(arena_allocator(&arena));
+    let l = Layout::from_size_align(24, 8).unwrap();
+    unsafe {
+        let p = alloc.realloc(alloc.alloc(l), l, 200);
+        p.add(200).write(0);
+        alloc.dealloc(p, Layout::from_size_align(200, 8).unwrap());
+    }
+}
+
+#[test]
+fn test_checking_lists_live_allocations() {
+    let arena = Arena::new(4096);
+    let alloc = Checked::new(arena_allocator(&arena));
+    let l = Layout::from_size_align(24, 8).unwrap();
+    unsafe {
+        let p1 = alloc.alloc(l);
+        let mark = alloc.mark();
+        let p2 = alloc.alloc(l);
+        let p3 = alloc.alloc(l);
+        alloc.dealloc(p2, l);
+
+        let mut live = Vec::new();
+        alloc.for_each_live(0, |a| live.push((a.addr, a.size, a.seq)));
+        assert_eq!(live, [(p3 as usize, 24, 2), (p1 as usize, 24, 0)]);
+        let mut since = Vec::new();
+        alloc.for_each_live(mark, |a| since.push(a.addr));
+        assert_eq!(since, [p3 as usize]);
+    }
+}
+
+#[test]
+fn test_checking_quarantine_gives_way() {
+    let arena = Arena::new(4096);
+    let alloc = Checking::<_>::new(arena_allocator::<bump::Allocator>(&arena));
+    let l = Layout::from_size_align(64, 8).unwrap();
+    // A bump allocator only gets its memory back once everything's freed
+    for _ in 0..1000 {
+        let p = unsafe { alloc.alloc(l) };
+        assert!(!p.is_null());
+        unsafe { alloc.dealloc(p, l) };
+    }
+}
+
+#[test]
+fn test_checking_random_workload() {
+    check_random_workload(
+        16,
+        2000,
+        4096,
+        |arena| Checked::new(arena_allocator(arena)),
+        |_, _| {},
+        |alloc, _, seed| {
+            let mut live = 0;
+            alloc.for_each_live(0, |_| live += 1);
+            assert_eq!(live, 0, "seed {}", seed);
+        },
+    );
+}
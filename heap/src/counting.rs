@@ -0,0 +1,143 @@
+use core::alloc::{GlobalAlloc, Layout};
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+#[cfg(test)]
+use crate::{
+    bump, linked_list,
+    testing::{arena_allocator, Arena},
+};
+
+/// Heap usage as seen by `Counting`. Sizes are what callers asked for, not counting
+/// the allocator's own overhead.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Stats {
+    pub allocations: usize,
+    pub deallocations: usize,
+    /// Allocation requests that failed.
+    pub failures: usize,
+    /// Bytes currently allocated.
+    pub in_use: usize,
+    /// Most bytes ever allocated at once.
+    pub peak: usize,
+}
+
+/// Wraps an allocator to keep `Stats` on it.
+pub struct Counting<A> {
+    inner: A,
+    allocations: AtomicUsize,
+    deallocations: AtomicUsize,
+    failures: AtomicUsize,
+    in_use: AtomicUsize,
+    peak: AtomicUsize,
+}
+
+impl<A> Counting<A> {
+    pub const fn new(inner: A) -> Self {
+        Self {
+            inner,
+            allocations: AtomicUsize::new(0),
+            deallocations: AtomicUsize::new(0),
+            failures: AtomicUsize::new(0),
+            in_use: AtomicUsize::new(0),
+            peak: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn inner(&self) -> &A {
+        &self.inner
+    }
+
+    pub fn stats(&self) -> Stats {
+        Stats {
+            allocations: self.allocations.load(Ordering::Relaxed),
+            deallocations: self.deallocations.load(Ordering::Relaxed),
+            failures: self.failures.load(Ordering::Relaxed),
+            in_use: self.in_use.load(Ordering::Relaxed),
+            peak: self.peak.load(Ordering::Relaxed),
+        }
+    }
+}
+
+unsafe impl<A: GlobalAlloc> GlobalAlloc for Counting<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = self.inner.alloc(layout);
+        if ptr.is_null() {
+            self.failures.fetch_add(1, Ordering::Relaxed);
+            return ptr;
+        }
+        self.allocations.fetch_add(1, Ordering::Relaxed);
+        let in_use = self.in_use.fetch_add(layout.size(), Ordering::Relaxed) + layout.size();
+        self.peak.fetch_max(in_use, Ordering::Relaxed);
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.inner.dealloc(ptr, layout);
+        self.deallocations.fetch_add(1, Ordering::Relaxed);
+        self.in_use.fetch_sub(layout.size(), Ordering::Relaxed);
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_ptr = self.inner.realloc(ptr, layout, new_size);
+        if new_ptr.is_null() {
+            self.failures.fetch_add(1, Ordering::Relaxed);
+            return new_ptr;
+        }
+        if new_size >= layout.size() {
+            let grown = new_size - layout.size();
+            let in_use = self.in_use.fetch_add(grown, Ordering::Relaxed) + grown;
+            self.peak.fetch_max(in_use, Ordering::Relaxed);
+        } else {
+            self.in_use
+                .fetch_sub(layout.size() - new_size, Ordering::Relaxed);
+        }
+        new_ptr
+    }
+}
+
+#[test]
+fn test_counting_stats() {
+    let arena = Arena::new(64);
+    let alloc = Counting::new(arena_allocator::<bump::Allocator>(&arena));
+    let l = Layout::from_size_align(24, 8).unwrap();
+    unsafe {
+        let p1 = alloc.alloc(l);
+        let p2 = alloc.alloc(l);
+        assert!(alloc.alloc(l).is_null());
+        alloc.dealloc(p2, l);
+        alloc.dealloc(p1, l);
+    }
+    let expected = Stats {
+        allocations: 2,
+        deallocations: 2,
+        failures: 1,
+        in_use: 0,
+        peak: 48,
+    };
+    assert_eq!(alloc.stats(), expected);
+}
+
+#[test]
+fn test_counting_realloc_in_place() {
+    let arena = Arena::new(4096);
+    let alloc = Counting::new(arena_allocator::<linked_list::Allocator>(&arena));
+    let l = Layout::from_size_align(64, 8).unwrap();
+    unsafe {
+        let p = alloc.alloc(l);
+        assert_eq!(alloc.realloc(p, l, 256), p);
+        assert_eq!(alloc.stats().in_use, 256);
+        assert_eq!(
+            alloc.realloc(p, Layout::from_size_align(256, 8).unwrap(), 32),
+            p
+        );
+        alloc.dealloc(p, Layout::from_size_align(32, 8).unwrap());
+    }
+    let expected = Stats {
+        allocations: 1,
+        deallocations: 1,
+        failures: 0,
+        in_use: 0,
+        peak: 256,
+    };
+    assert_eq!(alloc.stats(), expected);
+}
@@ -0,0 +1,389 @@
+//! Blocks of fixed, power of two sizes, cut from pages taken from a
+//! `linked_list::Allocator`, which also serves anything bigger.
+//!
+//! Pages are split buddy style: a block is halved until it's the size asked for, and a
+//! freed block is merged with its buddy, the other half of the block they were split
+//! from, whenever that's free too. A page that ends up entirely free goes back to the
+//! fallback, apart from one kept as a spare, so allocating and freeing a single block
+//! doesn't take a page from the fallback each time.
+
+use super::linked_list;
+use super::locked::Locked;
+use core::alloc::{GlobalAlloc, Layout};
+use core::ptr::{self, NonNull};
+
+#[cfg(test)]
+use crate::testing::{arena_allocator, check_random_workload, Arena, Rng};
+#[cfg(test)]
+use core::mem;
+
+/// The block sizes to use.
+///
+/// Each must be a power of two because they're also used as the block's
+/// alignment, and twice the one before, because blocks are split in half.
+const BLOCK_SIZES: &[usize] = &[16, 32, 64, 128, 256, 512, 1024, 2048];
+const ORDERS: usize = BLOCK_SIZES.len();
+const MIN_BLOCK: usize = BLOCK_SIZES[0];
+
+/// What blocks are cut from, taken from the fallback aligned to its size.
+const PAGE_SIZE: usize = 4096;
+
+/// The size of block each page's `Header` takes up.
+const HEADER_ORDER: usize = 2;
+
+/// A free block, in the list for its size.
+struct Node {
+    prev: *mut Node,
+    next: *mut Node,
+}
+
+/// The start of every page, recording which of its blocks are free. It fills the
+/// first block of its size, which is never handed out, so the page's other blocks of
+/// that size and bigger start out free.
+struct Header {
+    /// A bit for every block of each size, set if it's free as a whole: first the
+    /// smallest blocks, then those twice the size, and so on.
+    free: [u64; 8],
+}
+
+/// The bit in `Header::free` for the block `page_offset` into its page.
+fn bit(page_offset: usize, order: usize) -> usize {
+    let bits = 2 * PAGE_SIZE / MIN_BLOCK;
+    bits - (bits >> order) + page_offset / BLOCK_SIZES[order]
+}
+
+fn page_of(addr: usize) -> usize {
+    addr & !(PAGE_SIZE - 1)
+}
+
+fn page_layout() -> Layout {
+    Layout::from_size_align(PAGE_SIZE, PAGE_SIZE).unwrap()
+}
+
+#[inline]
+fn list_index(layout: &Layout) -> Option<usize> {
+    let required_block_size = layout.size().max(layout.align());
+    BLOCK_SIZES.iter().position(|&s| s >= required_block_size)
+}
+
+pub struct Allocator {
+    list_heads: [*mut Node; ORDERS],
+    /// An empty page kept back from the fallback.
+    spare: Option<usize>,
+    fallback: linked_list::Allocator,
+}
+
+// The lists only point into memory the allocator owns.
+unsafe impl Send for Allocator {}
+
+impl Allocator {
+    /// Create a new, empty `Allocator` with an empty fallback.
+    pub const fn empty() -> Self {
+        Self {
+            list_heads: [ptr::null_mut(); ORDERS],
+            spare: None,
+            fallback: linked_list::Allocator::empty(),
+        }
+    }
+
+    /// Initialize the allocator with the provided heap bounds.
+    ///
+    /// # Safety
+    ///
+    /// The caller must guarantee that the provided heap bounds are invalid
+    /// and that the memory is unused.
+    pub unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
+        self.fallback.init(heap_start, heap_size);
+    }
+
+    /// The allocator pages and large allocations come from.
+    pub fn fallback(&self) -> &linked_list::Allocator {
+        &self.fallback
+    }
+
+    /// Give the spare page back to the fallback, if there is one.
+    pub fn trim(&mut self) {
+        if let Some(page) = self.spare.take() {
+            unsafe { self.release_page(page) };
+        }
+    }
+
+    /// Allocate a region using the fallback allocator, giving it the spare page to
+    /// try again if it's out of memory.
+    unsafe fn fallback_alloc(&mut self, layout: Layout) -> *mut u8 {
+        match self.fallback.allocate(layout) {
+            Ok(p) => p.as_ptr(),
+            Err(linked_list::AllocError::OOM) if self.spare.is_some() => {
+                self.trim();
+                self.fallback_alloc(layout)
+            }
+            Err(_) => ptr::null_mut(),
+        }
+    }
+
+    unsafe fn is_free(&self, addr: usize, order: usize) -> bool {
+        let header = &*(page_of(addr) as *const Header);
+        let bit = bit(addr % PAGE_SIZE, order);
+        header.free[bit / 64] & (1 << (bit % 64)) != 0
+    }
+
+    unsafe fn set_free(&mut self, addr: usize, order: usize, free: bool) {
+        let header = &mut *(page_of(addr) as *mut Header);
+        let bit = bit(addr % PAGE_SIZE, order);
+        if free {
+            header.free[bit / 64] |= 1 << (bit % 64);
+        } else {
+            header.free[bit / 64] &= !(1 << (bit % 64));
+        }
+    }
+
+    /// Add the block at `addr` to the free list for `order`.
+    unsafe fn push(&mut self, addr: usize, order: usize) {
+        let node = addr as *mut Node;
+        let next = self.list_heads[order];
+        node.write(Node {
+            prev: ptr::null_mut(),
+            next,
+        });
+        if let Some(next) = next.as_mut() {
+            next.prev = node;
+        }
+        self.list_heads[order] = node;
+        self.set_free(addr, order, true);
+    }
+
+    /// Take the block at `addr` off the free list for `order`.
+    unsafe fn remove(&mut self, addr: usize, order: usize) {
+        let node = &mut *(addr as *mut Node);
+        match node.prev.as_mut() {
+            Some(prev) => prev.next = node.next,
+            None => self.list_heads[order] = node.next,
+        }
+        if let Some(next) = node.next.as_mut() {
+            next.prev = node.prev;
+        }
+        self.set_free(addr, order, false);
+    }
+
+    /// The smallest order at least `order` with a free block.
+    fn find(&self, order: usize) -> Option<usize> {
+        (order..ORDERS).find(|&i| !self.list_heads[i].is_null())
+    }
+
+    /// Take a page from the fallback and free all its blocks.
+    unsafe fn add_page(&mut self) -> bool {
+        let page = match self.fallback.allocate(page_layout()) {
+            Ok(p) => p.as_ptr() as usize,
+            Err(_) => return false,
+        };
+        (page as *mut Header).write(Header { free: [0; 8] });
+        for (order, size) in BLOCK_SIZES.iter().enumerate().skip(HEADER_ORDER) {
+            self.push(page + size, order);
+        }
+        true
+    }
+
+    /// Whether all of `page` but its header is free, which it is once everything in it
+    /// has merged back into the blocks it started out as.
+    unsafe fn is_empty(&self, page: usize) -> bool {
+        (HEADER_ORDER..ORDERS).all(|order| self.is_free(page + BLOCK_SIZES[order], order))
+    }
+
+    unsafe fn release_page(&mut self, page: usize) {
+        for (order, size) in BLOCK_SIZES.iter().enumerate().skip(HEADER_ORDER) {
+            self.remove(page + size, order);
+        }
+        let ptr = NonNull::new(page as *mut u8).unwrap();
+        self.fallback.deallocate(ptr, page_layout());
+    }
+
+    unsafe fn alloc_block(&mut self, order: usize) -> *mut u8 {
+        let mut from = match self.find(order) {
+            Some(from) => from,
+            None if self.add_page() => self.find(order).unwrap(),
+            None => return ptr::null_mut(),
+        };
+        let addr = self.list_heads[from] as usize;
+        self.remove(addr, from);
+        if self.spare == Some(page_of(addr)) {
+            self.spare = None;
+        }
+        // Split off the top half until it's the right size
+        while from > order {
+            from -= 1;
+            self.push(addr + BLOCK_SIZES[from], from);
+        }
+        addr as *mut u8
+    }
+
+    unsafe fn dealloc_block(&mut self, mut addr: usize, mut order: usize) {
+        while order < ORDERS - 1 {
+            let buddy = addr ^ BLOCK_SIZES[order];
+            if !self.is_free(buddy, order) {
+                break;
+            }
+            self.remove(buddy, order);
+            addr = addr.min(buddy);
+            order += 1;
+        }
+        self.push(addr, order);
+
+        let page = page_of(addr);
+        if self.is_empty(page) {
+            match self.spare {
+                Some(spare) if spare != page => self.release_page(page),
+                _ => self.spare = Some(page),
+            }
+        }
+    }
+}
+
+unsafe impl GlobalAlloc for Locked<Allocator> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let mut allocator = self.lock();
+        match list_index(&layout) {
+            Some(i) => allocator.alloc_block(i),
+            None => allocator.fallback_alloc(layout),
+        }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let mut allocator = self.lock();
+        match list_index(&layout) {
+            Some(i) => allocator.dealloc_block(ptr as usize, i),
+            None => {
+                let ptr = NonNull::new(ptr).unwrap();
+                allocator.fallback.deallocate(ptr, layout);
+            }
+        }
+    }
+}
+
+/// Check everything has gone back to the fallback and merged there.
+#[cfg(test)]
+fn check_all_returned(alloc: &Locked<Allocator>, arena: &Arena) {
+    let mut allocator = alloc.lock();
+    allocator.trim();
+    let regions: Vec<_> = allocator.fallback().free_regions().collect();
+    assert_eq!(regions, [(arena.start(), arena.size())]);
+}
+
+#[test]
+fn test_fixed_size_block_header_fits() {
+    assert_eq!(mem::size_of::<Header>(), BLOCK_SIZES[HEADER_ORDER]);
+    assert!(bit(PAGE_SIZE - 1, ORDERS - 1) < 64 * 8);
+    assert!(mem::size_of::<Node>() <= MIN_BLOCK);
+}
+
+#[test]
+fn test_fixed_size_block_reuses_blocks() {
+    let arena = Arena::new(4096);
+    let alloc = arena_allocator::<Allocator>(&arena);
+    let l = Layout::from_size_align(20, 4).unwrap();
+    unsafe {
+        let p1 = alloc.alloc(l);
+        assert_eq!(p1 as usize % 32, 0, "should come from the 32 byte list");
+        alloc.dealloc(p1, l);
+        assert_eq!(alloc.alloc(l), p1);
+    }
+}
+
+#[test]
+fn test_fixed_size_block_large_allocations() {
+    let arena = Arena::new(16 * 1024);
+    let alloc = arena_allocator::<Allocator>(&arena);
+    let l = Layout::from_size_align(4096, 8).unwrap();
+    unsafe {
+        let p1 = alloc.alloc(l);
+        let p2 = alloc.alloc(l);
+        assert!(!p1.is_null() && !p2.is_null());
+        alloc.dealloc(p1, l);
+        alloc.dealloc(p2, l);
+        let whole = Layout::from_size_align(arena.size(), 8).unwrap();
+        assert_eq!(alloc.alloc(whole) as usize, arena.start());
+    }
+}
+
+#[test]
+fn test_fixed_size_block_splits_any_bin() {
+    let arena = Arena::new(4096);
+    let alloc = arena_allocator::<Allocator>(&arena);
+    let small = Layout::from_size_align(16, 16).unwrap();
+    let large = Layout::from_size_align(2048, 8).unwrap();
+    unsafe {
+        // Split from the 64 byte block after the header
+        let p1 = alloc.alloc(small) as usize;
+        assert_eq!(p1, arena.start() + 64);
+        let p2 = alloc.alloc(small) as usize;
+        assert_eq!(p2, arena.start() + 80);
+        // And the rest of the page is still there in one piece
+        assert_eq!(alloc.alloc(large) as usize, arena.start() + 2048);
+    }
+}
+
+#[test]
+fn test_fixed_size_block_coalesces_pages() {
+    let arena = Arena::new(64 * 1024);
+    let alloc = arena_allocator::<Allocator>(&arena);
+    let l = Layout::from_size_align(16, 8).unwrap();
+    let mut blocks = Vec::new();
+    loop {
+        let p = unsafe { alloc.alloc(l) };
+        if p.is_null() {
+            break;
+        }
+        blocks.push(p);
+    }
+    // Every page, all but its header
+    assert_eq!(blocks.len(), 16 * (4096 - 64) / 16);
+
+    let mut rng = Rng::new(1);
+    while !blocks.is_empty() {
+        let p = blocks.swap_remove(rng.below(blocks.len()));
+        unsafe { alloc.dealloc(p, l) };
+    }
+    check_all_returned(&alloc, &arena);
+}
+
+#[test]
+fn test_fixed_size_block_keeps_one_spare() {
+    let arena = Arena::new(64 * 1024);
+    let alloc = arena_allocator::<Allocator>(&arena);
+    let l = Layout::from_size_align(2048, 8).unwrap();
+    unsafe {
+        let p1 = alloc.alloc(l);
+        let p2 = alloc.alloc(l);
+        assert_ne!(page_of(p1 as usize), page_of(p2 as usize));
+        alloc.dealloc(p1, l);
+        alloc.dealloc(p2, l);
+        let allocator = alloc.lock();
+        assert_eq!(allocator.spare, Some(page_of(p1 as usize)));
+        // The second page went back, leaving the rest of the arena in one piece
+        let regions: Vec<_> = allocator.fallback().free_regions().collect();
+        assert_eq!(regions, [(arena.start() + 4096, arena.size() - 4096)]);
+    }
+}
+
+#[test]
+fn test_fixed_size_block_random_workload() {
+    check_random_workload(
+        64,
+        2000,
+        8192,
+        arena_allocator::<Allocator>,
+        |_, _| {},
+        |alloc, arena, _| check_all_returned(alloc, arena),
+    );
+}
+
+#[test]
+fn test_fixed_size_block_stress_small() {
+    check_random_workload(
+        16,
+        20_000,
+        2048,
+        arena_allocator::<Allocator>,
+        |_, _| {},
+        |alloc, arena, _| check_all_returned(alloc, arena),
+    );
+}
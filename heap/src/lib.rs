@@ -0,0 +1,40 @@
+//! The kernel heap's allocators.
+//!
+//! They're nothing but address arithmetic over the memory they're given, so they're
+//! kept out of the kernel crate to be built and tested on the host, over a `Vec<u8>`:
+//!
+//! ```text
+//! cargo test-heap
+//! ```
+//!
+//! The `testing` feature makes the helpers for that available to the fuzz targets and
+//! to the kernel's own tests, which use its random number generator, [`testing::Rng`].
+#![cfg_attr(not(test), no_std)]
+
+#[cfg(any(test, feature = "testing"))]
+extern crate alloc;
+
+pub mod bump;
+pub mod checking;
+pub mod counting;
+pub mod fixed_size_block;
+pub mod linked_list;
+pub mod locked;
+pub mod slab;
+pub mod tlsf;
+
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+
+pub use checking::Checking;
+pub use counting::{Counting, Stats};
+pub use locked::Locked;
+
+/// Align the given address `addr` upwards to alignment `align`.
+///
+/// Requires that `align` is a power of two, which is guaranteed
+/// by the `GlobalAlloc` trait.
+#[inline(always)]
+fn align_up(addr: usize, align: usize) -> usize {
+    (addr + align - 1) & !(align - 1)
+}
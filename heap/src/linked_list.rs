@@ -0,0 +1,652 @@
+use super::align_up;
+use super::locked::Locked;
+use core::alloc::{GlobalAlloc, Layout};
+use core::mem;
+use core::ptr::{self, NonNull};
+
+#[cfg(test)]
+use crate::testing::{arena_allocator, check_random_workload, Arena, Model, Rng};
+
+struct Node {
+    size: usize,
+    next: Option<&'static mut Node>,
+}
+
+impl Node {
+    const fn new(size: usize) -> Node {
+        Node { size, next: None }
+    }
+
+    fn start_addr(&self) -> usize {
+        // Start address of our node header
+        self as *const Self as usize
+    }
+
+    fn end_addr(&self) -> usize {
+        self.start_addr() + self.size
+    }
+}
+
+#[derive(Debug)]
+pub enum AllocError {
+    OOM,
+    TooSmall,
+    Overflow,
+}
+
+type Result<T> = core::result::Result<T, AllocError>;
+
+/// Which of the free regions an allocation fits in it's made from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Policy {
+    /// The first, in address order.
+    FirstFit,
+    /// The one it fits most tightly.
+    BestFit,
+    /// The first after the end of the last allocation, wrapping around to the start.
+    NextFit,
+    /// The one with the most left over.
+    WorstFit,
+}
+
+pub struct Allocator {
+    head: Node,
+    policy: Policy,
+    /// Where `NextFit` starts looking.
+    rover: usize,
+}
+
+impl Allocator {
+    /// Create a new empty allocator
+    pub const fn empty() -> Self {
+        Self {
+            head: Node::new(0),
+            policy: Policy::FirstFit,
+            rover: 0,
+        }
+    }
+
+    /// Initialize the allocator with the given heap bounds, allocating first fit.
+    ///
+    /// # Safety
+    ///
+    /// The caller must ensure that the given memory range is unused.
+    pub unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
+        self.init_with_policy(heap_start, heap_size, Policy::FirstFit)
+    }
+
+    /// Initialize the allocator with the given heap bounds and placement policy.
+    ///
+    /// # Safety
+    ///
+    /// The caller must ensure that the given memory range is unused.
+    pub unsafe fn init_with_policy(&mut self, heap_start: usize, heap_size: usize, policy: Policy) {
+        self.policy = policy;
+        self.rover = heap_start;
+        self.add_free_region(heap_start, heap_size)
+    }
+
+    pub fn policy(&self) -> Policy {
+        self.policy
+    }
+
+    /// Add a region to our free list.
+    ///
+    /// This region will be merged with another if its `start_addr` is another region's
+    /// `end_addr` or if its end_addr is another region's `start_addr`.
+    ///
+    /// # Safety
+    ///
+    /// This method is unsafe as it creates a new `Node` at the specified address.
+    /// This may cause undefined behavior if `addr` is invalid or this memory is in use elsewhere.
+    unsafe fn add_free_region(&mut self, addr: usize, size: usize) {
+        // Ensure that the free region is large enough to hold a region header
+        assert_eq!(align_up(addr, mem::align_of::<Node>()), addr);
+        assert!(size >= mem::size_of::<Node>());
+
+        // append the new node to the start of our free list
+        let node_ptr = addr as *mut Node;
+        node_ptr.write(Node::new(size));
+        let node = &mut *node_ptr;
+
+        let our_start = node.start_addr();
+        let our_end = node.end_addr();
+
+        let mut current = &mut self.head;
+        while let Some(ref mut region) = current.next {
+            let region_start = region.start_addr();
+            let region_end = region.end_addr();
+            if region_end == our_start {
+                // append ourselves to this region
+                region.size += node.size;
+
+                // See if we can append `region.next` now that we've extended `region`
+                let mut tail = region.next.take();
+                if let Some(ref mut n) = tail {
+                    if n.start_addr() == region.end_addr() {
+                        // merge once more
+                        region.next = n.next.take();
+                        region.size += n.size;
+                    } else {
+                        region.next = tail;
+                    }
+                }
+                return;
+            } else if region_start == our_end {
+                // append this region to ourselves
+                node.size += region.size;
+                // Repair the list
+                node.next = region.next.take();
+                current.next = Some(node);
+                return;
+            } else if region_start > our_start {
+                // insert here
+                break;
+            }
+            current = current.next.as_mut().unwrap();
+        }
+        node.next = current.next.take();
+        current.next = Some(node);
+    }
+
+    /// The free regions, as `(start, size)`, in address order.
+    pub fn free_regions(&self) -> impl Iterator<Item = (usize, usize)> + '_ {
+        let mut next = self.head.next.as_deref();
+        core::iter::from_fn(move || {
+            let region = next?;
+            next = region.next.as_deref();
+            Some((region.start_addr(), region.size))
+        })
+    }
+
+    /// Find a free region with the given size and alignment, following the policy, and
+    /// remove it from our free list.
+    ///
+    /// Returns the list node and the start address of the allocation.
+    fn find_region(&mut self, size: usize, align: usize) -> Result<(&'static mut Node, usize)> {
+        // The region's start, what would be left over, and where the allocation goes
+        let mut chosen: Option<(usize, usize, usize)> = None;
+        // For `NextFit`, the first one before the rover, in case there's none after
+        let mut wrapped = None;
+        let mut next = self.head.next.as_deref();
+        while let Some(region) = next {
+            next = region.next.as_deref();
+            let alloc_start = match Self::alloc_from_region(region, size, align) {
+                Ok(alloc_start) => alloc_start,
+                Err(_) => continue,
+            };
+            let left_over = region.end_addr() - (alloc_start + size);
+            let candidate = Some((region.start_addr(), left_over, alloc_start));
+            match self.policy {
+                Policy::FirstFit => {
+                    chosen = candidate;
+                    break;
+                }
+                Policy::NextFit if region.start_addr() >= self.rover => {
+                    chosen = candidate;
+                    break;
+                }
+                Policy::NextFit => wrapped = wrapped.or(candidate),
+                Policy::BestFit => {
+                    match chosen {
+                        Some((_, best, _)) if best <= left_over => {}
+                        _ => chosen = candidate,
+                    }
+                    if left_over == 0 {
+                        break;
+                    }
+                }
+                Policy::WorstFit => match chosen {
+                    Some((_, worst, _)) if worst >= left_over => {}
+                    _ => chosen = candidate,
+                },
+            }
+        }
+        let (start, _, alloc_start) = chosen.or(wrapped).ok_or(AllocError::OOM)?;
+        Ok((self.take_region_at(start).unwrap(), alloc_start))
+    }
+
+    /// Remove the free region starting at `addr` from our free list, if there is one.
+    fn take_region_at(&mut self, addr: usize) -> Option<&'static mut Node> {
+        let mut current = &mut self.head;
+        while let Some(ref mut region) = current.next {
+            if region.start_addr() == addr {
+                let next = region.next.take();
+                let ret = current.next.take();
+                current.next = next;
+                return ret;
+            } else if region.start_addr() > addr {
+                break;
+            }
+            current = current.next.as_mut().unwrap();
+        }
+        None
+    }
+
+    fn alloc_from_region(region: &Node, size: usize, align: usize) -> Result<usize> {
+        let mut alloc_start = align_up(region.start_addr(), align);
+        let gap = alloc_start - region.start_addr();
+        if gap > 0 && gap < mem::size_of::<Node>() {
+            // The space in front goes back on the list, so it has to fit a node too
+            alloc_start = align_up(region.start_addr() + mem::size_of::<Node>(), align);
+        }
+        let alloc_end = alloc_start.checked_add(size).ok_or(AllocError::Overflow)?;
+
+        if alloc_end > region.end_addr() {
+            // region too small
+            return Err(AllocError::TooSmall);
+        }
+        let excess_size = region.end_addr() - alloc_end;
+        if excess_size > 0 && excess_size < mem::size_of::<Node>() {
+            // Region is too small for this allocation plus a new unused node
+            return Err(AllocError::TooSmall);
+        }
+        Ok(alloc_start)
+    }
+
+    /// Adjust the given layout such that any allocated memory region is capable of storing a `Node`.
+    ///
+    /// Returns the adjusted (size, alignment)
+    #[inline]
+    fn size_align(layout: Layout) -> (usize, usize) {
+        let layout = layout
+            .align_to(mem::align_of::<Node>())
+            .expect("failed to adjust alignment")
+            .pad_to_align();
+        let size = layout.size().max(mem::size_of::<Node>());
+        (size, layout.align())
+    }
+
+    /// Allocate a region for `layout`, chosen by the policy.
+    ///
+    /// # Safety
+    ///
+    /// The allocator must have been initialized, and the memory it was given must still
+    /// be ours to hand out.
+    pub unsafe fn allocate(&mut self, layout: Layout) -> Result<NonNull<u8>> {
+        let (size, align) = Allocator::size_align(layout);
+        let (region, alloc_start) = self.find_region(size, align)?;
+        let (region_start, region_end) = (region.start_addr(), region.end_addr());
+        let alloc_end = alloc_start.checked_add(size).expect("alloc overflow");
+        if alloc_start > region_start {
+            // Left over from aligning the allocation
+            self.add_free_region(region_start, alloc_start - region_start);
+        }
+        let excess_size = region_end - alloc_end;
+        if excess_size > 0 {
+            // hello fragmentation
+            self.add_free_region(alloc_end, excess_size);
+        }
+        self.rover = alloc_end;
+        Ok(NonNull::new_unchecked(alloc_start as *mut u8))
+    }
+
+    /// Give back the allocation at `ptr`.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must have come from this allocator with the same `layout`, and not have been
+    /// given back already. Nothing may use the memory afterwards.
+    pub unsafe fn deallocate(&mut self, ptr: NonNull<u8>, layout: Layout) {
+        let (adjusted_size, _) = Allocator::size_align(layout);
+        self.add_free_region(ptr.as_ptr() as usize, adjusted_size)
+    }
+
+    /// Resize the allocation at `ptr` to `new_size` without moving it, taking from or
+    /// giving back to the free region just after it. Returns whether it could.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must be a live allocation from this allocator made with `layout`. When this
+    /// returns true the allocation is `new_size` bytes with the same alignment, and only
+    /// those bytes may be used from then on.
+    pub unsafe fn resize_in_place(
+        &mut self,
+        ptr: NonNull<u8>,
+        layout: Layout,
+        new_size: usize,
+    ) -> bool {
+        let (old_size, _) = Allocator::size_align(layout);
+        let new_layout = Layout::from_size_align_unchecked(new_size, layout.align());
+        let (new_size, _) = Allocator::size_align(new_layout);
+        if new_size == old_size {
+            return true;
+        }
+        let start = ptr.as_ptr() as usize;
+        let after = self
+            .take_region_at(start + old_size)
+            .map(|region| region.size);
+        let available = old_size + after.unwrap_or(0);
+        // What's left has to make a region of its own
+        if new_size <= available {
+            let left_over = available - new_size;
+            if left_over == 0 || left_over >= mem::size_of::<Node>() {
+                if left_over > 0 {
+                    self.add_free_region(start + new_size, left_over);
+                }
+                // Like an allocation, `NextFit` carries on from the end of it
+                self.rover = start + new_size;
+                return true;
+            }
+        }
+        if let Some(size) = after {
+            self.add_free_region(start + old_size, size);
+        }
+        false
+    }
+}
+
+unsafe impl GlobalAlloc for Locked<Allocator> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let mut ll = self.lock();
+        match ll.allocate(layout) {
+            Ok(p) => p.as_ptr(),
+            Err(_) => ptr::null_mut(),
+        }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let ptr = NonNull::new(ptr).unwrap();
+        self.lock().deallocate(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        if self
+            .lock()
+            .resize_in_place(NonNull::new(ptr).unwrap(), layout, new_size)
+        {
+            return ptr;
+        }
+        let new_layout = Layout::from_size_align_unchecked(new_size, layout.align());
+        let new_ptr = self.alloc(new_layout);
+        if !new_ptr.is_null() {
+            ptr::copy_nonoverlapping(ptr, new_ptr, layout.size().min(new_size));
+            self.dealloc(ptr, layout);
+        }
+        new_ptr
+    }
+}
+
+/// An allocator with free regions of 256 and 128 bytes, in that order, and the rest of
+/// the arena after them, returned as `(allocator, first, second, rest)`.
+#[cfg(test)]
+fn holes(arena: &Arena, policy: Policy) -> (Locked<Allocator>, usize, usize, usize) {
+    let alloc = Locked::new(Allocator::empty());
+    unsafe {
+        alloc
+            .lock()
+            .init_with_policy(arena.start(), arena.size(), policy)
+    };
+    let sizes = [64, 256, 64, 128, 64];
+    let ptrs: Vec<_> = sizes
+        .iter()
+        .map(|&size| unsafe { alloc.alloc(Layout::from_size_align(size, 8).unwrap()) })
+        .collect();
+    unsafe {
+        alloc.dealloc(ptrs[1], Layout::from_size_align(256, 8).unwrap());
+        alloc.dealloc(ptrs[3], Layout::from_size_align(128, 8).unwrap());
+    }
+    let rest = ptrs[4] as usize + 64;
+    (alloc, ptrs[1] as usize, ptrs[3] as usize, rest)
+}
+
+#[test]
+fn test_linked_list_policies() {
+    let arena = Arena::new(4096);
+    let l = Layout::from_size_align(100, 8).unwrap();
+    for &(policy, pick) in &[
+        (Policy::FirstFit, 0),
+        (Policy::BestFit, 1),
+        (Policy::WorstFit, 2),
+        // Carrying on from the last allocation
+        (Policy::NextFit, 2),
+    ] {
+        let (alloc, first, second, rest) = holes(&arena, policy);
+        let p = unsafe { alloc.alloc(l) } as usize;
+        assert_eq!(p, [first, second, rest][pick], "{:?}", policy);
+    }
+}
+
+#[test]
+fn test_linked_list_next_fit_wraps() {
+    let arena = Arena::new(512);
+    let alloc = Locked::new(Allocator::empty());
+    unsafe {
+        alloc
+            .lock()
+            .init_with_policy(arena.start(), arena.size(), Policy::NextFit)
+    };
+    let l = Layout::from_size_align(128, 8).unwrap();
+    unsafe {
+        let ptrs: Vec<_> = (0..4).map(|_| alloc.alloc(l)).collect();
+        alloc.dealloc(ptrs[0], l);
+        alloc.dealloc(ptrs[2], l);
+        // Nothing's free after the last allocation, so it starts again from the front
+        assert_eq!(alloc.alloc(l), ptrs[0]);
+        assert_eq!(alloc.alloc(l), ptrs[2]);
+    }
+}
+
+#[test]
+fn test_linked_list_next_fit_after_resize() {
+    let arena = Arena::new(4096);
+    let alloc = Locked::new(Allocator::empty());
+    unsafe {
+        alloc
+            .lock()
+            .init_with_policy(arena.start(), arena.size(), Policy::NextFit)
+    };
+    let l = Layout::from_size_align(128, 8).unwrap();
+    unsafe {
+        let ptrs: Vec<_> = (0..4).map(|_| alloc.alloc(l)).collect();
+        alloc.dealloc(ptrs[1], l);
+        // Growing into half of the hole moves the rover to the end of the allocation
+        assert_eq!(alloc.realloc(ptrs[0], l, 192), ptrs[0]);
+        let small = Layout::from_size_align(32, 8).unwrap();
+        assert_eq!(alloc.alloc(small), ptrs[0].add(192));
+    }
+}
+
+#[test]
+fn test_linked_list_realloc_in_place() {
+    let arena = Arena::new(4096);
+    let alloc = arena_allocator::<Allocator>(&arena);
+    let l = Layout::from_size_align(64, 8).unwrap();
+    unsafe {
+        let p1 = alloc.alloc(l);
+        let p2 = alloc.alloc(l);
+        alloc.dealloc(p2, l);
+        // Grows into where p2 was and beyond
+        assert_eq!(alloc.realloc(p1, l, 1000), p1);
+        let l = Layout::from_size_align(1000, 8).unwrap();
+        // And shrinks, giving the end back
+        assert_eq!(alloc.realloc(p1, l, 32), p1);
+        let regions: Vec<_> = alloc.lock().free_regions().collect();
+        assert_eq!(regions, [(p1 as usize + 32, arena.size() - 32)]);
+
+        // Moves when the next region is in use
+        let l = Layout::from_size_align(32, 8).unwrap();
+        let p3 = alloc.alloc(l);
+        p1.write_bytes(7, 32);
+        let moved = alloc.realloc(p1, l, 64);
+        assert_eq!(moved, p3.add(32));
+        assert!((0..32).all(|i| *moved.add(i) == 7));
+    }
+}
+
+#[test]
+fn test_linked_list_realloc_leaves_a_node() {
+    let arena = Arena::new(4096);
+    let alloc = arena_allocator::<Allocator>(&arena);
+    let l = Layout::from_size_align(64, 8).unwrap();
+    unsafe {
+        let p1 = alloc.alloc(l);
+        let p2 = alloc.alloc(l);
+        // Shrinking by less than a node can't give anything back, so it moves
+        assert_ne!(alloc.realloc(p1, l, 56), p1);
+        // Growing to leave less than a node of the region after has to move too
+        let end = arena.size() - 128;
+        assert_ne!(alloc.realloc(p2, l, 64 + end - 8), p2);
+    }
+}
+
+#[test]
+fn test_linked_list_policies_random_workload() {
+    for &policy in &[Policy::BestFit, Policy::NextFit, Policy::WorstFit] {
+        check_random_workload(
+            16,
+            2000,
+            8192,
+            |arena| {
+                let alloc = Locked::new(Allocator::empty());
+                unsafe {
+                    alloc
+                        .lock()
+                        .init_with_policy(arena.start(), arena.size(), policy)
+                };
+                alloc
+            },
+            |alloc, model| model.check_free_regions(alloc.lock().free_regions()),
+            |alloc, arena, seed| {
+                let regions: Vec<_> = alloc.lock().free_regions().collect();
+                let whole = [(arena.start(), arena.size())];
+                assert_eq!(regions, whole, "{:?} seed {}", policy, seed);
+            },
+        );
+    }
+}
+
+#[test]
+fn test_linked_list_realloc_random_workload() {
+    for seed in 0..16 {
+        let arena = Arena::new(64 * 1024);
+        let alloc = arena_allocator::<Allocator>(&arena);
+        let mut rng = Rng::new(seed);
+        let mut model = Model::new(&arena);
+        for _ in 0..2000 {
+            match rng.below(5) {
+                _ if model.is_empty() => {
+                    model.alloc(&alloc, rng.layout(4096));
+                }
+                0 | 1 => {
+                    model.alloc(&alloc, rng.layout(4096));
+                }
+                2 => model.free(&alloc, rng.below(model.len())),
+                _ => {
+                    let index = rng.below(model.len());
+                    model.realloc(&alloc, index, 1 + rng.below(4096));
+                }
+            }
+            model.check_free_regions(alloc.lock().free_regions());
+        }
+        model.free_all(&alloc);
+        let regions: Vec<_> = alloc.lock().free_regions().collect();
+        assert_eq!(regions, [(arena.start(), arena.size())], "seed {}", seed);
+    }
+}
+
+#[test]
+fn test_linked_list_stays_sorted_trivial() {
+    let arena = Arena::new(512);
+    let alloc = arena_allocator::<Allocator>(&arena);
+    let l1 = Layout::from_size_align(2, 1).expect("invalid layout");
+    unsafe {
+        let p1 = alloc.alloc(l1);
+        assert!(!p1.is_null(), "failed to allocate first block");
+
+        // Put the block back on our free list
+        alloc.dealloc(p1, l1);
+        let pnew = alloc.alloc(l1);
+        assert_eq!(pnew, p1, "should have reused the first node found");
+    }
+}
+
+#[test]
+fn test_linked_list_merge_after() {
+    // Test whether our logic for merging after a given node works
+    let arena = Arena::new(512);
+    let alloc = arena_allocator::<Allocator>(&arena);
+    let l = Layout::from_size_align(128, 8).expect("invalid layout");
+    unsafe {
+        let p1 = alloc.alloc(l);
+        assert!(!p1.is_null(), "failed to allocate first block");
+        let p2 = alloc.alloc(l);
+        assert!(!p2.is_null(), "failed to allocate second block");
+        let p3 = alloc.alloc(l);
+        assert!(!p3.is_null(), "failed to allocate third block");
+
+        // Put the first block at the head of the free list
+        alloc.dealloc(p1, l);
+        alloc.dealloc(p2, l);
+
+        // p2 should be merged into p1, but there should be a gap after it
+        let double_l = Layout::from_size_align(256, 8).expect("invalid double layout");
+        let p = alloc.alloc(double_l);
+        assert_eq!(p, p1, "We should be able to use a block starting at p1");
+        assert_eq!(alloc.lock().free_regions().count(), 1);
+    }
+}
+
+#[test]
+fn test_linked_list_merge_before() {
+    let arena = Arena::new(512);
+    let alloc = arena_allocator::<Allocator>(&arena);
+    let l = Layout::from_size_align(128, 8).expect("invalid layout");
+    unsafe {
+        let p1 = alloc.alloc(l);
+        let p2 = alloc.alloc(l);
+        let p3 = alloc.alloc(l);
+        assert!(!p3.is_null());
+
+        // p1 goes in front of p2, and takes it over along with the rest of the list
+        alloc.dealloc(p2, l);
+        alloc.dealloc(p1, l);
+        let regions: Vec<_> = alloc.lock().free_regions().collect();
+        assert_eq!(
+            regions,
+            [(p1 as usize, 256), (p3 as usize + 128, 128)],
+            "p1 and p2 should be merged"
+        );
+
+        // Filling the gap joins everything up
+        alloc.dealloc(p3, l);
+        let regions: Vec<_> = alloc.lock().free_regions().collect();
+        assert_eq!(regions, [(arena.start(), arena.size())]);
+    }
+}
+
+#[test]
+fn test_linked_list_aligned_allocation_keeps_gap() {
+    let arena = Arena::new(4096);
+    let alloc = arena_allocator::<Allocator>(&arena);
+    unsafe {
+        let small = Layout::from_size_align(8, 8).unwrap();
+        let aligned = Layout::from_size_align(64, 1024).unwrap();
+        let p1 = alloc.alloc(small);
+        let p2 = alloc.alloc(aligned);
+        assert_eq!(p2 as usize % 1024, 0);
+        // The space between them is still there to use
+        let regions: Vec<_> = alloc.lock().free_regions().collect();
+        assert_eq!(regions[0], (p1 as usize + 16, 1024 - 16));
+
+        alloc.dealloc(p2, aligned);
+        alloc.dealloc(p1, small);
+        let regions: Vec<_> = alloc.lock().free_regions().collect();
+        assert_eq!(regions, [(arena.start(), arena.size())]);
+    }
+}
+
+#[test]
+fn test_linked_list_random_workload() {
+    check_random_workload(
+        64,
+        2000,
+        8192,
+        arena_allocator::<Allocator>,
+        |alloc, model| model.check_free_regions(alloc.lock().free_regions()),
+        |alloc, arena, seed| {
+            let regions: Vec<_> = alloc.lock().free_regions().collect();
+            assert_eq!(regions, [(arena.start(), arena.size())], "seed {}", seed);
+        },
+    );
+}
@@ -0,0 +1,548 @@
+//! Caches of objects of one type, in slabs of whole pages.
+//!
+//! A cache is made with a constructor, which is run on every object of a slab when the
+//! slab is added. Objects go back to their cache as they are, without being dropped, so
+//! whatever the constructor set up doesn't need doing again the next time the object
+//! is handed out. They're only dropped when their slab's page is given back.
+//!
+//! A slab is a page starting with a `Slab` header, then the slab's free list, as an
+//! array of the index of the next free object after each one, then the objects. Each
+//! cache keeps its slabs on three lists: partial, full and empty. Objects come from a
+//! partial slab if there is one, so the empty ones stay empty, to be reclaimed when
+//! memory runs short.
+
+use super::align_up;
+use core::marker::PhantomData;
+use core::ops::{Deref, DerefMut};
+use core::ptr::{self, NonNull};
+use core::{fmt, mem};
+use spin::Mutex;
+
+#[cfg(test)]
+use crate::testing::{Pages, Rng};
+#[cfg(test)]
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+pub const PAGE_SIZE: usize = 4096;
+
+/// Where caches get their pages from.
+pub trait PageSource {
+    /// A `PAGE_SIZE` aligned page, or `None` if there are none to spare.
+    fn alloc_page(&self) -> Option<usize>;
+
+    /// Take back a page.
+    ///
+    /// # Safety
+    ///
+    /// `page` has to have come from `alloc_page`, and not be used any more.
+    unsafe fn free_page(&self, page: usize);
+}
+
+/// How a cache is doing.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct CacheStats {
+    pub object_size: usize,
+    pub per_slab: usize,
+    /// Objects handed out.
+    pub in_use: usize,
+    /// Slabs on each list.
+    pub partial: usize,
+    pub full: usize,
+    pub empty: usize,
+}
+
+/// A cache of any type, to list them and reclaim from them all.
+pub trait AnyCache: Sync {
+    fn name(&self) -> &'static str;
+
+    fn stats(&self) -> CacheStats;
+
+    /// Give back the pages of all the empty slabs, returning how many there were.
+    fn reclaim(&self) -> usize;
+}
+
+/// Marks the end of a slab's free list.
+const NONE: u16 = u16::MAX;
+
+struct Slab {
+    prev: *mut Slab,
+    next: *mut Slab,
+    /// Index of the first free object, or `NONE`.
+    free: u16,
+    in_use: u16,
+}
+
+/// A list of slabs, linked through their headers.
+struct List {
+    head: *mut Slab,
+    len: usize,
+}
+
+impl List {
+    const fn new() -> Self {
+        List {
+            head: ptr::null_mut(),
+            len: 0,
+        }
+    }
+
+    unsafe fn push(&mut self, slab: *mut Slab) {
+        (*slab).prev = ptr::null_mut();
+        (*slab).next = self.head;
+        if let Some(head) = self.head.as_mut() {
+            head.prev = slab;
+        }
+        self.head = slab;
+        self.len += 1;
+    }
+
+    unsafe fn remove(&mut self, slab: *mut Slab) {
+        let slab = &mut *slab;
+        match slab.prev.as_mut() {
+            Some(prev) => prev.next = slab.next,
+            None => self.head = slab.next,
+        }
+        if let Some(next) = slab.next.as_mut() {
+            next.prev = slab.prev;
+        }
+        self.len -= 1;
+    }
+
+    unsafe fn pop(&mut self) -> Option<*mut Slab> {
+        let slab = self.head;
+        if slab.is_null() {
+            return None;
+        }
+        self.remove(slab);
+        Some(slab)
+    }
+}
+
+struct Lists {
+    partial: List,
+    full: List,
+    empty: List,
+    in_use: usize,
+}
+
+// The slabs are only touched with the lock held, or once they're off the lists.
+unsafe impl Send for Lists {}
+
+/// Where things go in a slab of `T`s.
+#[derive(Debug, Clone, Copy)]
+struct Geometry {
+    /// Offsets of the free list and the first object.
+    free_list: usize,
+    objects: usize,
+    /// Distance from one object to the next.
+    stride: usize,
+    capacity: usize,
+}
+
+impl Geometry {
+    fn of<T>() -> Geometry {
+        let align = mem::align_of::<T>();
+        let stride = align_up(mem::size_of::<T>().max(1), align);
+        let free_list = mem::size_of::<Slab>();
+        let objects = |capacity| align_up(free_list + 2 * capacity, align);
+        let mut capacity = (PAGE_SIZE - free_list) / (stride + 2);
+        while capacity > 0 && objects(capacity) + capacity * stride > PAGE_SIZE {
+            capacity -= 1;
+        }
+        Geometry {
+            free_list,
+            objects: objects(capacity),
+            stride,
+            capacity,
+        }
+    }
+}
+
+/// A cache of `T`s, with pages from `P`.
+pub struct Cache<T, P: PageSource> {
+    name: &'static str,
+    ctor: fn() -> T,
+    source: P,
+    geometry: Geometry,
+    lists: Mutex<Lists>,
+    objects: PhantomData<T>,
+}
+
+// Objects are handed out to whoever has the cache.
+unsafe impl<T: Send, P: PageSource + Sync> Sync for Cache<T, P> {}
+
+impl<T, P: PageSource> Cache<T, P> {
+    /// Make a cache for objects made by `ctor`, which has to fit at least one of them
+    /// in a page.
+    pub fn new(name: &'static str, ctor: fn() -> T, source: P) -> Self {
+        let geometry = Geometry::of::<T>();
+        assert!(
+            geometry.capacity > 0,
+            "{} objects don't fit in a slab",
+            name
+        );
+        Cache {
+            name,
+            ctor,
+            source,
+            geometry,
+            lists: Mutex::new(Lists {
+                partial: List::new(),
+                full: List::new(),
+                empty: List::new(),
+                in_use: 0,
+            }),
+            objects: PhantomData,
+        }
+    }
+
+    fn slab_of(ptr: *mut T) -> *mut Slab {
+        (ptr as usize & !(PAGE_SIZE - 1)) as *mut Slab
+    }
+
+    fn object(&self, slab: *mut Slab, index: u16) -> *mut T {
+        (slab as usize + self.geometry.objects + index as usize * self.geometry.stride) as *mut T
+    }
+
+    fn next_free(&self, slab: *mut Slab, index: u16) -> *mut u16 {
+        (slab as usize + self.geometry.free_list + index as usize * 2) as *mut u16
+    }
+
+    /// An object from the cache, or `None` if there's no room and no page for another
+    /// slab.
+    pub fn alloc(&self) -> Option<Obj<'_, T, P>> {
+        loop {
+            {
+                let mut lists = self.lists.lock();
+                if lists.partial.head.is_null() {
+                    if let Some(slab) = unsafe { lists.empty.pop() } {
+                        unsafe { lists.partial.push(slab) };
+                    }
+                }
+                let slab = lists.partial.head;
+                if !slab.is_null() {
+                    return Some(unsafe { self.take(&mut lists, slab) });
+                }
+            }
+            // Not with the lock held: the source might reclaim from this cache to find
+            // a page, and the constructor might want an object from it
+            let page = self.source.alloc_page()?;
+            let slab = unsafe { self.init_slab(page) };
+            unsafe { self.lists.lock().empty.push(slab) };
+        }
+    }
+
+    /// Take the first free object of `slab`, which is on the partial list.
+    unsafe fn take(&self, lists: &mut Lists, slab: *mut Slab) -> Obj<'_, T, P> {
+        let index = (*slab).free;
+        (*slab).free = *self.next_free(slab, index);
+        (*slab).in_use += 1;
+        lists.in_use += 1;
+        if (*slab).free == NONE {
+            lists.partial.remove(slab);
+            lists.full.push(slab);
+        }
+        Obj {
+            cache: self,
+            ptr: NonNull::new_unchecked(self.object(slab, index)),
+        }
+    }
+
+    /// Put an object back, as it is.
+    unsafe fn free(&self, ptr: *mut T) {
+        let slab = Self::slab_of(ptr);
+        let index =
+            ((ptr as usize - slab as usize - self.geometry.objects) / self.geometry.stride) as u16;
+        let mut lists = self.lists.lock();
+        let was_full = (*slab).free == NONE;
+        *self.next_free(slab, index) = (*slab).free;
+        (*slab).free = index;
+        (*slab).in_use -= 1;
+        lists.in_use -= 1;
+        if was_full {
+            lists.full.remove(slab);
+        } else if (*slab).in_use == 0 {
+            lists.partial.remove(slab);
+        }
+        if (*slab).in_use == 0 {
+            lists.empty.push(slab);
+        } else if was_full {
+            lists.partial.push(slab);
+        }
+    }
+
+    /// Lay out a slab in `page`, constructing all its objects.
+    unsafe fn init_slab(&self, page: usize) -> *mut Slab {
+        let slab = page as *mut Slab;
+        slab.write(Slab {
+            prev: ptr::null_mut(),
+            next: ptr::null_mut(),
+            free: 0,
+            in_use: 0,
+        });
+        let capacity = self.geometry.capacity as u16;
+        for index in 0..capacity {
+            let next = if index + 1 < capacity {
+                index + 1
+            } else {
+                NONE
+            };
+            *self.next_free(slab, index) = next;
+            self.object(slab, index).write((self.ctor)());
+        }
+        slab
+    }
+
+    /// Drop all of a slab's objects and give back its page.
+    unsafe fn release_slab(&self, slab: *mut Slab) {
+        for index in 0..self.geometry.capacity as u16 {
+            ptr::drop_in_place(self.object(slab, index));
+        }
+        self.source.free_page(slab as usize);
+    }
+}
+
+impl<T, P: PageSource + Sync> AnyCache for Cache<T, P>
+where
+    T: Send,
+{
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn stats(&self) -> CacheStats {
+        let lists = self.lists.lock();
+        CacheStats {
+            object_size: mem::size_of::<T>(),
+            per_slab: self.geometry.capacity,
+            in_use: lists.in_use,
+            partial: lists.partial.len,
+            full: lists.full.len,
+            empty: lists.empty.len,
+        }
+    }
+
+    fn reclaim(&self) -> usize {
+        let mut reclaimed = 0;
+        // One at a time, as dropping the objects could use the cache
+        while let Some(slab) = unsafe { self.lists.lock().empty.pop() } {
+            unsafe { self.release_slab(slab) };
+            reclaimed += 1;
+        }
+        reclaimed
+    }
+}
+
+impl<T, P: PageSource> Drop for Cache<T, P> {
+    fn drop(&mut self) {
+        loop {
+            let lists = self.lists.get_mut();
+            // Partial or full slabs mean objects were leaked, so they're done with too
+            let slab = unsafe {
+                lists
+                    .empty
+                    .pop()
+                    .or_else(|| lists.partial.pop())
+                    .or_else(|| lists.full.pop())
+            };
+            match slab {
+                Some(slab) => unsafe { self.release_slab(slab) },
+                None => break,
+            }
+        }
+    }
+}
+
+/// An object from a `Cache`, which goes back to it when dropped.
+pub struct Obj<'a, T, P: PageSource> {
+    cache: &'a Cache<T, P>,
+    ptr: NonNull<T>,
+}
+
+unsafe impl<T: Send, P: PageSource + Sync> Send for Obj<'_, T, P> {}
+unsafe impl<T: Sync, P: PageSource + Sync> Sync for Obj<'_, T, P> {}
+
+impl<T, P: PageSource> Deref for Obj<'_, T, P> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { self.ptr.as_ref() }
+    }
+}
+
+impl<T, P: PageSource> DerefMut for Obj<'_, T, P> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { self.ptr.as_mut() }
+    }
+}
+
+impl<T, P: PageSource> Drop for Obj<'_, T, P> {
+    fn drop(&mut self) {
+        unsafe { self.cache.free(self.ptr.as_ptr()) };
+    }
+}
+
+impl<T: fmt::Debug, P: PageSource> fmt::Debug for Obj<'_, T, P> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+#[test]
+fn test_slab_geometry() {
+    #[repr(align(64))]
+    struct Aligned;
+
+    for &(geometry, size, align) in &[
+        (Geometry::of::<u8>(), 1, 1),
+        (Geometry::of::<u64>(), 8, 8),
+        (Geometry::of::<Aligned>(), 0, 64),
+        (Geometry::of::<[u8; 1000]>(), 1000, 1),
+    ] {
+        assert!(geometry.capacity > 0);
+        assert_eq!(geometry.stride % align, 0);
+        assert!(geometry.stride >= size);
+        assert_eq!(geometry.objects % align, 0);
+        assert!(geometry.free_list + 2 * geometry.capacity <= geometry.objects);
+        assert!(geometry.objects + geometry.capacity * geometry.stride <= PAGE_SIZE);
+    }
+    assert_eq!(Geometry::of::<[u8; 1000]>().capacity, 4);
+}
+
+#[test]
+fn test_slab_keeps_objects_constructed() {
+    static CONSTRUCTED: AtomicUsize = AtomicUsize::new(0);
+    fn ctor() -> u64 {
+        CONSTRUCTED.fetch_add(1, Ordering::Relaxed);
+        7
+    }
+
+    let pages = Pages::new(4);
+    let cache = Cache::new("u64", ctor, &pages);
+    let per_slab = cache.stats().per_slab;
+    let mut obj = cache.alloc().unwrap();
+    assert_eq!(*obj, 7);
+    assert_eq!(CONSTRUCTED.load(Ordering::Relaxed), per_slab);
+    *obj = 8;
+    drop(obj);
+    assert_eq!(*cache.alloc().unwrap(), 8);
+    assert_eq!(CONSTRUCTED.load(Ordering::Relaxed), per_slab);
+}
+
+#[test]
+fn test_slab_lists() {
+    let pages = Pages::new(4);
+    let cache = Cache::new("block", || [0u8; 1000], &pages);
+    let mut objs: Vec<_> = (0..5).map(|_| cache.alloc().unwrap()).collect();
+    let stats = cache.stats();
+    assert_eq!(
+        (stats.in_use, stats.full, stats.partial, stats.empty),
+        (5, 1, 1, 0)
+    );
+    assert_eq!(pages.available(), 2);
+
+    // Taking one out of the full slab makes it partial
+    let first = objs.remove(0);
+    drop(first);
+    let stats = cache.stats();
+    assert_eq!(
+        (stats.in_use, stats.full, stats.partial, stats.empty),
+        (4, 0, 2, 0)
+    );
+
+    objs.clear();
+    let stats = cache.stats();
+    assert_eq!(
+        (stats.in_use, stats.full, stats.partial, stats.empty),
+        (0, 0, 0, 2)
+    );
+    assert_eq!(cache.reclaim(), 2);
+    assert_eq!(pages.available(), 4);
+    assert_eq!(cache.stats().empty, 0);
+}
+
+#[test]
+fn test_slab_prefers_partial_slabs() {
+    let pages = Pages::new(4);
+    let cache = Cache::new("block", || [0u8; 1000], &pages);
+    let objs: Vec<_> = (0..8).map(|_| cache.alloc().unwrap()).collect();
+    // Free one from each slab, then everything else in the first
+    let mut objs = objs.into_iter();
+    let first: Vec<_> = objs.by_ref().take(4).collect();
+    let mut second: Vec<_> = objs.collect();
+    second.pop();
+    drop(first);
+    let stats = cache.stats();
+    assert_eq!((stats.partial, stats.empty), (1, 1));
+    let obj = cache.alloc().unwrap();
+    assert_eq!(cache.stats().empty, 1);
+    drop(obj);
+}
+
+#[test]
+fn test_slab_drops_on_reclaim() {
+    static DROPPED: AtomicUsize = AtomicUsize::new(0);
+    struct Counted;
+    impl Drop for Counted {
+        fn drop(&mut self) {
+            DROPPED.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    let pages = Pages::new(1);
+    let cache = Cache::new("counted", || Counted, &pages);
+    let per_slab = cache.stats().per_slab;
+    drop(cache.alloc().unwrap());
+    assert_eq!(DROPPED.load(Ordering::Relaxed), 0);
+    assert_eq!(cache.reclaim(), 1);
+    assert_eq!(DROPPED.load(Ordering::Relaxed), per_slab);
+    drop(cache.alloc().unwrap());
+    drop(cache);
+    assert_eq!(DROPPED.load(Ordering::Relaxed), 2 * per_slab);
+    assert_eq!(pages.available(), 1);
+}
+
+#[test]
+fn test_slab_out_of_pages() {
+    let pages = Pages::new(2);
+    let cache = Cache::new("block", || [0u8; 1000], &pages);
+    let mut objs: Vec<_> = core::iter::from_fn(|| cache.alloc()).collect();
+    assert_eq!(objs.len(), 8);
+    objs.truncate(3);
+    assert!(cache.alloc().is_some());
+}
+
+#[test]
+fn test_slab_random_workload() {
+    for seed in 0..16 {
+        let mut rng = Rng::new(seed);
+        let pages = Pages::new(8);
+        let cache = Cache::new("pair", || (0u64, 0u64), &pages);
+        let mut live = Vec::new();
+        for step in 0..4000u64 {
+            if live.is_empty() || rng.below(5) < 3 {
+                if let Some(mut obj) = cache.alloc() {
+                    *obj = (step, !step);
+                    live.push(obj);
+                }
+            } else {
+                let obj = live.swap_remove(rng.below(live.len()));
+                assert_eq!(obj.1, !obj.0, "seed {}", seed);
+            }
+            if rng.below(100) == 0 {
+                cache.reclaim();
+            }
+        }
+        let mut addrs: Vec<_> = live.iter().map(|obj| &**obj as *const _ as usize).collect();
+        addrs.sort_unstable();
+        addrs.dedup();
+        assert_eq!(addrs.len(), live.len());
+        for obj in &live {
+            assert_eq!(obj.1, !obj.0);
+        }
+        assert_eq!(cache.stats().in_use, live.len());
+        live.clear();
+        cache.reclaim();
+        assert_eq!(pages.available(), 8);
+    }
+}
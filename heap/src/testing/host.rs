@@ -0,0 +1,309 @@
+//! Running the allocators over ordinary memory on the host, for the tests here and the
+//! fuzz targets.
+
+use super::Rng;
+use crate::slab::{PageSource, PAGE_SIZE};
+use crate::{bump, fixed_size_block, linked_list, tlsf, Locked};
+use alloc::{vec, vec::Vec};
+use core::alloc::{GlobalAlloc, Layout};
+use spin::Mutex;
+
+/// Heap size for `check_random_workload`.
+const WORKLOAD_HEAP_SIZE: usize = 64 * 1024;
+
+/// Memory for an allocator to manage, page aligned like the kernel heap.
+pub struct Arena {
+    _buf: Vec<u8>,
+    start: usize,
+    size: usize,
+}
+
+impl Arena {
+    pub fn new(size: usize) -> Arena {
+        let buf = vec![0; size + 4096];
+        let start = crate::align_up(buf.as_ptr() as usize, 4096);
+        Arena {
+            _buf: buf,
+            start,
+            size,
+        }
+    }
+
+    pub fn start(&self) -> usize {
+        self.start
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub fn end(&self) -> usize {
+        self.start + self.size
+    }
+}
+
+/// The allocators that are handed the memory they manage once, by `init`.
+pub trait Heap {
+    fn empty() -> Self;
+
+    /// # Safety
+    ///
+    /// As for the allocator's own `init`.
+    unsafe fn init(&mut self, start: usize, size: usize);
+}
+
+macro_rules! impl_heap {
+    ($($allocator:path),*) => {
+        $(
+            impl Heap for $allocator {
+                fn empty() -> Self {
+                    <$allocator>::empty()
+                }
+
+                unsafe fn init(&mut self, start: usize, size: usize) {
+                    <$allocator>::init(self, start, size)
+                }
+            }
+        )*
+    };
+}
+
+impl_heap!(
+    bump::Allocator,
+    fixed_size_block::Allocator,
+    linked_list::Allocator,
+    tlsf::Allocator
+);
+
+/// An `A` managing all of `arena`.
+pub fn arena_allocator<A: Heap>(arena: &Arena) -> Locked<A> {
+    let alloc = Locked::new(A::empty());
+    unsafe { alloc.lock().init(arena.start(), arena.size()) };
+    alloc
+}
+
+/// Pages for slab caches, out of an `Arena`.
+pub struct Pages {
+    _arena: Arena,
+    free: Mutex<Vec<usize>>,
+}
+
+impl Pages {
+    pub fn new(count: usize) -> Pages {
+        let arena = Arena::new(count * PAGE_SIZE);
+        let free = (0..count).map(|i| arena.start() + i * PAGE_SIZE).collect();
+        Pages {
+            _arena: arena,
+            free: Mutex::new(free),
+        }
+    }
+
+    /// Pages not handed out.
+    pub fn available(&self) -> usize {
+        self.free.lock().len()
+    }
+}
+
+impl PageSource for &Pages {
+    fn alloc_page(&self) -> Option<usize> {
+        self.free.lock().pop()
+    }
+
+    unsafe fn free_page(&self, page: usize) {
+        let mut free = self.free.lock();
+        assert!(!free.contains(&page), "page {:#x} freed twice", page);
+        free.push(page);
+    }
+}
+
+/// The allocations that should be live, which every new one is checked against.
+///
+/// Each allocation is filled with a byte of its own, so anything else written over
+/// it, like the allocator's bookkeeping, shows up when it's freed.
+pub struct Model {
+    start: usize,
+    end: usize,
+    live: Vec<(usize, Layout, u8)>,
+    filled: u8,
+}
+
+impl Model {
+    pub fn new(arena: &Arena) -> Model {
+        Model {
+            start: arena.start(),
+            end: arena.end(),
+            live: Vec::new(),
+            filled: 0,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.live.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.live.len()
+    }
+
+    /// Allocate `layout` from `allocator` and check it. Returns whether it succeeded.
+    pub fn alloc(&mut self, allocator: &impl GlobalAlloc, layout: Layout) -> bool {
+        let ptr = unsafe { allocator.alloc(layout) };
+        if ptr.is_null() {
+            return false;
+        }
+        self.add(ptr, layout);
+        true
+    }
+
+    /// Free the `index`th live allocation, checking it wasn't written over.
+    pub fn free(&mut self, allocator: &impl GlobalAlloc, index: usize) {
+        let (start, layout, fill) = self.live.swap_remove(index);
+        check_fill(start, layout.size(), fill);
+        unsafe { allocator.dealloc(start as *mut u8, layout) };
+    }
+
+    /// Resize the `index`th live allocation, checking its contents came along. Returns
+    /// whether it succeeded, leaving the allocation as it was if not.
+    pub fn realloc(&mut self, allocator: &impl GlobalAlloc, index: usize, new_size: usize) -> bool {
+        let (start, layout, fill) = self.live[index];
+        check_fill(start, layout.size(), fill);
+        let ptr = unsafe { allocator.realloc(start as *mut u8, layout, new_size) };
+        if ptr.is_null() {
+            return false;
+        }
+        self.live.swap_remove(index);
+        check_fill(ptr as usize, layout.size().min(new_size), fill);
+        self.add(
+            ptr,
+            Layout::from_size_align(new_size, layout.align()).unwrap(),
+        );
+        true
+    }
+
+    /// Check a new allocation against the others and fill it.
+    fn add(&mut self, ptr: *mut u8, layout: Layout) {
+        let (start, end) = (ptr as usize, ptr as usize + layout.size());
+        assert_eq!(
+            start % layout.align(),
+            0,
+            "{:?} misaligned at {:#x}",
+            layout,
+            start
+        );
+        assert!(
+            start >= self.start && end <= self.end,
+            "{:#x}..{:#x} outside of the heap",
+            start,
+            end
+        );
+        for &(other, other_layout, _) in &self.live {
+            let other_end = other + other_layout.size();
+            assert!(
+                end <= other || start >= other_end,
+                "{:#x}..{:#x} overlaps {:#x}..{:#x}",
+                start,
+                end,
+                other,
+                other_end
+            );
+        }
+        self.filled = self.filled.wrapping_add(1);
+        unsafe { ptr.write_bytes(self.filled, layout.size()) };
+        self.live.push((start, layout, self.filled));
+    }
+
+    /// Check a free list, given as `(start, size)` pairs: it has to be in address
+    /// order, with neighbouring regions merged, and clear of everything allocated.
+    pub fn check_free_regions(&self, regions: impl Iterator<Item = (usize, usize)>) {
+        let mut last_end = None;
+        for (start, size) in regions {
+            let end = start + size;
+            assert!(
+                start >= self.start && end <= self.end,
+                "free region {:#x}..{:#x} outside of the heap",
+                start,
+                end
+            );
+            if let Some(last_end) = last_end {
+                assert!(
+                    start > last_end,
+                    "free region {:#x} out of order or not merged",
+                    start
+                );
+            }
+            for &(other, layout, _) in &self.live {
+                let other_end = other + layout.size();
+                assert!(
+                    end <= other || start >= other_end,
+                    "free region {:#x}..{:#x} overlaps {:#x}..{:#x}",
+                    start,
+                    end,
+                    other,
+                    other_end
+                );
+            }
+            last_end = Some(end);
+        }
+    }
+
+    pub fn free_all(&mut self, allocator: &impl GlobalAlloc) {
+        while !self.is_empty() {
+            self.free(allocator, self.len() - 1);
+        }
+    }
+}
+
+fn check_fill(start: usize, len: usize, fill: u8) {
+    let bytes = unsafe { core::slice::from_raw_parts(start as *const u8, len) };
+    if let Some(i) = bytes.iter().position(|&b| b != fill) {
+        panic!(
+            "byte {} of the allocation at {:#x} was overwritten",
+            i, start
+        );
+    }
+}
+
+/// Randomly allocate and free for `steps` steps, checking every allocation, and leave
+/// what's still allocated in the returned model.
+pub fn random_workload(
+    allocator: &impl GlobalAlloc,
+    arena: &Arena,
+    seed: u64,
+    steps: usize,
+    max_size: usize,
+) -> Model {
+    let mut rng = Rng::new(seed);
+    let mut model = Model::new(arena);
+    for _ in 0..steps {
+        // Lean towards allocating, to fill the heap up now and then
+        if model.is_empty() || rng.below(5) < 3 {
+            let layout = rng.layout(max_size);
+            model.alloc(allocator, layout);
+        } else {
+            let index = rng.below(model.len());
+            model.free(allocator, index);
+        }
+    }
+    model
+}
+
+/// Run `random_workload` once for each seed below `seeds`, on an allocator made by
+/// `new` over a fresh arena. `live` is called with what's left allocated at the end,
+/// and `freed` with the seed once that's all been freed.
+pub fn check_random_workload<A: GlobalAlloc>(
+    seeds: u64,
+    steps: usize,
+    max_size: usize,
+    new: impl Fn(&Arena) -> A,
+    live: impl Fn(&A, &Model),
+    freed: impl Fn(&A, &Arena, u64),
+) {
+    for seed in 0..seeds {
+        let arena = Arena::new(WORKLOAD_HEAP_SIZE);
+        let alloc = new(&arena);
+        let mut model = random_workload(&alloc, &arena, seed, steps, max_size);
+        live(&alloc, &model);
+        model.free_all(&alloc);
+        freed(&alloc, &arena, seed);
+    }
+}
@@ -0,0 +1,10 @@
+//! Helpers for exercising the allocators, with the `testing` feature.
+//!
+//! [`Rng`] is for workloads run in the kernel too, so none of this needs more than
+//! `alloc`.
+
+mod host;
+mod rng;
+
+pub use host::*;
+pub use rng::Rng;
@@ -0,0 +1,37 @@
+use core::alloc::Layout;
+
+/// xorshift64*, so a failing run can be repeated from its seed.
+pub struct Rng(u64);
+
+impl Rng {
+    pub fn new(seed: u64) -> Rng {
+        Rng(seed.wrapping_mul(0x9e37_79b9_7f4a_7c15) | 1)
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    /// A number in `0..n`.
+    pub fn below(&mut self, n: usize) -> usize {
+        (self.next_u64() % n as u64) as usize
+    }
+
+    /// Mostly small sizes and alignments, with the odd large one.
+    pub fn layout(&mut self, max_size: usize) -> Layout {
+        let size = match self.below(8) {
+            0..=4 => 1 + self.below(64),
+            5 | 6 => 1 + self.below(512),
+            _ => 1 + self.below(max_size),
+        };
+        let align = match self.below(8) {
+            0..=5 => 1 << self.below(4),
+            6 => 1 << self.below(7),
+            _ => 1 << self.below(13),
+        };
+        Layout::from_size_align(size, align).unwrap()
+    }
+}
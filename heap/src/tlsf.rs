@@ -0,0 +1,507 @@
+//! Two-Level Segregated Fit: free blocks are kept in lists by size class, found through
+//! two levels of bitmaps, so allocating and freeing take bounded time however
+//! fragmented the heap gets.
+//!
+//! The first level splits sizes by powers of two, and the second splits each of those
+//! into `SL_COUNT` equal ranges. Every block starts with a header holding its size and
+//! the address of the block before it, boundary tags which let a freed block merge with
+//! both its neighbours straight away, so no two free blocks are ever next to each
+//! other. The heap ends with an empty block that's always in use, so every block has
+//! one after it.
+
+use super::align_up;
+use super::locked::Locked;
+use core::alloc::{GlobalAlloc, Layout};
+use core::mem;
+use core::ptr;
+
+#[cfg(test)]
+use crate::testing::{arena_allocator, check_random_workload, Arena, Model, Rng};
+
+/// Alignment of every block, and so the granularity of sizes.
+const ALIGN: usize = 16;
+const ALIGN_LOG2: usize = 4;
+
+/// Second level lists for each first level class.
+const SL_LOG2: usize = 4;
+const SL_COUNT: usize = 1 << SL_LOG2;
+
+/// Sizes below this all go in the first first level class, split linearly.
+const SMALL_SIZE: usize = SL_COUNT * ALIGN;
+const FL_SHIFT: usize = SL_LOG2 + ALIGN_LOG2;
+/// The biggest block is just under `1 << (FL_MAX + 1)` bytes.
+const FL_MAX: usize = 32;
+const FL_COUNT: usize = FL_MAX - FL_SHIFT + 1;
+
+const HEADER: usize = mem::size_of::<Header>();
+/// The smallest block, with room for the free list links.
+const MIN_SIZE: usize = mem::size_of::<Links>();
+
+/// Flags in the low bits of `Header::size`.
+const FREE: usize = 1;
+const PREV_FREE: usize = 2;
+
+#[repr(C)]
+struct Header {
+    /// The block just before this one, or null for the first. Only needed while that
+    /// one's free, but kept up to date anyway.
+    prev_phys: *mut Header,
+    /// Size of the block after the header, with the flags.
+    size: usize,
+}
+
+/// Where a free block is in its list, just after its header.
+struct Links {
+    prev: *mut Header,
+    next: *mut Header,
+}
+
+impl Header {
+    fn size(&self) -> usize {
+        self.size & !(ALIGN - 1)
+    }
+
+    fn set_size(&mut self, size: usize) {
+        self.size = size | (self.size & (FREE | PREV_FREE));
+    }
+
+    fn is_free(&self) -> bool {
+        self.size & FREE != 0
+    }
+
+    fn payload(&self) -> usize {
+        self as *const Header as usize + HEADER
+    }
+
+    unsafe fn next_phys(&self) -> &'static mut Header {
+        &mut *((self.payload() + self.size()) as *mut Header)
+    }
+
+    unsafe fn links(&self) -> &'static mut Links {
+        &mut *(self.payload() as *mut Links)
+    }
+}
+
+/// The size class `size` belongs to.
+fn mapping(size: usize) -> (usize, usize) {
+    if size < SMALL_SIZE {
+        (0, size / ALIGN)
+    } else {
+        let msb = mem::size_of::<usize>() * 8 - 1 - size.leading_zeros() as usize;
+        let sl = (size >> (msb - SL_LOG2)) ^ SL_COUNT;
+        (msb - FL_SHIFT + 1, sl)
+    }
+}
+
+/// The first size class whose blocks are all at least `size`.
+fn mapping_search(size: usize) -> (usize, usize) {
+    if size < SMALL_SIZE {
+        mapping(size)
+    } else {
+        let msb = mem::size_of::<usize>() * 8 - 1 - size.leading_zeros() as usize;
+        mapping(size + (1 << (msb - SL_LOG2)) - 1)
+    }
+}
+
+pub struct Allocator {
+    /// A bit for each first level class with any free blocks, and within those, each
+    /// second level class.
+    fl_bitmap: u32,
+    sl_bitmap: [u32; FL_COUNT],
+    lists: [[*mut Header; SL_COUNT]; FL_COUNT],
+    first: *mut Header,
+}
+
+// The lists only point into memory the allocator owns.
+unsafe impl Send for Allocator {}
+
+impl Allocator {
+    pub const fn empty() -> Self {
+        Self {
+            fl_bitmap: 0,
+            sl_bitmap: [0; FL_COUNT],
+            lists: [[ptr::null_mut(); SL_COUNT]; FL_COUNT],
+            first: ptr::null_mut(),
+        }
+    }
+
+    /// Initialize the allocator with the given heap bounds.
+    ///
+    /// # Safety
+    ///
+    /// The caller must ensure that the given memory range is unused, and this is only
+    /// called once.
+    pub unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
+        let start = align_up(heap_start, ALIGN);
+        let end = (heap_start + heap_size) & !(ALIGN - 1);
+        if end < start + 2 * HEADER + MIN_SIZE {
+            return;
+        }
+        let size = end - start - 2 * HEADER;
+        assert!(mapping(size).0 < FL_COUNT, "heap too big");
+
+        let first = start as *mut Header;
+        first.write(Header {
+            prev_phys: ptr::null_mut(),
+            size: size | FREE,
+        });
+        ((*first).next_phys() as *mut Header).write(Header {
+            prev_phys: first,
+            size: PREV_FREE,
+        });
+        self.first = first;
+        self.insert(&mut *first);
+    }
+
+    /// The free blocks, as `(start, size)`, in address order.
+    pub fn free_regions(&self) -> impl Iterator<Item = (usize, usize)> + '_ {
+        let mut block = unsafe { self.first.as_ref() };
+        core::iter::from_fn(move || loop {
+            let current = block?;
+            // The end is the only empty block
+            block = match current.size() {
+                0 => None,
+                _ => Some(unsafe { &*current.next_phys() }),
+            };
+            if current.is_free() {
+                return Some((current.payload(), current.size()));
+            }
+        })
+    }
+
+    unsafe fn insert(&mut self, block: &mut Header) {
+        let (fl, sl) = mapping(block.size());
+        let next = self.lists[fl][sl];
+        *block.links() = Links {
+            prev: ptr::null_mut(),
+            next,
+        };
+        if let Some(next) = next.as_mut() {
+            next.links().prev = block;
+        }
+        self.lists[fl][sl] = block;
+        self.fl_bitmap |= 1 << fl;
+        self.sl_bitmap[fl] |= 1 << sl;
+    }
+
+    unsafe fn remove(&mut self, block: &mut Header) {
+        let (fl, sl) = mapping(block.size());
+        let links = block.links();
+        match links.prev.as_mut() {
+            Some(prev) => prev.links().next = links.next,
+            None => self.lists[fl][sl] = links.next,
+        }
+        if let Some(next) = links.next.as_mut() {
+            next.links().prev = links.prev;
+        }
+        if self.lists[fl][sl].is_null() {
+            self.sl_bitmap[fl] &= !(1 << sl);
+            if self.sl_bitmap[fl] == 0 {
+                self.fl_bitmap &= !(1 << fl);
+            }
+        }
+    }
+
+    /// Take a free block of at least `size` off its list.
+    unsafe fn take(&mut self, size: usize) -> Option<&'static mut Header> {
+        let block = match self.find(size) {
+            Some(block) => block,
+            None => {
+                // The class `size` is in has smaller blocks too, but its first one
+                // might do
+                let (fl, sl) = mapping(size);
+                match self.lists.get(fl)?[sl].as_mut() {
+                    Some(block) if block.size() >= size => block,
+                    _ => return None,
+                }
+            }
+        };
+        self.remove(block);
+        Some(block)
+    }
+
+    /// The first block in the first class with blocks all at least `size`.
+    unsafe fn find(&mut self, size: usize) -> Option<&'static mut Header> {
+        let (fl, sl) = mapping_search(size);
+        if fl >= FL_COUNT {
+            return None;
+        }
+        let sl_map = self.sl_bitmap[fl] & (!0 << sl);
+        let (fl, sl_map) = if sl_map != 0 {
+            (fl, sl_map)
+        } else {
+            let fl_map = self.fl_bitmap & (!0 << (fl + 1));
+            if fl_map == 0 {
+                return None;
+            }
+            let fl = fl_map.trailing_zeros() as usize;
+            (fl, self.sl_bitmap[fl])
+        };
+        Some(&mut *self.lists[fl][sl_map.trailing_zeros() as usize])
+    }
+
+    /// Split the front off `block`, which has been taken off its list, until its
+    /// payload is aligned to `align`, and free the front.
+    unsafe fn align_block(
+        &mut self,
+        block: &'static mut Header,
+        align: usize,
+    ) -> &'static mut Header {
+        let payload = block.payload();
+        let mut aligned = align_up(payload, align);
+        if aligned == payload {
+            return block;
+        }
+        // The front has to be big enough to be a block of its own
+        while aligned - payload < HEADER + MIN_SIZE {
+            aligned += align;
+        }
+        let gap = aligned - payload;
+        let rest = (aligned - HEADER) as *mut Header;
+        rest.write(Header {
+            prev_phys: block,
+            size: (block.size() - gap) | FREE | PREV_FREE,
+        });
+        let rest = &mut *rest;
+        rest.next_phys().prev_phys = rest;
+        block.set_size(gap - HEADER);
+        self.insert(block);
+        rest
+    }
+
+    /// Split the end off `block`, which has been taken off its list, if there's enough
+    /// left over for another block, and mark it used.
+    unsafe fn use_block(&mut self, block: &mut Header, size: usize) {
+        if block.size() >= size + HEADER + MIN_SIZE {
+            let rest = (block.payload() + size) as *mut Header;
+            rest.write(Header {
+                prev_phys: block,
+                size: (block.size() - size - HEADER) | FREE,
+            });
+            let rest = &mut *rest;
+            rest.next_phys().prev_phys = rest;
+            block.set_size(size);
+            self.insert(rest);
+        } else {
+            block.next_phys().size &= !PREV_FREE;
+        }
+        block.size &= !FREE;
+    }
+
+    unsafe fn allocate(&mut self, layout: Layout) -> *mut u8 {
+        if layout.size() > 1 << FL_MAX {
+            return ptr::null_mut();
+        }
+        let size = align_up(layout.size().max(MIN_SIZE), ALIGN);
+        let block = if layout.align() <= ALIGN {
+            self.take(size)
+        } else {
+            // Room to move up to the alignment, and leave a block in front
+            self.take(size + layout.align() + HEADER + MIN_SIZE)
+                .map(|block| self.align_block(block, layout.align()))
+        };
+        match block {
+            Some(block) => {
+                self.use_block(block, size);
+                block.payload() as *mut u8
+            }
+            None => ptr::null_mut(),
+        }
+    }
+
+    unsafe fn deallocate(&mut self, ptr: *mut u8) {
+        let mut block = &mut *((ptr as usize - HEADER) as *mut Header);
+        block.size |= FREE;
+        let next = block.next_phys();
+        if next.is_free() {
+            self.remove(next);
+            block.set_size(block.size() + HEADER + next.size());
+        }
+        if block.size & PREV_FREE != 0 {
+            let prev = &mut *block.prev_phys;
+            self.remove(prev);
+            prev.set_size(prev.size() + HEADER + block.size());
+            block = prev;
+        }
+        let next = block.next_phys();
+        next.prev_phys = block;
+        next.size |= PREV_FREE;
+        self.insert(block);
+    }
+
+    /// Check the blocks and the lists agree with each other.
+    #[cfg(test)]
+    fn check(&self) {
+        let mut free = 0;
+        let mut prev: *mut Header = ptr::null_mut();
+        let mut prev_free = false;
+        let mut block = self.first;
+        loop {
+            let b = unsafe { &*block };
+            assert_eq!(b.prev_phys, prev, "block {:#x} prev", block as usize);
+            assert_eq!(
+                b.size & PREV_FREE != 0,
+                prev_free,
+                "block {:#x}",
+                block as usize
+            );
+            assert!(
+                !(prev_free && b.is_free()),
+                "unmerged at {:#x}",
+                block as usize
+            );
+            if b.size() == 0 {
+                break;
+            }
+            if b.is_free() {
+                free += 1;
+                let (fl, sl) = mapping(b.size());
+                let mut listed = self.lists[fl][sl];
+                while listed != block {
+                    assert!(!listed.is_null(), "{:#x} not listed", block as usize);
+                    listed = unsafe { (*listed).links().next };
+                }
+            }
+            prev = block;
+            prev_free = b.is_free();
+            block = unsafe { b.next_phys() };
+        }
+
+        let mut listed = 0;
+        for fl in 0..FL_COUNT {
+            assert_eq!(self.fl_bitmap & (1 << fl) != 0, self.sl_bitmap[fl] != 0);
+            for sl in 0..SL_COUNT {
+                let head = self.lists[fl][sl];
+                assert_eq!(self.sl_bitmap[fl] & (1 << sl) != 0, !head.is_null());
+                let mut block = head;
+                while let Some(b) = unsafe { block.as_ref() } {
+                    assert!(b.is_free());
+                    assert_eq!(mapping(b.size()), (fl, sl));
+                    listed += 1;
+                    block = unsafe { b.links().next };
+                }
+            }
+        }
+        assert_eq!(listed, free);
+    }
+}
+
+unsafe impl GlobalAlloc for Locked<Allocator> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        self.lock().allocate(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, _layout: Layout) {
+        self.lock().deallocate(ptr)
+    }
+}
+
+#[test]
+fn test_tlsf_mapping() {
+    let mut last = (0, 0);
+    for size in (ALIGN..1 << 20).step_by(ALIGN) {
+        let class = mapping(size);
+        assert!(class >= last, "{} went down a class", size);
+        assert!(class.1 < SL_COUNT);
+        last = class;
+        // Every block in the class found for `size` is big enough
+        let found = mapping_search(size);
+        assert!(found >= class);
+        if found != class {
+            assert!(mapping(size - ALIGN) < found || size < SMALL_SIZE);
+        }
+    }
+    assert_eq!(mapping(SMALL_SIZE), (1, 0));
+    assert_eq!(mapping_search(SMALL_SIZE + ALIGN), (1, 1));
+}
+
+#[test]
+fn test_tlsf_merges_both_ways() {
+    let arena = Arena::new(4096);
+    let alloc = arena_allocator::<Allocator>(&arena);
+    let l = Layout::from_size_align(64, 8).unwrap();
+    let whole = [(arena.start() + HEADER, arena.size() - 2 * HEADER)];
+    unsafe {
+        let ptrs: Vec<_> = (0..4).map(|_| alloc.alloc(l)).collect();
+        alloc.dealloc(ptrs[1], l);
+        alloc.dealloc(ptrs[3], l);
+        alloc.lock().check();
+        assert_eq!(alloc.lock().free_regions().count(), 2);
+        // Between two free blocks
+        alloc.dealloc(ptrs[2], l);
+        alloc.lock().check();
+        assert_eq!(alloc.lock().free_regions().count(), 1);
+        alloc.dealloc(ptrs[0], l);
+        alloc.lock().check();
+        assert!(alloc.lock().free_regions().eq(whole.iter().copied()));
+    }
+}
+
+#[test]
+fn test_tlsf_aligned() {
+    let arena = Arena::new(64 * 1024);
+    let alloc = arena_allocator::<Allocator>(&arena);
+    let mut model = Model::new(&arena);
+    for &align in &[32, 64, 4096, 16, 256, 8192] {
+        let l = Layout::from_size_align(24, align).unwrap();
+        assert!(model.alloc(&alloc, l));
+        alloc.lock().check();
+    }
+    model.free_all(&alloc);
+    alloc.lock().check();
+    assert_eq!(alloc.lock().free_regions().count(), 1);
+}
+
+#[test]
+fn test_tlsf_out_of_memory() {
+    let arena = Arena::new(4096);
+    let alloc = arena_allocator::<Allocator>(&arena);
+    unsafe {
+        let too_big = Layout::from_size_align(4096, 8).unwrap();
+        assert!(alloc.alloc(too_big).is_null());
+        let all = Layout::from_size_align(4096 - 2 * HEADER, 8).unwrap();
+        let p = alloc.alloc(all);
+        assert_eq!(p as usize, arena.start() + HEADER);
+        assert!(alloc
+            .alloc(Layout::from_size_align(1, 1).unwrap())
+            .is_null());
+        alloc.dealloc(p, all);
+        alloc.lock().check();
+    }
+}
+
+#[test]
+fn test_tlsf_checked_workload() {
+    for seed in 0..16 {
+        let arena = Arena::new(64 * 1024);
+        let alloc = arena_allocator::<Allocator>(&arena);
+        let mut rng = Rng::new(seed);
+        let mut model = Model::new(&arena);
+        for _ in 0..1000 {
+            if model.is_empty() || rng.below(5) < 3 {
+                model.alloc(&alloc, rng.layout(8192));
+            } else {
+                model.free(&alloc, rng.below(model.len()));
+            }
+            alloc.lock().check();
+            model.check_free_regions(alloc.lock().free_regions());
+        }
+    }
+}
+
+#[test]
+fn test_tlsf_random_workload() {
+    check_random_workload(
+        64,
+        2000,
+        8192,
+        arena_allocator::<Allocator>,
+        |alloc, model| model.check_free_regions(alloc.lock().free_regions()),
+        |alloc, arena, seed| {
+            alloc.lock().check();
+            let regions: Vec<_> = alloc.lock().free_regions().collect();
+            let whole = [(arena.start() + HEADER, arena.size() - 2 * HEADER)];
+            assert_eq!(regions, whole, "seed {}", seed);
+        },
+    );
+}
@@ -1,9 +1,18 @@
-mod bump;
-mod fixed_size_block;
-mod linked_list;
-mod locked;
+//! The kernel heap, using one of the allocators from `firstos_heap`, as chosen by the
+//! `heap_*` features. `heap_debug` adds checks for misuse on top of whichever it is.
+//!
+//! Objects of a fixed size can come from [`slab`] caches instead.
 
-use locked::Locked;
+pub mod slab;
+
+pub use firstos_heap::Stats;
+
+use firstos_heap::{self as heap, Counting, Locked};
+#[cfg(feature = "heap_debug")]
+use firstos_heap::{
+    checking::{Backtrace, SITE_DEPTH},
+    Checking,
+};
 use x86_64::{
     structures::paging::{
         mapper::MapToError, FrameAllocator, Mapper, Page, PageTableFlags, Size4KiB,
@@ -15,25 +24,107 @@ pub const HEAP_START: usize = 0x_4444_4444_0000;
 pub const HEAP_SIZE: usize = 100 * 1024; // 100 KiB
 
 #[cfg(feature = "heap_fixed_block")]
-#[global_allocator]
-static ALLOCATOR: Locked<fixed_size_block::Allocator> =
-    Locked::new(fixed_size_block::Allocator::empty());
+type Backend = Locked<heap::fixed_size_block::Allocator>;
+#[cfg(feature = "heap_fixed_block")]
+const fn new_backend() -> Backend {
+    Locked::new(heap::fixed_size_block::Allocator::empty())
+}
 
 #[cfg(feature = "heap_linked_list")]
-#[global_allocator]
-static ALLOCATOR: Locked<linked_list::Allocator> = Locked::new(linked_list::Allocator::empty());
+type Backend = Locked<heap::linked_list::Allocator>;
+#[cfg(feature = "heap_linked_list")]
+const fn new_backend() -> Backend {
+    Locked::new(heap::linked_list::Allocator::empty())
+}
 
+/// Where `heap_linked_list` places allocations, as chosen by the `heap_*_fit` features.
+#[cfg(feature = "heap_linked_list")]
+const LINKED_LIST_POLICY: heap::linked_list::Policy = {
+    use heap::linked_list::Policy;
+    if cfg!(feature = "heap_best_fit") {
+        Policy::BestFit
+    } else if cfg!(feature = "heap_next_fit") {
+        Policy::NextFit
+    } else if cfg!(feature = "heap_worst_fit") {
+        Policy::WorstFit
+    } else {
+        Policy::FirstFit
+    }
+};
+
+#[cfg(feature = "heap_bump")]
+type Backend = Locked<heap::bump::Allocator>;
 #[cfg(feature = "heap_bump")]
+const fn new_backend() -> Backend {
+    Locked::new(heap::bump::Allocator::empty())
+}
+
+#[cfg(feature = "heap_tlsf")]
+type Backend = Locked<heap::tlsf::Allocator>;
+#[cfg(feature = "heap_tlsf")]
+const fn new_backend() -> Backend {
+    Locked::new(heap::tlsf::Allocator::empty())
+}
+
+#[cfg(not(feature = "heap_debug"))]
 #[global_allocator]
-static ALLOCATOR: Locked<bump::Allocator> = Locked::new(bump::Allocator::empty());
-
-/// Align the given address `addr` upwards to alignment `align`.
-///
-/// Requires that `align` is a power of two, which is guaranteed
-/// by the `GlobalAlloc` trait.
-#[inline(always)]
-fn align_up(addr: usize, align: usize) -> usize {
-    (addr + align - 1) & !(align - 1)
+static ALLOCATOR: Counting<Backend> = Counting::new(new_backend());
+
+/// With `heap_debug`, the allocator is wrapped to catch misuse, see
+/// `firstos_heap::checking`.
+#[cfg(feature = "heap_debug")]
+#[global_allocator]
+static ALLOCATOR: Counting<Checking<Backend, FramePointers>> =
+    Counting::new(Checking::new(new_backend()));
+
+#[cfg(not(feature = "heap_debug"))]
+fn backend() -> &'static Backend {
+    ALLOCATOR.inner()
+}
+
+#[cfg(feature = "heap_debug")]
+fn backend() -> &'static Backend {
+    ALLOCATOR.inner().inner()
+}
+
+/// The heap's checks, to list what's still allocated.
+#[cfg(feature = "heap_debug")]
+pub fn checking() -> &'static Checking<Backend, FramePointers> {
+    ALLOCATOR.inner()
+}
+
+/// Walks the frame pointers, which the `test-heap-debug` alias in `.cargo/config.toml`
+/// builds the kernel with.
+#[cfg(feature = "heap_debug")]
+pub struct FramePointers;
+
+#[cfg(feature = "heap_debug")]
+impl Backtrace for FramePointers {
+    fn capture(sites: &mut [usize; SITE_DEPTH]) {
+        // Furthest apart two frames can be before the chain is taken to be broken, by
+        // something built without frame pointers
+        const MAX_FRAME: usize = 64 * 1024;
+
+        let mut frame: usize;
+        unsafe { asm!("mov {}, rbp", out(reg) frame, options(nomem, nostack, preserves_flags)) };
+        for site in sites.iter_mut() {
+            if frame == 0 || frame % 8 != 0 {
+                break;
+            }
+            let frame_ptr = frame as *const usize;
+            let (next, ret) = unsafe { (*frame_ptr, *frame_ptr.add(1)) };
+            *site = ret;
+            if next <= frame || next - frame > MAX_FRAME {
+                break;
+            }
+            frame = next;
+        }
+    }
+}
+
+/// Usage statistics for the kernel heap.
+pub fn stats() -> Stats {
+    ALLOCATOR.stats()
 }
 
 pub fn init(
@@ -58,8 +149,15 @@ pub fn init(
         }
     }
 
+    #[cfg(not(feature = "heap_linked_list"))]
+    unsafe {
+        backend().lock().init(HEAP_START, HEAP_SIZE);
+    }
+    #[cfg(feature = "heap_linked_list")]
     unsafe {
-        ALLOCATOR.lock().init(HEAP_START, HEAP_SIZE);
+        backend()
+            .lock()
+            .init_with_policy(HEAP_START, HEAP_SIZE, LINKED_LIST_POLICY);
     }
 
     Ok(())
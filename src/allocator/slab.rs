@@ -0,0 +1,96 @@
+//! Slab caches for kernel objects of a fixed size, like inodes, with their pages
+//! straight from the frame allocator rather than the heap. See `firstos_heap::slab`.
+//!
+//! Caches are made with [`create`] and last for good. When the frame allocator runs
+//! out, every cache gives back its empty slabs before a cache gives up on growing.
+
+use crate::memory;
+use alloc::boxed::Box;
+use firstos_heap::slab::{self, PageSource};
+use spin::Mutex;
+use x86_64::{structures::paging::PhysFrame, VirtAddr};
+
+pub use firstos_heap::slab::{AnyCache, CacheStats};
+
+pub type Cache<T> = slab::Cache<T, Frames>;
+pub type Obj<T> = slab::Obj<'static, T, Frames>;
+
+/// Most caches there can be.
+const MAX_CACHES: usize = 32;
+
+static CACHES: Mutex<[Option<&'static dyn AnyCache>; MAX_CACHES]> = Mutex::new([None; MAX_CACHES]);
+
+/// Pages from `memory::allocate_frame`, through the mapping of physical memory.
+pub struct Frames;
+
+impl PageSource for Frames {
+    fn alloc_page(&self) -> Option<usize> {
+        let frame = memory::allocate_frame().or_else(|| {
+            reclaim_all();
+            memory::allocate_frame()
+        })?;
+        Some(memory::phys_to_virt(frame.start_address()).as_u64() as usize)
+    }
+
+    unsafe fn free_page(&self, page: usize) {
+        let addr = memory::virt_to_phys(VirtAddr::new(page as u64));
+        memory::deallocate_frame(PhysFrame::containing_address(addr));
+    }
+}
+
+/// Make a cache called `name` of objects made by `ctor`.
+pub fn create<T: Send + 'static>(name: &'static str, ctor: fn() -> T) -> &'static Cache<T> {
+    let cache: &'static Cache<T> = Box::leak(Box::new(Cache::new(name, ctor, Frames)));
+    let mut caches = CACHES.lock();
+    let slot = caches
+        .iter_mut()
+        .find(|slot| slot.is_none())
+        .expect("too many slab caches");
+    *slot = Some(cache);
+    cache
+}
+
+/// Call `f` with every cache, oldest first.
+pub fn for_each(mut f: impl FnMut(&dyn AnyCache)) {
+    // Not with the lock held, so `f` can make caches
+    let caches = *CACHES.lock();
+    for cache in caches.iter().flatten() {
+        f(*cache);
+    }
+}
+
+/// Give back the empty slabs of every cache, returning how many pages that freed.
+pub fn reclaim_all() -> usize {
+    let mut reclaimed = 0;
+    for_each(|cache| reclaimed += cache.reclaim());
+    reclaimed
+}
+
+#[test_case]
+fn test_cache_pages_come_from_frames() {
+    let cache = create("test_frames", || [0u64; 64]);
+    let free = memory::frame_stats().free;
+    let mut obj = cache.alloc().unwrap();
+    obj[63] = 1;
+    assert_eq!(memory::frame_stats().free, free - 1);
+    drop(obj);
+    assert_eq!(cache.stats().empty, 1);
+    reclaim_all();
+    assert_eq!(memory::frame_stats().free, free);
+    assert_eq!(
+        cache.stats(),
+        CacheStats {
+            object_size: 512,
+            per_slab: 7,
+            ..CacheStats::default()
+        }
+    );
+}
+
+#[test_case]
+fn test_cache_listed_by_name() {
+    create("test_listed", || 0u32);
+    let mut found = false;
+    for_each(|cache| found |= cache.name() == "test_listed");
+    assert!(found);
+}
@@ -8,7 +8,7 @@ extern crate alloc;
 
 use alloc::{boxed::Box, rc::Rc, vec, vec::Vec};
 use bootloader::BootInfo;
-use firstos::{self, allocator, memory, println};
+use firstos::{self, allocator, keyboard, logging, memory, println, serial, shell, vga};
 use x86_64::VirtAddr;
 
 use core::panic::PanicInfo;
@@ -16,8 +16,7 @@ use core::panic::PanicInfo;
 #[cfg(not(test))]
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
-    firstos::eprint!("{}", info);
-    firstos::hlt_loop();
+    firstos::panic::handle(info)
 }
 
 const MSG: &str = "We've booted! Hooray!";
@@ -31,6 +30,13 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
     let mut mapper = unsafe { memory::init(phys_mem_offset) };
     let mut frame_allocator = unsafe { memory::ListFrameAllocator::init(&boot_info) };
     allocator::init(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    memory::set_frame_allocator(frame_allocator);
+    // Only the main console keeps history, as it comes out of the small kernel heap;
+    // what went by on the log console is in the log buffer for `dmesg`.
+    vga::enable_scrollback(vga::MAIN_CONSOLE, vga::scrollback::DEFAULT_DEPTH);
+    logging::apply_boot_options();
+    keyboard::init();
+    serial::apply_boot_options();
 
     // allocate a number on the heap
     let heap_value = Box::new(41);
@@ -60,7 +66,7 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
 
     println!("{}", MSG);
 
-    firstos::hlt_loop();
+    shell::run();
 }
 
 #[cfg(test)]
@@ -0,0 +1,329 @@
+//! Write-back block buffer cache.
+//!
+//! Buffers are keyed by `(device, block)` and handed out as reference-counted
+//! [`BufferRef`]s. Writing through a buffer marks it dirty; dirty buffers are
+//! written back on [`BlockCache::flush`], when they're evicted, and
+//! periodically from the timer interrupt. Once the cache grows past its memory
+//! budget the least recently used buffers that nobody else holds are evicted.
+
+use super::{BlockDevice, BlockError};
+use alloc::{boxed::Box, collections::BTreeMap, sync::Arc, vec, vec::Vec};
+use core::sync::atomic::{AtomicBool, Ordering};
+use lazy_static::lazy_static;
+use spin::{Mutex, MutexGuard};
+
+/// Memory budget of the global cache in bytes.
+pub const DEFAULT_BUDGET: usize = 16 * 1024;
+
+/// Timer ticks between periodic flushes of the global cache, roughly five seconds
+/// at the PIT's default 18.2 Hz.
+pub const FLUSH_INTERVAL: u64 = 91;
+
+lazy_static! {
+    pub static ref CACHE: Mutex<BlockCache> = Mutex::new(BlockCache::new(DEFAULT_BUDGET));
+}
+
+/// Called from the timer interrupt to write back dirty buffers every `FLUSH_INTERVAL` ticks.
+///
+/// Skips this round if the cache or any individual buffer is in use so we never spin
+/// inside the interrupt handler.
+pub fn on_timer(ticks: u64) {
+    if ticks % FLUSH_INTERVAL != 0 {
+        return;
+    }
+    if let Some(mut cache) = CACHE.try_lock() {
+        cache.flush_nonblocking();
+    }
+}
+
+type Result<T> = core::result::Result<T, BlockError>;
+
+/// Handle for a device registered with a `BlockCache`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct DeviceId(usize);
+
+type Key = (DeviceId, u64);
+
+pub struct Buffer {
+    block: u64,
+    data: Mutex<Vec<u8>>,
+    dirty: AtomicBool,
+}
+
+pub type BufferRef = Arc<Buffer>;
+
+impl Buffer {
+    pub fn block(&self) -> u64 {
+        self.block
+    }
+
+    /// Lock the buffer contents for reading.
+    pub fn read(&self) -> MutexGuard<'_, Vec<u8>> {
+        self.data.lock()
+    }
+
+    /// Lock the buffer contents for writing, marking the buffer dirty.
+    pub fn write(&self) -> MutexGuard<'_, Vec<u8>> {
+        let data = self.data.lock();
+        self.dirty.store(true, Ordering::Release);
+        data
+    }
+
+    pub fn is_dirty(&self) -> bool {
+        self.dirty.load(Ordering::Acquire)
+    }
+}
+
+struct Entry {
+    buffer: BufferRef,
+    /// Value of the cache's clock when this entry was last used.
+    stamp: u64,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Stats {
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+    pub writebacks: u64,
+}
+
+pub struct BlockCache {
+    devices: Vec<Box<dyn BlockDevice + Send>>,
+    entries: BTreeMap<Key, Entry>,
+    /// Entries ordered from least to most recently used.
+    lru: BTreeMap<u64, Key>,
+    clock: u64,
+    budget: usize,
+    used: usize,
+    stats: Stats,
+}
+
+impl BlockCache {
+    /// Create an empty cache that tries to keep at most `budget` bytes of buffers.
+    pub fn new(budget: usize) -> Self {
+        Self {
+            devices: Vec::new(),
+            entries: BTreeMap::new(),
+            lru: BTreeMap::new(),
+            clock: 0,
+            budget,
+            used: 0,
+            stats: Stats::default(),
+        }
+    }
+
+    /// Hand a device over to the cache.
+    pub fn register(&mut self, device: Box<dyn BlockDevice + Send>) -> DeviceId {
+        self.devices.push(device);
+        DeviceId(self.devices.len() - 1)
+    }
+
+    pub fn block_size(&self, device: DeviceId) -> usize {
+        self.devices[device.0].block_size()
+    }
+
+    pub fn block_count(&self, device: DeviceId) -> u64 {
+        self.devices[device.0].block_count()
+    }
+
+    pub fn stats(&self) -> Stats {
+        self.stats
+    }
+
+    /// Bytes of buffer memory currently held by the cache.
+    pub fn used(&self) -> usize {
+        self.used
+    }
+
+    pub fn budget(&self) -> usize {
+        self.budget
+    }
+
+    /// Change the memory budget, evicting buffers if we're now over it.
+    pub fn set_budget(&mut self, budget: usize) {
+        self.budget = budget;
+        self.shrink();
+    }
+
+    /// Get the buffer for `block` of `device`, reading it from the device on a miss.
+    pub fn get(&mut self, device: DeviceId, block: u64) -> Result<BufferRef> {
+        let key = (device, block);
+        let stamp = self.tick();
+        if let Some(entry) = self.entries.get_mut(&key) {
+            self.lru.remove(&entry.stamp);
+            self.lru.insert(stamp, key);
+            entry.stamp = stamp;
+            self.stats.hits += 1;
+            return Ok(entry.buffer.clone());
+        }
+
+        self.stats.misses += 1;
+        let dev = &mut self.devices[device.0];
+        let mut data = vec![0; dev.block_size()];
+        dev.read_block(block, &mut data)?;
+        self.used += data.len();
+        let buffer = Arc::new(Buffer {
+            block,
+            data: Mutex::new(data),
+            dirty: AtomicBool::new(false),
+        });
+        self.entries.insert(
+            key,
+            Entry {
+                buffer: buffer.clone(),
+                stamp,
+            },
+        );
+        self.lru.insert(stamp, key);
+        // The buffer we're returning is still referenced, so it can't be evicted here
+        self.shrink();
+        Ok(buffer)
+    }
+
+    /// Write back every dirty buffer.
+    pub fn flush(&mut self) -> Result<()> {
+        for (&(device, _), entry) in self.entries.iter() {
+            let dev = &mut self.devices[device.0];
+            Self::write_back(dev.as_mut(), &mut self.stats, &entry.buffer, true)?;
+        }
+        Ok(())
+    }
+
+    /// Write back every dirty buffer of `device`.
+    pub fn flush_device(&mut self, device: DeviceId) -> Result<()> {
+        let range = (device, 0)..=(device, u64::MAX);
+        for (_, entry) in self.entries.range(range) {
+            let dev = &mut self.devices[device.0];
+            Self::write_back(dev.as_mut(), &mut self.stats, &entry.buffer, true)?;
+        }
+        Ok(())
+    }
+
+    /// Write back dirty buffers that aren't currently locked, ignoring errors.
+    ///
+    /// Safe to call from interrupt context.
+    fn flush_nonblocking(&mut self) {
+        for (&(device, _), entry) in self.entries.iter() {
+            let dev = &mut self.devices[device.0];
+            let _ = Self::write_back(dev.as_mut(), &mut self.stats, &entry.buffer, false);
+        }
+    }
+
+    /// Write `buffer` back to `device` if it's dirty.
+    ///
+    /// When `wait` is false a buffer whose contents are locked is left dirty.
+    fn write_back(
+        device: &mut dyn BlockDevice,
+        stats: &mut Stats,
+        buffer: &Buffer,
+        wait: bool,
+    ) -> Result<()> {
+        let data = if wait {
+            buffer.data.lock()
+        } else {
+            match buffer.data.try_lock() {
+                Some(data) => data,
+                None => return Ok(()),
+            }
+        };
+        if buffer.dirty.swap(false, Ordering::AcqRel) {
+            if let Err(e) = device.write_block(buffer.block, &data) {
+                buffer.dirty.store(true, Ordering::Release);
+                return Err(e);
+            }
+            stats.writebacks += 1;
+        }
+        Ok(())
+    }
+
+    /// Evict least recently used buffers until we're within budget.
+    ///
+    /// Buffers that are referenced outside the cache, or that fail to write back, are
+    /// skipped, so the cache may stay over budget until they're released.
+    fn shrink(&mut self) {
+        let mut stamp = 0;
+        while self.used > self.budget {
+            let victim = self.lru.range(stamp..).find_map(|(&s, key)| {
+                let entry = &self.entries[key];
+                if Arc::strong_count(&entry.buffer) == 1 {
+                    Some((s, *key))
+                } else {
+                    None
+                }
+            });
+            let (s, key) = match victim {
+                Some(v) => v,
+                None => return,
+            };
+            stamp = s + 1;
+
+            let entry = &self.entries[&key];
+            let dev = &mut self.devices[(key.0).0];
+            if Self::write_back(dev.as_mut(), &mut self.stats, &entry.buffer, true).is_err() {
+                continue;
+            }
+            let entry = self.entries.remove(&key).unwrap();
+            self.lru.remove(&entry.stamp);
+            self.used -= entry.buffer.data.lock().len();
+            self.stats.evictions += 1;
+        }
+    }
+
+    fn tick(&mut self) -> u64 {
+        self.clock += 1;
+        self.clock
+    }
+}
+
+/// A `BlockDevice` that goes through a `BlockCache`, so filesystems can sit on top of it
+/// unchanged.
+pub struct CachedDevice<'a> {
+    cache: &'a Mutex<BlockCache>,
+    device: DeviceId,
+    block_size: usize,
+    block_count: u64,
+}
+
+impl<'a> CachedDevice<'a> {
+    pub fn new(cache: &'a Mutex<BlockCache>, device: DeviceId) -> Self {
+        let (block_size, block_count) = {
+            let cache = cache.lock();
+            (cache.block_size(device), cache.block_count(device))
+        };
+        Self {
+            cache,
+            device,
+            block_size,
+            block_count,
+        }
+    }
+}
+
+impl<'a> BlockDevice for CachedDevice<'a> {
+    fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    fn block_count(&self) -> u64 {
+        self.block_count
+    }
+
+    fn read_block(&mut self, block: u64, buf: &mut [u8]) -> Result<()> {
+        if buf.len() != self.block_size {
+            return Err(BlockError::BadBufferSize);
+        }
+        let buffer = self.cache.lock().get(self.device, block)?;
+        buf.copy_from_slice(&buffer.read());
+        Ok(())
+    }
+
+    fn write_block(&mut self, block: u64, buf: &[u8]) -> Result<()> {
+        if buf.len() != self.block_size {
+            return Err(BlockError::BadBufferSize);
+        }
+        let buffer = self.cache.lock().get(self.device, block)?;
+        buffer.write().copy_from_slice(buf);
+        Ok(())
+    }
+}
@@ -0,0 +1,107 @@
+pub mod cache;
+
+use alloc::{vec, vec::Vec};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockError {
+    /// The requested block lies past the end of the device.
+    OutOfRange,
+    /// The buffer isn't exactly one block long.
+    BadBufferSize,
+    /// The device doesn't accept writes.
+    ReadOnly,
+}
+
+type Result<T> = core::result::Result<T, BlockError>;
+
+/// A device addressed in fixed-size blocks.
+pub trait BlockDevice {
+    /// Size in bytes of a single device block.
+    fn block_size(&self) -> usize;
+
+    /// Number of blocks on the device.
+    fn block_count(&self) -> u64;
+
+    /// Read block `block` into `buf`, which must be exactly `block_size()` bytes.
+    fn read_block(&mut self, block: u64, buf: &mut [u8]) -> Result<()>;
+
+    /// Write `buf`, which must be exactly `block_size()` bytes, to block `block`.
+    fn write_block(&mut self, block: u64, buf: &[u8]) -> Result<()>;
+}
+
+enum Storage {
+    Static(&'static [u8]),
+    Owned(Vec<u8>),
+}
+
+impl Storage {
+    fn as_slice(&self) -> &[u8] {
+        match self {
+            Storage::Static(s) => s,
+            Storage::Owned(v) => v.as_slice(),
+        }
+    }
+}
+
+/// A block device backed by memory.
+pub struct RamDisk {
+    block_size: usize,
+    storage: Storage,
+}
+
+impl RamDisk {
+    /// Create a zeroed, writable RAM disk of `blocks` blocks on the kernel heap.
+    pub fn new(block_size: usize, blocks: usize) -> Self {
+        Self {
+            block_size,
+            storage: Storage::Owned(vec![0; block_size * blocks]),
+        }
+    }
+
+    /// Create a read-only RAM disk over an existing image, e.g. one pulled in
+    /// with `include_bytes!`. Any trailing partial block is ignored.
+    pub fn from_static(block_size: usize, image: &'static [u8]) -> Self {
+        Self {
+            block_size,
+            storage: Storage::Static(image),
+        }
+    }
+
+    fn range(&self, block: u64, len: usize) -> Result<core::ops::Range<usize>> {
+        if len != self.block_size {
+            return Err(BlockError::BadBufferSize);
+        }
+        if block >= self.block_count() {
+            return Err(BlockError::OutOfRange);
+        }
+        let start = block as usize * self.block_size;
+        Ok(start..start + self.block_size)
+    }
+}
+
+impl BlockDevice for RamDisk {
+    fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    fn block_count(&self) -> u64 {
+        (self.storage.as_slice().len() / self.block_size) as u64
+    }
+
+    fn read_block(&mut self, block: u64, buf: &mut [u8]) -> Result<()> {
+        let range = self.range(block, buf.len())?;
+        buf.copy_from_slice(&self.storage.as_slice()[range]);
+        Ok(())
+    }
+
+    fn write_block(&mut self, block: u64, buf: &[u8]) -> Result<()> {
+        let range = self.range(block, buf.len())?;
+        match self.storage {
+            Storage::Static(_) => Err(BlockError::ReadOnly),
+            Storage::Owned(ref mut v) => {
+                v[range].copy_from_slice(buf);
+                Ok(())
+            }
+        }
+    }
+}
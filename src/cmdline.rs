@@ -0,0 +1,80 @@
+//! Kernel command line.
+//!
+//! Our bootloader can't pass one, so it's read from QEMU's firmware configuration
+//! device instead:
+//!
+//! ```text
+//! -fw_cfg name=opt/firstos/cmdline,string="keyboard.layout=uk keyboard.scancodes=2"
+//! ```
+//!
+//! Options are whitespace separated `key=value` pairs or bare `flag`s. Anything after a
+//! `--` isn't for the kernel, and is passed on to the test runner as its arguments. The
+//! command line is read on first use, which has to be after the heap is set up.
+
+use crate::qemu;
+use alloc::string::String;
+use lazy_static::lazy_static;
+
+/// fw_cfg file holding the command line.
+pub const FW_CFG_FILE: &str = "opt/firstos/cmdline";
+
+lazy_static! {
+    static ref CMDLINE: String = qemu::fw_cfg_file(FW_CFG_FILE)
+        .and_then(|bytes| String::from_utf8(bytes).ok())
+        .unwrap_or_default();
+}
+
+/// The whole command line, empty if none was given.
+pub fn cmdline() -> &'static str {
+    CMDLINE.trim_end_matches('\0')
+}
+
+/// All options as `(key, value)` pairs, in order. Flags have an empty value.
+pub fn options() -> impl Iterator<Item = (&'static str, &'static str)> {
+    parse(cmdline())
+}
+
+/// Value of the last `key=value` option for `key`, or `""` if it was given as a flag.
+pub fn get(key: &str) -> Option<&'static str> {
+    options().filter(|&(k, _)| k == key).map(|(_, v)| v).last()
+}
+
+/// Split a command line into `(key, value)` pairs, for when it has to be read without
+/// the heap.
+pub fn parse(cmdline: &str) -> impl Iterator<Item = (&str, &str)> {
+    cmdline
+        .split_whitespace()
+        .take_while(|&word| word != "--")
+        .map(|option| match option.find('=') {
+            Some(i) => (&option[..i], &option[i + 1..]),
+            None => (option, ""),
+        })
+}
+
+/// The words after `--`, which are passed through rather than being kernel options.
+pub fn passthrough(cmdline: &str) -> impl Iterator<Item = &str> {
+    cmdline
+        .split_whitespace()
+        .skip_while(|&word| word != "--")
+        .skip(1)
+}
+
+#[test_case]
+fn test_parse() {
+    use alloc::vec::Vec;
+    let options: Vec<_> = parse("  a=1 quiet\tb=x=y c= ").collect();
+    assert_eq!(
+        options,
+        [("a", "1"), ("quiet", ""), ("b", "x=y"), ("c", "")]
+    );
+}
+
+#[test_case]
+fn test_passthrough() {
+    use alloc::vec::Vec;
+    let cmdline = "a=1 -- vga --skip b=2 --";
+    let options: Vec<_> = parse(cmdline).collect();
+    assert_eq!(options, [("a", "1")]);
+    let args: Vec<_> = passthrough(cmdline).collect();
+    assert_eq!(args, ["vga", "--skip", "b=2", "--"]);
+}
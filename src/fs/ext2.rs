@@ -0,0 +1,597 @@
+//! Read-only ext2 driver.
+//!
+//! Supports revision 0 and 1 filesystems with 1 KiB through 64 KiB blocks,
+//! which covers images produced by `mke2fs -t ext2 -d <dir>`.
+
+use crate::block::{BlockDevice, BlockError};
+use alloc::{string::String, vec, vec::Vec};
+use core::mem;
+
+const SUPERBLOCK_OFFSET: u64 = 1024;
+const SUPERBLOCK_SIZE: usize = 1024;
+const EXT2_MAGIC: u16 = 0xef53;
+const GROUP_DESCRIPTOR_SIZE: usize = 32;
+
+/// Inode number of the root directory.
+pub const ROOT_INODE: u32 = 2;
+
+/// Number of direct block pointers in an inode.
+const DIRECT_BLOCKS: u64 = 12;
+const INDIRECT_BLOCK: usize = 12;
+const DOUBLE_INDIRECT_BLOCK: usize = 13;
+const TRIPLE_INDIRECT_BLOCK: usize = 14;
+
+/// Incompatible feature: directory entries carry a file type byte.
+const INCOMPAT_FILETYPE: u32 = 0x0002;
+const SUPPORTED_INCOMPAT: u32 = INCOMPAT_FILETYPE;
+
+/// Symlinks shorter than this are stored in the inode's block pointers.
+const FAST_SYMLINK_MAX: u64 = 60;
+/// How many symlinks we'll follow while resolving a single path.
+const MAX_SYMLINK_DEPTH: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    Io(BlockError),
+    BadMagic,
+    /// The filesystem uses a feature we can't read.
+    Unsupported(u32),
+    /// An on-disk structure is inconsistent.
+    Corrupt,
+    InvalidInode(u32),
+    NotFound,
+    NotADirectory,
+    NotASymlink,
+    TooManyLinks,
+    /// There isn't the memory to hold what was read.
+    OutOfMemory,
+}
+
+impl From<BlockError> for Error {
+    fn from(e: BlockError) -> Self {
+        Error::Io(e)
+    }
+}
+
+type Result<T> = core::result::Result<T, Error>;
+
+#[inline]
+fn le_u16(buf: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([buf[offset], buf[offset + 1]])
+}
+
+#[inline]
+fn le_u32(buf: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([
+        buf[offset],
+        buf[offset + 1],
+        buf[offset + 2],
+        buf[offset + 3],
+    ])
+}
+
+#[derive(Debug, Clone)]
+pub struct Superblock {
+    pub inodes_count: u32,
+    pub blocks_count: u32,
+    pub first_data_block: u32,
+    pub log_block_size: u32,
+    pub blocks_per_group: u32,
+    pub inodes_per_group: u32,
+    pub magic: u16,
+    pub rev_level: u32,
+    pub inode_size: u16,
+    pub feature_compat: u32,
+    pub feature_incompat: u32,
+    pub feature_ro_compat: u32,
+}
+
+impl Superblock {
+    fn parse(buf: &[u8]) -> Self {
+        let rev_level = le_u32(buf, 76);
+        Self {
+            inodes_count: le_u32(buf, 0),
+            blocks_count: le_u32(buf, 4),
+            first_data_block: le_u32(buf, 20),
+            log_block_size: le_u32(buf, 24),
+            blocks_per_group: le_u32(buf, 32),
+            inodes_per_group: le_u32(buf, 40),
+            magic: le_u16(buf, 56),
+            rev_level,
+            // Revision 0 filesystems have fixed 128 byte inodes and no feature flags
+            inode_size: if rev_level == 0 { 128 } else { le_u16(buf, 88) },
+            feature_compat: if rev_level == 0 { 0 } else { le_u32(buf, 92) },
+            feature_incompat: if rev_level == 0 { 0 } else { le_u32(buf, 96) },
+            feature_ro_compat: if rev_level == 0 { 0 } else { le_u32(buf, 100) },
+        }
+    }
+
+    pub fn block_size(&self) -> usize {
+        1024 << self.log_block_size
+    }
+
+    fn group_count(&self) -> u32 {
+        let data_blocks = (self.blocks_count - self.first_data_block) as u64;
+        let per_group = self.blocks_per_group as u64;
+        ((data_blocks + per_group - 1) / per_group) as u32
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct GroupDescriptor {
+    inode_table: u32,
+}
+
+impl GroupDescriptor {
+    fn parse(buf: &[u8]) -> Self {
+        Self {
+            inode_table: le_u32(buf, 8),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileType {
+    Unknown,
+    Regular,
+    Directory,
+    CharDevice,
+    BlockDevice,
+    Fifo,
+    Socket,
+    Symlink,
+}
+
+impl FileType {
+    fn from_mode(mode: u16) -> Self {
+        match mode & 0xf000 {
+            0x1000 => FileType::Fifo,
+            0x2000 => FileType::CharDevice,
+            0x4000 => FileType::Directory,
+            0x6000 => FileType::BlockDevice,
+            0x8000 => FileType::Regular,
+            0xa000 => FileType::Symlink,
+            0xc000 => FileType::Socket,
+            _ => FileType::Unknown,
+        }
+    }
+
+    fn from_dirent(file_type: u8) -> Self {
+        match file_type {
+            1 => FileType::Regular,
+            2 => FileType::Directory,
+            3 => FileType::CharDevice,
+            4 => FileType::BlockDevice,
+            5 => FileType::Fifo,
+            6 => FileType::Socket,
+            7 => FileType::Symlink,
+            _ => FileType::Unknown,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Inode {
+    pub number: u32,
+    pub mode: u16,
+    pub size: u64,
+    pub links_count: u16,
+    /// Number of 512 byte sectors allocated, including indirect and xattr blocks.
+    pub sectors: u32,
+    pub file_acl: u32,
+    block: [u32; 15],
+}
+
+impl Inode {
+    fn parse(number: u32, buf: &[u8]) -> Self {
+        let mode = le_u16(buf, 0);
+        let mut block = [0; 15];
+        for (i, b) in block.iter_mut().enumerate() {
+            *b = le_u32(buf, 40 + 4 * i);
+        }
+        let size_low = le_u32(buf, 4) as u64;
+        // Only regular files use the high half of the size (`i_dir_acl` otherwise)
+        let size_high = if FileType::from_mode(mode) == FileType::Regular {
+            le_u32(buf, 108) as u64
+        } else {
+            0
+        };
+        Self {
+            number,
+            mode,
+            size: size_high << 32 | size_low,
+            links_count: le_u16(buf, 26),
+            sectors: le_u32(buf, 28),
+            file_acl: le_u32(buf, 104),
+            block,
+        }
+    }
+
+    pub fn file_type(&self) -> FileType {
+        FileType::from_mode(self.mode)
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.file_type() == FileType::Directory
+    }
+
+    /// Whether the target of this symlink lives in `i_block` rather than a data block.
+    fn is_fast_symlink(&self, block_size: usize) -> bool {
+        let acl_sectors = if self.file_acl != 0 {
+            (block_size / 512) as u32
+        } else {
+            0
+        };
+        self.size < FAST_SYMLINK_MAX && self.sectors == acl_sectors
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct DirEntry {
+    pub inode: u32,
+    pub file_type: FileType,
+    pub name: String,
+}
+
+pub struct Ext2<D: BlockDevice> {
+    device: D,
+    superblock: Superblock,
+    groups: Vec<GroupDescriptor>,
+    block_size: usize,
+    /// Bounce buffer used to read device blocks for unaligned reads.
+    sector: Vec<u8>,
+}
+
+impl<D: BlockDevice> Ext2<D> {
+    /// Mount the filesystem on `device`, validating the superblock and reading the
+    /// block group descriptor table.
+    pub fn mount(device: D) -> Result<Self> {
+        let sector = vec![0; device.block_size()];
+        let mut fs = Self {
+            device,
+            superblock: Superblock::parse(&[0; SUPERBLOCK_SIZE]),
+            groups: Vec::new(),
+            block_size: 0,
+            sector,
+        };
+
+        let mut buf = [0; SUPERBLOCK_SIZE];
+        fs.read_at(SUPERBLOCK_OFFSET, &mut buf)?;
+        let sb = Superblock::parse(&buf);
+        if sb.magic != EXT2_MAGIC {
+            return Err(Error::BadMagic);
+        }
+        let unsupported = sb.feature_incompat & !SUPPORTED_INCOMPAT;
+        if unsupported != 0 {
+            return Err(Error::Unsupported(unsupported));
+        }
+        if sb.log_block_size > 6
+            || sb.blocks_per_group == 0
+            || sb.inodes_per_group == 0
+            || sb.blocks_count <= sb.first_data_block
+        {
+            return Err(Error::Corrupt);
+        }
+        if (sb.inode_size as usize) < 128 || sb.block_size() % fs.device.block_size() != 0 {
+            return Err(Error::Corrupt);
+        }
+
+        // The descriptor table starts in the block following the superblock
+        let table_offset = (sb.first_data_block as u64 + 1) * sb.block_size() as u64;
+        let table_size = sb.group_count() as u64 * GROUP_DESCRIPTOR_SIZE as u64;
+        let device_size = fs.device.block_count() * fs.device.block_size() as u64;
+        if table_offset + table_size > device_size {
+            return Err(Error::Corrupt);
+        }
+        let mut groups = Vec::with_capacity(sb.group_count() as usize);
+        let mut desc = [0; GROUP_DESCRIPTOR_SIZE];
+        for i in 0..sb.group_count() as u64 {
+            fs.read_at(table_offset + i * GROUP_DESCRIPTOR_SIZE as u64, &mut desc)?;
+            groups.push(GroupDescriptor::parse(&desc));
+        }
+
+        fs.block_size = sb.block_size();
+        fs.superblock = sb;
+        fs.groups = groups;
+        Ok(fs)
+    }
+
+    pub fn superblock(&self) -> &Superblock {
+        &self.superblock
+    }
+
+    pub fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    /// Release the underlying device.
+    pub fn into_device(self) -> D {
+        self.device
+    }
+
+    /// Read `buf.len()` bytes starting at byte `offset` of the device.
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<()> {
+        let dev_bs = self.device.block_size() as u64;
+        let mut sector = mem::take(&mut self.sector);
+        let mut done = 0;
+        let mut result = Ok(());
+        while done < buf.len() {
+            let pos = offset + done as u64;
+            let within = (pos % dev_bs) as usize;
+            let n = (sector.len() - within).min(buf.len() - done);
+            if let Err(e) = self.device.read_block(pos / dev_bs, &mut sector) {
+                result = Err(e.into());
+                break;
+            }
+            buf[done..done + n].copy_from_slice(&sector[within..within + n]);
+            done += n;
+        }
+        self.sector = sector;
+        result
+    }
+
+    /// Read filesystem block `block` into `buf`, which must be `block_size()` bytes long.
+    pub fn read_block(&mut self, block: u32, buf: &mut [u8]) -> Result<()> {
+        if block >= self.superblock.blocks_count {
+            return Err(Error::Corrupt);
+        }
+        assert_eq!(buf.len(), self.block_size);
+        self.read_at(block as u64 * self.block_size as u64, buf)
+    }
+
+    /// Read the `index`th block pointer stored in block `block`.
+    fn read_pointer(&mut self, block: u32, index: u64) -> Result<u32> {
+        if block >= self.superblock.blocks_count {
+            return Err(Error::Corrupt);
+        }
+        let mut ptr = [0; 4];
+        let offset = block as u64 * self.block_size as u64 + index * 4;
+        self.read_at(offset, &mut ptr)?;
+        Ok(u32::from_le_bytes(ptr))
+    }
+
+    pub fn read_inode(&mut self, number: u32) -> Result<Inode> {
+        if number == 0 || number > self.superblock.inodes_count {
+            return Err(Error::InvalidInode(number));
+        }
+        let index = number - 1;
+        let group = (index / self.superblock.inodes_per_group) as usize;
+        let within = (index % self.superblock.inodes_per_group) as u64;
+        let table = self.groups.get(group).ok_or(Error::Corrupt)?.inode_table;
+
+        let inode_size = self.superblock.inode_size as u64;
+        let offset = table as u64 * self.block_size as u64 + within * inode_size;
+        // We only care about the fields in the original 128 byte inode
+        let mut buf = [0; 128];
+        self.read_at(offset, &mut buf)?;
+        Ok(Inode::parse(number, &buf))
+    }
+
+    /// Map a block index within a file onto a filesystem block.
+    ///
+    /// Returns `None` for holes in sparse files.
+    pub fn block_map(&mut self, inode: &Inode, file_block: u64) -> Result<Option<u32>> {
+        let per_block = (self.block_size / 4) as u64;
+
+        // Work out which tree the block lives in and the index at each level
+        let mut index = file_block;
+        if index < DIRECT_BLOCKS {
+            return Ok(Some(inode.block[index as usize]).filter(|&b| b != 0));
+        }
+        index -= DIRECT_BLOCKS;
+        let (root, depth) = if index < per_block {
+            (inode.block[INDIRECT_BLOCK], 1)
+        } else if index - per_block < per_block * per_block {
+            index -= per_block;
+            (inode.block[DOUBLE_INDIRECT_BLOCK], 2)
+        } else {
+            index -= per_block + per_block * per_block;
+            if index >= per_block * per_block * per_block {
+                return Err(Error::Corrupt);
+            }
+            (inode.block[TRIPLE_INDIRECT_BLOCK], 3)
+        };
+
+        let mut block = root;
+        for level in (0..depth).rev() {
+            if block == 0 {
+                return Ok(None);
+            }
+            let stride = per_block.pow(level);
+            block = self.read_pointer(block, index / stride)?;
+            index %= stride;
+        }
+        Ok(Some(block).filter(|&b| b != 0))
+    }
+
+    /// Read from the file backing `inode` starting at byte `offset`.
+    ///
+    /// Returns the number of bytes read, which is short only at end of file.
+    pub fn read(&mut self, inode: &Inode, offset: u64, buf: &mut [u8]) -> Result<usize> {
+        if offset >= inode.size {
+            return Ok(0);
+        }
+        let len = buf.len().min((inode.size - offset) as usize);
+        let bs = self.block_size as u64;
+        let mut done = 0;
+        while done < len {
+            let pos = offset + done as u64;
+            let within = pos % bs;
+            let n = ((bs - within) as usize).min(len - done);
+            let dest = &mut buf[done..done + n];
+            match self.block_map(inode, pos / bs)? {
+                Some(block) => self.read_at(block as u64 * bs + within, dest)?,
+                None => dest.iter_mut().for_each(|b| *b = 0),
+            }
+            done += n;
+        }
+        Ok(len)
+    }
+
+    /// Read an entire file into memory.
+    ///
+    /// The size on disk isn't trusted past the sectors the inode has allocated, so
+    /// sparse files with holes are refused along with corrupt ones; use `read` for
+    /// those.
+    pub fn read_to_vec(&mut self, inode: &Inode) -> Result<Vec<u8>> {
+        if inode.size > inode.sectors as u64 * 512 {
+            return Err(Error::Corrupt);
+        }
+        let mut data = Vec::new();
+        data.try_reserve_exact(inode.size as usize)
+            .map_err(|_| Error::OutOfMemory)?;
+        data.resize(inode.size as usize, 0);
+        self.read(inode, 0, &mut data)?;
+        Ok(data)
+    }
+
+    /// Call `f` for every entry in the directory `dir`, including `.` and `..`.
+    ///
+    /// Iteration stops early if `f` returns `false`.
+    pub fn for_each_entry<F>(&mut self, dir: &Inode, mut f: F) -> Result<()>
+    where
+        F: FnMut(&DirEntry) -> bool,
+    {
+        if !dir.is_dir() {
+            return Err(Error::NotADirectory);
+        }
+        let has_file_type = self.superblock.feature_incompat & INCOMPAT_FILETYPE != 0;
+        let mut block = vec![0; self.block_size];
+        let blocks = (dir.size + self.block_size as u64 - 1) / self.block_size as u64;
+        for b in 0..blocks {
+            let n = self.read(dir, b * self.block_size as u64, &mut block)?;
+            let mut offset = 0;
+            while offset + 8 <= n {
+                let inode = le_u32(&block, offset);
+                let rec_len = le_u16(&block, offset + 4) as usize;
+                let name_len = if has_file_type {
+                    block[offset + 6] as usize
+                } else {
+                    le_u16(&block, offset + 6) as usize
+                };
+                if rec_len < 8 || offset + rec_len > n || 8 + name_len > rec_len {
+                    return Err(Error::Corrupt);
+                }
+                // Unused entries have an inode number of 0
+                if inode != 0 {
+                    let name = &block[offset + 8..offset + 8 + name_len];
+                    let entry = DirEntry {
+                        inode,
+                        file_type: if has_file_type {
+                            FileType::from_dirent(block[offset + 7])
+                        } else {
+                            FileType::Unknown
+                        },
+                        name: String::from_utf8_lossy(name).into_owned(),
+                    };
+                    if !f(&entry) {
+                        return Ok(());
+                    }
+                }
+                offset += rec_len;
+            }
+        }
+        Ok(())
+    }
+
+    /// List every entry in the directory `dir`.
+    pub fn read_dir(&mut self, dir: &Inode) -> Result<Vec<DirEntry>> {
+        let mut entries = Vec::new();
+        self.for_each_entry(dir, |e| {
+            entries.push(e.clone());
+            true
+        })?;
+        Ok(entries)
+    }
+
+    /// Find `name` in the directory `dir` without following symlinks.
+    pub fn lookup(&mut self, dir: &Inode, name: &str) -> Result<Inode> {
+        let mut found = None;
+        self.for_each_entry(dir, |e| {
+            if e.name == name {
+                found = Some(e.inode);
+                false
+            } else {
+                true
+            }
+        })?;
+        match found {
+            Some(number) => self.read_inode(number),
+            None => Err(Error::NotFound),
+        }
+    }
+
+    /// Read the target of the symlink `inode`.
+    pub fn read_link(&mut self, inode: &Inode) -> Result<String> {
+        if inode.file_type() != FileType::Symlink {
+            return Err(Error::NotASymlink);
+        }
+        // Targets are kept to a block, so anything longer is a bad size
+        if inode.size > self.block_size as u64 {
+            return Err(Error::Corrupt);
+        }
+        let target = if inode.is_fast_symlink(self.block_size) {
+            inode
+                .block
+                .iter()
+                .flat_map(|b| b.to_le_bytes().to_vec())
+                .take(inode.size as usize)
+                .collect()
+        } else {
+            self.read_to_vec(inode)?
+        };
+        Ok(String::from_utf8_lossy(&target).into_owned())
+    }
+
+    /// Resolve an absolute path to an inode, following symlinks.
+    pub fn open(&mut self, path: &str) -> Result<Inode> {
+        let root = self.read_inode(ROOT_INODE)?;
+        let mut depth = 0;
+        self.resolve(&root, path, true, &mut depth)
+    }
+
+    /// Resolve an absolute path to an inode without following a symlink in the final
+    /// component.
+    pub fn open_nofollow(&mut self, path: &str) -> Result<Inode> {
+        let root = self.read_inode(ROOT_INODE)?;
+        let mut depth = 0;
+        self.resolve(&root, path, false, &mut depth)
+    }
+
+    /// Resolve `path` relative to the directory `start`.
+    ///
+    /// Absolute paths restart from the root directory. `depth` counts how many symlinks
+    /// we've traversed so cycles eventually fail with `TooManyLinks`.
+    fn resolve(
+        &mut self,
+        start: &Inode,
+        path: &str,
+        follow: bool,
+        depth: &mut usize,
+    ) -> Result<Inode> {
+        let mut current = if path.starts_with('/') {
+            self.read_inode(ROOT_INODE)?
+        } else {
+            start.clone()
+        };
+        let mut components = path.split('/').filter(|c| !c.is_empty()).peekable();
+        while let Some(name) = components.next() {
+            if !current.is_dir() {
+                return Err(Error::NotADirectory);
+            }
+            let next = self.lookup(&current, name)?;
+            let is_last = components.peek().is_none();
+            if next.file_type() == FileType::Symlink && (follow || !is_last) {
+                *depth += 1;
+                if *depth > MAX_SYMLINK_DEPTH {
+                    return Err(Error::TooManyLinks);
+                }
+                let target = self.read_link(&next)?;
+                current = self.resolve(&current, &target, true, depth)?;
+            } else {
+                current = next;
+            }
+        }
+        Ok(current)
+    }
+}
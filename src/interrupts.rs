@@ -2,7 +2,8 @@ use lazy_static::lazy_static;
 use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
 use pic8259_simple::ChainedPics;
 use spin;
-use crate::{println,print,gdt};
+use core::sync::atomic::{AtomicU64, Ordering};
+use crate::gdt;
 
 // Map chained pics to interrupts 32-47
 pub const PIC_1_OFFSET: u8 = 32;
@@ -17,6 +18,18 @@ pub static PICS: spin::Mutex<ChainedPics> = spin::Mutex::new(unsafe {
 pub enum InterruptIndex {
     Timer = PIC_1_OFFSET,
     Keyboard,
+    /// IRQ3, shared by COM2 and COM4.
+    Serial2 = PIC_1_OFFSET + 3,
+    /// IRQ4, shared by COM1 and COM3.
+    Serial1,
+    Mouse = PIC_2_OFFSET + 4,
+}
+
+impl InterruptIndex {
+    /// The IRQ line behind this interrupt.
+    pub fn irq(self) -> u8 {
+        self as u8 - PIC_1_OFFSET
+    }
 }
 
 impl Into<u8> for InterruptIndex {
@@ -43,6 +56,9 @@ lazy_static! {
         }
         idt[InterruptIndex::Timer.into()].set_handler_fn(timer_handler);
         idt[InterruptIndex::Keyboard.into()].set_handler_fn(keyboard_handler);
+        idt[InterruptIndex::Serial1.into()].set_handler_fn(serial1_handler);
+        idt[InterruptIndex::Serial2.into()].set_handler_fn(serial2_handler);
+        idt[InterruptIndex::Mouse.into()].set_handler_fn(mouse_handler);
         idt
     };
 }
@@ -51,47 +67,84 @@ pub fn init_idt() {
     IDT.load();
 }
 
+/// Unmask `irq` on the PICs, along with the cascade from the secondary PIC if needed.
+pub fn unmask_irq(irq: u8) {
+    use x86_64::instructions::port::Port;
+    let (port, bit) = if irq < 8 { (0x21, irq) } else { (0xa1, irq - 8) };
+    unsafe {
+        let mut data: Port<u8> = Port::new(port);
+        let mask = data.read();
+        data.write(mask & !(1 << bit));
+    }
+    if irq >= 8 {
+        unmask_irq(2);
+    }
+}
+
+/// Timer interrupts per second, times 65536: the PIT's input clock, which we divide
+/// by the default 65536.
+pub const PIT_FREQUENCY: u64 = 1_193_182;
+
+/// Number of timer interrupts since boot.
+static TICKS: AtomicU64 = AtomicU64::new(0);
+
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
 extern "x86-interrupt" fn breakpoint_handler(
     stack_frame: &mut InterruptStackFrame
 ) {
-    println!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
+    log::error!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
 }
 
 extern "x86-interrupt" fn timer_handler(_stack_frame: &mut InterruptStackFrame) {
-    print!(".");
+    let ticks = TICKS.fetch_add(1, Ordering::Relaxed) + 1;
+    crate::block::cache::on_timer(ticks);
     unsafe {
         PICS.lock().notify_end_of_interrupt(InterruptIndex::Timer.into());
     }
+    // Last, since a timed out test panics out of here
+    crate::testing::check_watchdog(ticks);
 }
 
 extern "x86-interrupt" fn keyboard_handler(_stack_frame: &mut InterruptStackFrame) {
     use x86_64::instructions::port::Port;
-    use pc_keyboard::{layouts, DecodedKey, HandleControl, Keyboard, ScancodeSet1};
-    use spin::Mutex;
-
-   lazy_static! {
-        static ref KEYBOARD: Mutex<Keyboard<layouts::Us104Key, ScancodeSet1>> =
-            Mutex::new(Keyboard::new(layouts::Us104Key, ScancodeSet1,
-                HandleControl::Ignore)
-            );
-    }
-    
-    let mut keyboard = KEYBOARD.lock();
+
+    // Only hotkeys are handled here, the rest is decoded by `keyboard::next_key`
     let mut port = Port::new(0x60);
     let scancode: u8 = unsafe { port.read() };
-    if let Ok(Some(keyvent)) = keyboard.add_byte(scancode) {
-        if let Some(key) = keyboard.process_keyevent(keyvent) {
-            match key {
-                DecodedKey::Unicode(chr) => print!("{}", chr),
-                DecodedKey::RawKey(key) => print!("{:?}", key),
-            }
-        }
-    }
+    crate::keyboard::handle_scancode(scancode);
     unsafe {
         PICS.lock().notify_end_of_interrupt(InterruptIndex::Keyboard.into());
     }
 }
 
+extern "x86-interrupt" fn serial1_handler(_stack_frame: &mut InterruptStackFrame) {
+    crate::serial::handle_interrupt(InterruptIndex::Serial1.irq());
+    unsafe {
+        PICS.lock().notify_end_of_interrupt(InterruptIndex::Serial1.into());
+    }
+}
+
+extern "x86-interrupt" fn serial2_handler(_stack_frame: &mut InterruptStackFrame) {
+    crate::serial::handle_interrupt(InterruptIndex::Serial2.irq());
+    unsafe {
+        PICS.lock().notify_end_of_interrupt(InterruptIndex::Serial2.into());
+    }
+}
+
+extern "x86-interrupt" fn mouse_handler(_stack_frame: &mut InterruptStackFrame) {
+    use x86_64::instructions::port::Port;
+
+    let mut port = Port::new(0x60);
+    let byte: u8 = unsafe { port.read() };
+    crate::mouse::push_byte(byte);
+    unsafe {
+        PICS.lock().notify_end_of_interrupt(InterruptIndex::Mouse.into());
+    }
+}
+
 // The error code is aalways 0, so ignore it
 extern "x86-interrupt" fn double_fault_handler(
     stack_frame: &mut InterruptStackFrame, _error_code: u64
@@ -101,11 +154,13 @@ extern "x86-interrupt" fn double_fault_handler(
 
 extern "x86-interrupt" fn page_fault_handler(stack_frame: &mut InterruptStackFrame, error_code: PageFaultErrorCode) {
     use x86_64::registers::control::Cr2;
-    println!("EXCEPTION: PAGE FAULT");
-    println!("Address: {:?}", Cr2::read());
-    println!("Error Code: {:?}", error_code);
-    println!("{:#?}", stack_frame);
-    crate::hlt_loop();
+    // Whatever faulted might hold locks we need, so leave reporting to the panic path
+    panic!(
+        "EXCEPTION: PAGE FAULT\nAddress: {:?}\nError Code: {:?}\n{:#?}",
+        Cr2::read(),
+        error_code,
+        stack_frame
+    );
 }
 
 
@@ -0,0 +1,799 @@
+//! Keyboard input.
+//!
+//! The keyboard interrupt handler carries out the console hotkeys (Alt+F1..F6 and
+//! Shift+PageUp/PageDown) itself, so they work with nobody reading input, like after a
+//! panic. Everything else is queued as raw scancodes and decoded into key presses by
+//! whoever reads input.
+//!
+//! The layout and scancode set can be changed at runtime, or at boot with the
+//! `keyboard.layout` and `keyboard.scancodes` command line options.
+
+use crate::ring::ByteRing;
+use crate::{cmdline, ps2, shell, vga};
+use alloc::{format, string::String, vec::Vec};
+use core::fmt::{self, Write};
+use lazy_static::lazy_static;
+use pc_keyboard::{
+    layouts, DecodeState, DecodedKey, HandleControl, KeyCode, KeyEvent, KeyState, Keyboard,
+    ScancodeSet as _, ScancodeSet1, ScancodeSet2,
+};
+use spin::Mutex;
+
+/// Scancodes queued by the interrupt handler.
+static SCANCODES: ByteRing<128> = ByteRing::new();
+
+/// Hotkeys are picked out of the scancodes with this. Only locked by the interrupt
+/// handler or with interrupts off.
+static HOTKEYS: Mutex<Hotkeys> = Mutex::new(Hotkeys::new(ScancodeSet::Set1));
+
+/// Take a scancode read from the keyboard controller, carrying out console hotkeys and
+/// queueing everything else. Called from the interrupt handler.
+pub(crate) fn handle_scancode(scancode: u8) {
+    let mut hotkeys = HOTKEYS.lock();
+    match hotkeys.feed(scancode) {
+        Fed::Hotkey(Hotkey::PageUp) => vga::page_up(),
+        Fed::Hotkey(Hotkey::PageDown) => vga::page_down(),
+        Fed::Hotkey(Hotkey::Console(console)) => vga::switch_console(console),
+        Fed::Pass(scancodes) => {
+            for &scancode in scancodes {
+                SCANCODES.push(scancode);
+            }
+        }
+    }
+}
+
+/// Ctrl+letter decodes to the matching ASCII control character, e.g. Ctrl+C to U+0003.
+const CTRL_HANDLING: HandleControl = HandleControl::MapLettersToUnicode;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Layout {
+    Us104,
+    Uk105,
+    De105,
+    Dvorak104,
+    Azerty,
+}
+
+impl Layout {
+    pub const ALL: [Layout; 5] = [
+        Layout::Us104,
+        Layout::Uk105,
+        Layout::De105,
+        Layout::Dvorak104,
+        Layout::Azerty,
+    ];
+
+    /// Name of the layout on the command line.
+    pub fn name(self) -> &'static str {
+        match self {
+            Layout::Us104 => "us",
+            Layout::Uk105 => "uk",
+            Layout::De105 => "de",
+            Layout::Dvorak104 => "dvorak",
+            Layout::Azerty => "azerty",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL
+            .iter()
+            .copied()
+            .find(|layout| layout.name() == name)
+    }
+}
+
+/// Scancode set we receive from the controller.
+///
+/// Keyboards always speak set 2 here. For set 1 we have the controller translate it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScancodeSet {
+    Set1,
+    Set2,
+}
+
+impl ScancodeSet {
+    /// Name of the set on the command line.
+    pub fn name(self) -> &'static str {
+        match self {
+            ScancodeSet::Set1 => "1",
+            ScancodeSet::Set2 => "2",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "1" => Some(ScancodeSet::Set1),
+            "2" => Some(ScancodeSet::Set2),
+            _ => None,
+        }
+    }
+}
+
+/// A `pc_keyboard::Keyboard` for any of our layouts. The layout is a type parameter
+/// there, so we have to enumerate them.
+enum AnyLayout<S: pc_keyboard::ScancodeSet> {
+    Us104(Keyboard<layouts::Us104Key, S>),
+    Uk105(Keyboard<layouts::Uk105Key, S>),
+    De105(Keyboard<layouts::De105Key, S>),
+    Dvorak104(Keyboard<layouts::Dvorak104Key, S>),
+    Azerty(Keyboard<layouts::Azerty, S>),
+}
+
+macro_rules! with_layout {
+    ($any:expr, $keyboard:ident => $body:expr) => {
+        match $any {
+            AnyLayout::Us104($keyboard) => $body,
+            AnyLayout::Uk105($keyboard) => $body,
+            AnyLayout::De105($keyboard) => $body,
+            AnyLayout::Dvorak104($keyboard) => $body,
+            AnyLayout::Azerty($keyboard) => $body,
+        }
+    };
+}
+
+impl<S: pc_keyboard::ScancodeSet> AnyLayout<S> {
+    fn new(layout: Layout, set: S) -> Self {
+        match layout {
+            Layout::Us104 => AnyLayout::Us104(Keyboard::new(layouts::Us104Key, set, CTRL_HANDLING)),
+            Layout::Uk105 => AnyLayout::Uk105(Keyboard::new(layouts::Uk105Key, set, CTRL_HANDLING)),
+            Layout::De105 => AnyLayout::De105(Keyboard::new(layouts::De105Key, set, CTRL_HANDLING)),
+            Layout::Dvorak104 => {
+                AnyLayout::Dvorak104(Keyboard::new(layouts::Dvorak104Key, set, CTRL_HANDLING))
+            }
+            Layout::Azerty => AnyLayout::Azerty(Keyboard::new(layouts::Azerty, set, CTRL_HANDLING)),
+        }
+    }
+
+    fn add_byte(&mut self, byte: u8) -> Option<KeyEvent> {
+        with_layout!(self, keyboard => keyboard.add_byte(byte).ok().flatten())
+    }
+
+    fn process_keyevent(&mut self, event: KeyEvent) -> Option<DecodedKey> {
+        with_layout!(self, keyboard => keyboard.process_keyevent(event))
+    }
+}
+
+enum AnyKeyboard {
+    Set1(AnyLayout<ScancodeSet1>),
+    Set2(AnyLayout<ScancodeSet2>),
+}
+
+impl AnyKeyboard {
+    fn new(layout: Layout, set: ScancodeSet) -> Self {
+        match set {
+            ScancodeSet::Set1 => AnyKeyboard::Set1(AnyLayout::new(layout, ScancodeSet1)),
+            ScancodeSet::Set2 => AnyKeyboard::Set2(AnyLayout::new(layout, ScancodeSet2)),
+        }
+    }
+
+    fn add_byte(&mut self, byte: u8) -> Option<KeyEvent> {
+        match self {
+            AnyKeyboard::Set1(keyboard) => keyboard.add_byte(byte),
+            AnyKeyboard::Set2(keyboard) => keyboard.add_byte(byte),
+        }
+    }
+
+    fn process_keyevent(&mut self, event: KeyEvent) -> Option<DecodedKey> {
+        match self {
+            AnyKeyboard::Set1(keyboard) => keyboard.process_keyevent(event),
+            AnyKeyboard::Set2(keyboard) => keyboard.process_keyevent(event),
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Modifiers {
+    pub shift: bool,
+    pub ctrl: bool,
+    pub alt: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyPress {
+    pub key: DecodedKey,
+    /// Modifiers held when the key was pressed.
+    pub modifiers: Modifiers,
+}
+
+struct Decoder {
+    keyboard: AnyKeyboard,
+    layout: Layout,
+    set: ScancodeSet,
+    modifiers: Modifiers,
+}
+
+impl Decoder {
+    fn new(layout: Layout, set: ScancodeSet) -> Self {
+        Self {
+            keyboard: AnyKeyboard::new(layout, set),
+            layout,
+            set,
+            modifiers: Modifiers::default(),
+        }
+    }
+
+    fn decode(&mut self, scancode: u8) -> Option<KeyPress> {
+        let event = self.keyboard.add_byte(scancode)?;
+        let down = event.state == KeyState::Down;
+        match event.code {
+            KeyCode::ShiftLeft | KeyCode::ShiftRight => self.modifiers.shift = down,
+            KeyCode::ControlLeft | KeyCode::ControlRight => self.modifiers.ctrl = down,
+            KeyCode::AltLeft | KeyCode::AltRight => self.modifiers.alt = down,
+            _ => {}
+        }
+        let key = self.keyboard.process_keyevent(event)?;
+        Some(KeyPress {
+            key,
+            modifiers: self.modifiers,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Hotkey {
+    PageUp,
+    PageDown,
+    Console(usize),
+}
+
+enum Fed<'a> {
+    Hotkey(Hotkey),
+    /// Scancodes that aren't part of a hotkey, possibly none yet.
+    Pass(&'a [u8]),
+}
+
+/// Decodes just enough of the scancodes to spot hotkeys.
+struct Hotkeys {
+    set: ScancodeSet,
+    state: DecodeState,
+    /// Scancodes of the key event being decoded, held back until we know whether it's
+    /// a hotkey.
+    held: [u8; 4],
+    held_len: usize,
+    shift: bool,
+    alt: bool,
+}
+
+impl Hotkeys {
+    const fn new(set: ScancodeSet) -> Self {
+        Self {
+            set,
+            state: DecodeState::Start,
+            held: [0; 4],
+            held_len: 0,
+            shift: false,
+            alt: false,
+        }
+    }
+
+    fn feed(&mut self, scancode: u8) -> Fed<'_> {
+        self.held[self.held_len] = scancode;
+        self.held_len += 1;
+        let result = match self.set {
+            ScancodeSet::Set1 => ScancodeSet1::advance_state(&mut self.state, scancode),
+            ScancodeSet::Set2 => ScancodeSet2::advance_state(&mut self.state, scancode),
+        };
+        let len = core::mem::replace(&mut self.held_len, 0);
+        match result {
+            Ok(None) if len < self.held.len() => {
+                self.held_len = len;
+                return Fed::Pass(&[]);
+            }
+            Ok(Some(event)) => {
+                if let Some(hotkey) = self.hotkey(event) {
+                    return Fed::Hotkey(hotkey);
+                }
+            }
+            // Let the reader make what it can of anything else
+            _ => {}
+        }
+        Fed::Pass(&self.held[..len])
+    }
+
+    fn hotkey(&mut self, event: KeyEvent) -> Option<Hotkey> {
+        let down = event.state == KeyState::Down;
+        let hotkey = match event.code {
+            KeyCode::ShiftLeft | KeyCode::ShiftRight => {
+                self.shift = down;
+                return None;
+            }
+            KeyCode::AltLeft | KeyCode::AltRight => {
+                self.alt = down;
+                return None;
+            }
+            _ if !down => return None,
+            KeyCode::PageUp if self.shift => Hotkey::PageUp,
+            KeyCode::PageDown if self.shift => Hotkey::PageDown,
+            _ if !self.alt => return None,
+            KeyCode::F1 => Hotkey::Console(0),
+            KeyCode::F2 => Hotkey::Console(1),
+            KeyCode::F3 => Hotkey::Console(2),
+            KeyCode::F4 => Hotkey::Console(3),
+            KeyCode::F5 => Hotkey::Console(4),
+            KeyCode::F6 => Hotkey::Console(5),
+            _ => return None,
+        };
+        Some(hotkey)
+    }
+}
+
+lazy_static! {
+    // The controller translates to set 1 out of the box
+    static ref DECODER: Mutex<Decoder> = Mutex::new(Decoder::new(Layout::Us104, ScancodeSet::Set1));
+}
+
+/// Apply the `keyboard.layout` and `keyboard.scancodes` command line options and add
+/// the `kbd` shell command.
+///
+/// Needs the heap, to read the command line.
+pub fn init() {
+    shell::register(&KBD_COMMAND);
+    if let Some(name) = cmdline::get("keyboard.layout") {
+        match Layout::from_name(name) {
+            Some(layout) => set_layout(layout),
+            None => log::warn!("unknown layout {:?}", name),
+        }
+    }
+    if let Some(name) = cmdline::get("keyboard.scancodes") {
+        match ScancodeSet::from_name(name) {
+            Some(set) => {
+                if let Err(e) = set_scancode_set(set) {
+                    log::error!("failed to select scancode set {}: {:?}", name, e);
+                }
+            }
+            None => log::warn!("unknown scancode set {:?}", name),
+        }
+    }
+}
+
+static KBD_COMMAND: shell::Command = shell::Command {
+    name: "kbd",
+    usage: "[layout <name> | scancodes <1|2>]",
+    help: "Show or change the keyboard layout and scancode set",
+    run: kbd,
+};
+
+fn kbd(args: &[&str], out: &mut dyn Write) -> Result<(), shell::Error> {
+    match args {
+        [] => {
+            let _ = writeln!(
+                out,
+                "layout {}, scancode set {}",
+                layout().name(),
+                scancode_set().name()
+            );
+        }
+        ["layout", name] => match Layout::from_name(name) {
+            Some(layout) => set_layout(layout),
+            None => {
+                let names: Vec<_> = Layout::ALL.iter().map(|l| l.name()).collect();
+                let message = format!("unknown layout, try one of {}", names.join(", "));
+                return Err(shell::Error::Failed(message));
+            }
+        },
+        ["scancodes", name] => {
+            let set = ScancodeSet::from_name(name).ok_or(shell::Error::Usage)?;
+            set_scancode_set(set)
+                .map_err(|e| shell::Error::Failed(format!("controller error: {:?}", e)))?;
+        }
+        _ => return Err(shell::Error::Usage),
+    }
+    Ok(())
+}
+
+pub fn layout() -> Layout {
+    DECODER.lock().layout
+}
+
+pub fn set_layout(layout: Layout) {
+    let mut decoder = DECODER.lock();
+    let set = decoder.set;
+    *decoder = Decoder::new(layout, set);
+}
+
+pub fn scancode_set() -> ScancodeSet {
+    DECODER.lock().set
+}
+
+/// Switch the scancode set we receive, reconfiguring the keyboard and controller.
+pub fn set_scancode_set(set: ScancodeSet) -> Result<(), ps2::Error> {
+    let mut decoder = DECODER.lock();
+    ps2::device_command(ps2::Channel::First, &[ps2::KEYBOARD_SCANCODE_SET, 2])?;
+    let config = ps2::read_config()?;
+    let config = match set {
+        ScancodeSet::Set1 => config | ps2::CONFIG_TRANSLATION,
+        ScancodeSet::Set2 => config & !ps2::CONFIG_TRANSLATION,
+    };
+    ps2::write_config(config)?;
+    // Anything still queued was sent in the old set
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        *HOTKEYS.lock() = Hotkeys::new(set);
+    });
+    while SCANCODES.pop().is_some() {}
+    let layout = decoder.layout;
+    *decoder = Decoder::new(layout, set);
+    Ok(())
+}
+
+/// The next key press, if one is waiting.
+pub fn next_key() -> Option<KeyPress> {
+    let mut decoder = DECODER.lock();
+    // Only ever pop scancodes with the decoder locked, so there's a single consumer
+    while let Some(scancode) = SCANCODES.pop() {
+        if let Some(press) = decoder.decode(scancode) {
+            return Some(press);
+        }
+    }
+    None
+}
+
+/// Whether there's keyboard input waiting to be decoded.
+pub fn pending() -> bool {
+    !SCANCODES.is_empty()
+}
+
+/// Wait for the next key press.
+///
+/// Must be called with interrupts enabled.
+pub fn read_key() -> KeyPress {
+    use x86_64::instructions::interrupts;
+    loop {
+        if let Some(press) = next_key() {
+            return press;
+        }
+        // Check again with interrupts off so a key arriving now still wakes us from `hlt`
+        interrupts::disable();
+        if SCANCODES.is_empty() {
+            interrupts::enable_and_hlt();
+        } else {
+            interrupts::enable();
+        }
+    }
+}
+
+/// Maximum number of lines kept in a `LineEditor`'s history.
+pub const HISTORY_LEN: usize = 16;
+
+const BACKSPACE: char = '\u{8}';
+const DELETE: char = '\u{7f}';
+// What Ctrl+letter decodes to with `CTRL_HANDLING`
+const CTRL_A: char = '\u{1}';
+const CTRL_C: char = '\u{3}';
+const CTRL_E: char = '\u{5}';
+const CTRL_U: char = '\u{15}';
+
+/// Line editing on top of key presses.
+///
+/// Supports backspace and delete, moving with the arrow keys, Home and End (or Ctrl+A
+/// and Ctrl+E), Ctrl+U to kill back to the start of the line, Ctrl+C to abandon the
+/// line and Up/Down to browse history. Edits are
+/// echoed as ANSI escape sequences, relative to the position saved when the line
+/// started.
+pub struct LineEditor {
+    line: Vec<char>,
+    cursor: usize,
+    history: Vec<String>,
+    /// Index into `history` while browsing it.
+    browsing: Option<usize>,
+    /// What was typed before we started browsing history.
+    draft: Vec<char>,
+    /// Whether we've saved the cursor position for the current line yet.
+    started: bool,
+}
+
+impl LineEditor {
+    pub const fn new() -> Self {
+        Self {
+            line: Vec::new(),
+            cursor: 0,
+            history: Vec::new(),
+            browsing: None,
+            draft: Vec::new(),
+            started: false,
+        }
+    }
+
+    /// Previously entered lines, oldest first.
+    pub fn history(&self) -> &[String] {
+        &self.history
+    }
+
+    /// Handle `press`, echoing to `out`. Returns the line once Enter is pressed.
+    pub fn feed(&mut self, press: KeyPress, out: &mut dyn Write) -> Option<String> {
+        if !self.started {
+            // Remember where the line starts so we can redraw it
+            let _ = out.write_str("\x1b7");
+            self.started = true;
+        }
+        let ctrl = press.modifiers.ctrl;
+        match press.key {
+            DecodedKey::Unicode('\n') => {
+                let _ = out.write_char('\n');
+                return Some(self.finish());
+            }
+            DecodedKey::Unicode(BACKSPACE) => {
+                if self.cursor > 0 {
+                    self.cursor -= 1;
+                    self.line.remove(self.cursor);
+                    if self.cursor == self.line.len() {
+                        let _ = out.write_char(BACKSPACE);
+                    } else {
+                        self.redraw(out);
+                    }
+                }
+            }
+            DecodedKey::Unicode(DELETE) | DecodedKey::RawKey(KeyCode::Delete) => {
+                if self.cursor < self.line.len() {
+                    self.line.remove(self.cursor);
+                    self.redraw(out);
+                }
+            }
+            DecodedKey::Unicode(CTRL_C) => {
+                let _ = out.write_str("^C\n");
+                self.line.clear();
+                return Some(self.finish());
+            }
+            DecodedKey::Unicode(CTRL_U) => self.kill_to_start(out),
+            DecodedKey::RawKey(KeyCode::ArrowLeft) => {
+                self.move_to(self.cursor.saturating_sub(1), out)
+            }
+            DecodedKey::RawKey(KeyCode::ArrowRight) => self.move_to(self.cursor + 1, out),
+            DecodedKey::RawKey(KeyCode::Home) | DecodedKey::Unicode(CTRL_A) => self.move_to(0, out),
+            DecodedKey::RawKey(KeyCode::End) | DecodedKey::Unicode(CTRL_E) => {
+                self.move_to(self.line.len(), out)
+            }
+            DecodedKey::RawKey(KeyCode::ArrowUp) => self.history_prev(out),
+            DecodedKey::RawKey(KeyCode::ArrowDown) => self.history_next(out),
+            DecodedKey::Unicode(c) if !c.is_control() && !ctrl => {
+                self.line.insert(self.cursor, c);
+                self.cursor += 1;
+                if self.cursor == self.line.len() {
+                    let _ = out.write_char(c);
+                } else {
+                    self.redraw(out);
+                }
+            }
+            _ => {}
+        }
+        None
+    }
+
+    fn finish(&mut self) -> String {
+        let line: String = self.line.drain(..).collect();
+        if !line.is_empty() && self.history.last() != Some(&line) {
+            if self.history.len() == HISTORY_LEN {
+                self.history.remove(0);
+            }
+            self.history.push(line.clone());
+        }
+        self.cursor = 0;
+        self.browsing = None;
+        self.started = false;
+        line
+    }
+
+    fn kill_to_start(&mut self, out: &mut dyn Write) {
+        self.line.drain(..self.cursor);
+        self.cursor = 0;
+        self.redraw(out);
+    }
+
+    fn move_to(&mut self, cursor: usize, out: &mut dyn Write) {
+        self.cursor = cursor.min(self.line.len());
+        self.place_cursor(out);
+    }
+
+    fn history_prev(&mut self, out: &mut dyn Write) {
+        let index = match self.browsing {
+            Some(0) => return,
+            Some(i) => i - 1,
+            None if self.history.is_empty() => return,
+            None => {
+                self.draft = self.line.clone();
+                self.history.len() - 1
+            }
+        };
+        self.browsing = Some(index);
+        self.line = self.history[index].chars().collect();
+        self.cursor = self.line.len();
+        self.redraw(out);
+    }
+
+    fn history_next(&mut self, out: &mut dyn Write) {
+        match self.browsing {
+            None => return,
+            Some(i) if i + 1 < self.history.len() => {
+                self.browsing = Some(i + 1);
+                self.line = self.history[i + 1].chars().collect();
+            }
+            Some(_) => {
+                self.browsing = None;
+                self.line = core::mem::take(&mut self.draft);
+            }
+        }
+        self.cursor = self.line.len();
+        self.redraw(out);
+    }
+
+    /// Rewrite the whole line and clear anything left over after it.
+    fn redraw(&self, out: &mut dyn Write) {
+        let _ = out.write_str("\x1b8");
+        for &c in &self.line {
+            let _ = out.write_char(c);
+        }
+        let _ = out.write_str("\x1b[J");
+        self.place_cursor(out);
+    }
+
+    /// Move the screen cursor to `self.cursor` by rewriting everything before it, which
+    /// keeps working when the line wraps.
+    fn place_cursor(&self, out: &mut dyn Write) {
+        let _ = out.write_str("\x1b8");
+        for &c in &self.line[..self.cursor] {
+            let _ = out.write_char(c);
+        }
+    }
+}
+
+/// Echoes line editing to the main console.
+struct Echo;
+
+impl Write for Echo {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        vga::print_interactive(format_args!("{}", s));
+        Ok(())
+    }
+}
+
+static EDITOR: Mutex<LineEditor> = Mutex::new(LineEditor::new());
+
+/// Read a line from the keyboard, with editing and history, echoing it to the main
+/// console.
+pub fn read_line() -> String {
+    let mut editor = EDITOR.lock();
+    loop {
+        if let Some(line) = editor.feed(read_key(), &mut Echo) {
+            return line;
+        }
+    }
+}
+
+#[cfg(test)]
+fn press(key: DecodedKey) -> KeyPress {
+    KeyPress {
+        key,
+        modifiers: Modifiers::default(),
+    }
+}
+
+#[cfg(test)]
+fn type_keys(editor: &mut LineEditor, keys: &[DecodedKey]) -> Option<String> {
+    let mut out = String::new();
+    let mut result = None;
+    for &key in keys {
+        result = editor.feed(press(key), &mut out);
+    }
+    result
+}
+
+#[test_case]
+fn test_line_editing() {
+    use DecodedKey::{RawKey, Unicode};
+    let mut editor = LineEditor::new();
+    let keys = [
+        Unicode('h'),
+        Unicode('l'),
+        Unicode('o'),
+        RawKey(KeyCode::ArrowLeft),
+        Unicode('l'),
+        RawKey(KeyCode::Home),
+        Unicode('x'),
+        RawKey(KeyCode::Delete),
+        Unicode(BACKSPACE),
+        RawKey(KeyCode::End),
+        Unicode('!'),
+        Unicode(BACKSPACE),
+        Unicode('\n'),
+    ];
+    // "hlo" -> "hllo" -> "xhllo" -> "xllo" -> "llo" -> "llo!" -> "llo"
+    let line = type_keys(&mut editor, &keys).unwrap();
+    assert_eq!(line, "llo");
+}
+
+#[test_case]
+fn test_ctrl_keys() {
+    use DecodedKey::{RawKey, Unicode};
+    let mut editor = LineEditor::new();
+    let mut keys = Vec::new();
+    keys.extend("hello world".chars().map(Unicode));
+    keys.extend(core::iter::repeat(RawKey(KeyCode::ArrowLeft)).take(5));
+    keys.push(Unicode(CTRL_U));
+    keys.push(Unicode('\n'));
+    assert_eq!(type_keys(&mut editor, &keys).unwrap(), "world");
+
+    // Ctrl+A and Ctrl+E work like Home and End
+    let keys = [
+        Unicode('b'),
+        Unicode(CTRL_A),
+        Unicode('a'),
+        Unicode(CTRL_E),
+        Unicode('c'),
+        Unicode('\n'),
+    ];
+    assert_eq!(type_keys(&mut editor, &keys).unwrap(), "abc");
+}
+
+#[test_case]
+fn test_ctrl_c_abandons_line() {
+    use DecodedKey::Unicode;
+    let mut editor = LineEditor::new();
+    let keys = [Unicode('x'), Unicode(CTRL_C)];
+    assert_eq!(type_keys(&mut editor, &keys).unwrap(), "");
+    assert!(editor.history().is_empty());
+    assert_eq!(
+        type_keys(&mut editor, &[Unicode('y'), Unicode('\n')]).unwrap(),
+        "y"
+    );
+}
+
+#[test_case]
+fn test_history() {
+    use DecodedKey::{RawKey, Unicode};
+    let mut editor = LineEditor::new();
+    type_keys(&mut editor, &[Unicode('a'), Unicode('\n')]);
+    type_keys(&mut editor, &[Unicode('b'), Unicode('\n')]);
+    // Blank lines aren't recorded
+    type_keys(&mut editor, &[Unicode('\n')]);
+    assert_eq!(editor.history(), &["a", "b"]);
+
+    let up = RawKey(KeyCode::ArrowUp);
+    let down = RawKey(KeyCode::ArrowDown);
+    assert_eq!(
+        type_keys(&mut editor, &[up, up, up, Unicode('\n')]).unwrap(),
+        "a"
+    );
+    // The draft comes back after browsing past the newest entry
+    let keys = [Unicode('z'), up, down, Unicode('\n')];
+    assert_eq!(type_keys(&mut editor, &keys).unwrap(), "z");
+    assert_eq!(editor.history(), &["a", "b", "a", "z"]);
+}
+
+#[cfg(test)]
+fn feed_hotkeys(hotkeys: &mut Hotkeys, scancodes: &[u8]) -> (Vec<Hotkey>, Vec<u8>) {
+    let mut found = Vec::new();
+    let mut passed = Vec::new();
+    for &scancode in scancodes {
+        match hotkeys.feed(scancode) {
+            Fed::Hotkey(hotkey) => found.push(hotkey),
+            Fed::Pass(scancodes) => passed.extend_from_slice(scancodes),
+        }
+    }
+    (found, passed)
+}
+
+#[test_case]
+fn test_hotkeys_held_back() {
+    let mut hotkeys = Hotkeys::new(ScancodeSet::Set1);
+    // Shift down, PageUp down and up, Shift up
+    let (found, passed) = feed_hotkeys(&mut hotkeys, &[0x2a, 0xe0, 0x49, 0xe0, 0xc9, 0xaa]);
+    assert_eq!(found, [Hotkey::PageUp]);
+    assert_eq!(passed, [0x2a, 0xe0, 0xc9, 0xaa]);
+    // PageUp on its own goes through, prefix and all
+    let (found, passed) = feed_hotkeys(&mut hotkeys, &[0xe0, 0x49]);
+    assert!(found.is_empty());
+    assert_eq!(passed, [0xe0, 0x49]);
+    // Alt+F2 switches console, Alt+F7 doesn't
+    let (found, passed) = feed_hotkeys(&mut hotkeys, &[0x38, 0x3c, 0x41]);
+    assert_eq!(found, [Hotkey::Console(1)]);
+    assert_eq!(passed, [0x38, 0x41]);
+}
+
+#[test_case]
+fn test_hotkeys_set2() {
+    let mut hotkeys = Hotkeys::new(ScancodeSet::Set2);
+    // Left Shift down, PageDown down and up, Left Shift up
+    let scancodes = [0x12, 0xe0, 0x7a, 0xe0, 0xf0, 0x7a, 0xf0, 0x12];
+    let (found, passed) = feed_hotkeys(&mut hotkeys, &scancodes);
+    assert_eq!(found, [Hotkey::PageDown]);
+    assert_eq!(passed, [0x12, 0xe0, 0xf0, 0x7a, 0xf0, 0x12]);
+}
@@ -3,66 +3,64 @@
 #![feature(const_mut_refs)]
 #![feature(custom_test_frameworks)]
 #![feature(abi_x86_interrupt)]
+#![feature(asm)]
 #![test_runner(crate::test_runner)]
 #![reexport_test_harness_main = "test_main"]
 #![feature(alloc_error_handler)]
 
 pub mod allocator;
+pub mod block;
+pub mod cmdline;
+pub mod fs;
 pub mod gdt;
 pub mod interrupts;
+pub mod keyboard;
+pub mod logging;
 pub mod memory;
+pub mod mouse;
+pub mod panic;
+pub mod ps2;
 pub mod qemu;
+pub mod ring;
 pub mod serial;
+pub mod shell;
+pub mod testing;
 pub mod vga;
 
-extern crate alloc;
+pub use testing::{test_panic_handler, test_runner, Testable};
 
-use core::panic::PanicInfo;
+extern crate alloc;
 
 use x86_64::instructions;
 
 pub fn init() {
+    logging::init();
     gdt::init();
+    vga::init();
     interrupts::init_idt();
     unsafe { interrupts::PICS.lock().initialize() };
+    init_ps2();
+    serial::init();
     instructions::interrupts::enable();
 }
 
-pub fn hlt_loop() -> ! {
-    loop {
-        instructions::hlt();
-    }
-}
-
-// Test harness setup
-pub trait Testable {
-    fn run(&self) -> ();
-}
-
-impl<T> Testable for T
-where
-    T: Fn(),
-{
-    fn run(&self) {
-        serial_print!("{}...\t", core::any::type_name::<T>());
-        self();
-        serial_println!("[ok]");
+/// Bring up the PS/2 controller and mouse. Input is optional, so failures are only logged.
+fn init_ps2() {
+    match ps2::init() {
+        Ok(ports) if ports.second => {
+            if let Err(e) = mouse::init() {
+                log::error!(target: "firstos::mouse", "initialization failed: {:?}", e);
+            }
+        }
+        Ok(_) => {}
+        Err(e) => log::error!(target: "firstos::ps2", "controller initialization failed: {:?}", e),
     }
 }
 
-pub fn test_runner(tests: &[&dyn Testable]) {
-    serial_println!("Running {} tests", tests.len());
-    for test in tests {
-        test.run();
+pub fn hlt_loop() -> ! {
+    loop {
+        instructions::hlt();
     }
-    qemu::exit(qemu::ExitCode::Success);
-}
-
-pub fn test_panic_handler(info: &PanicInfo) -> ! {
-    serial_println!(" [failed]\n");
-    serial_println!("Error: {}\n", info);
-    qemu::exit(qemu::ExitCode::Failure);
-    hlt_loop();
 }
 
 #[cfg(test)]
@@ -70,15 +68,20 @@ bootloader::entry_point!(test_kernel_main);
 
 /// Entry point for `cargo test`
 #[cfg(test)]
-fn test_kernel_main(_boot_info: &'static bootloader::BootInfo) -> ! {
+fn test_kernel_main(boot_info: &'static bootloader::BootInfo) -> ! {
     init();
+    let phys_mem_offset = x86_64::VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { memory::ListFrameAllocator::init(boot_info) };
+    allocator::init(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    memory::set_frame_allocator(frame_allocator);
     test_main();
     hlt_loop();
 }
 
 #[cfg(test)]
 #[panic_handler]
-fn panic(info: &PanicInfo) -> ! {
+fn panic(info: &core::panic::PanicInfo) -> ! {
     test_panic_handler(info)
 }
 
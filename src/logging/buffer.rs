@@ -0,0 +1,347 @@
+//! The kernel log buffer: recent console output and log records, kept in memory so
+//! they can be looked at after they've scrolled away.
+//!
+//! Writers never take a lock and the buffer needs no setup, so everything from the
+//! first instruction of `kernel_main` on gets recorded, including from interrupt and
+//! panic handlers. Each record holds a line, or part of one, and gets a sequence
+//! number. Once the buffer is full new records overwrite the oldest.
+//!
+//! Readers copy a record out, then check its sequence number didn't change in the
+//! meantime, so records written or overwritten during a dump are skipped rather than
+//! shown torn.
+
+use super::{timestamp, Entry, Sink};
+use crate::interrupts;
+use core::cell::UnsafeCell;
+use core::fmt::{self, Write};
+use core::ptr;
+use core::sync::atomic::{fence, AtomicU64, Ordering};
+use log::Level;
+
+/// Number of records kept.
+pub const CAPACITY: usize = 1024;
+/// Most text a record holds. Longer lines are split over several records.
+const TEXT_LEN: usize = 112;
+
+/// Level of records from `print!` and friends.
+const CONSOLE: u8 = 0;
+
+pub static BUFFER: LogBuffer = LogBuffer::new();
+
+/// Keep a copy of console output.
+pub fn record_console(args: fmt::Arguments) {
+    BUFFER.record(interrupts::ticks(), CONSOLE, args);
+}
+
+#[derive(Clone, Copy)]
+struct Meta {
+    ticks: u64,
+    /// `CONSOLE` or a `log::Level`.
+    level: u8,
+    len: u8,
+    /// Whether the line ends here rather than continuing in the next record.
+    line_end: bool,
+}
+
+struct Slot {
+    /// Sequence number of the record held plus one, or 0 while it's being written.
+    seq: AtomicU64,
+    meta: UnsafeCell<Meta>,
+    text: UnsafeCell<[u8; TEXT_LEN]>,
+}
+
+const EMPTY_SLOT: Slot = Slot {
+    seq: AtomicU64::new(0),
+    meta: UnsafeCell::new(Meta {
+        ticks: 0,
+        level: CONSOLE,
+        len: 0,
+        line_end: true,
+    }),
+    text: UnsafeCell::new([0; TEXT_LEN]),
+};
+
+pub struct LogBuffer {
+    /// Sequence number of the next record.
+    next: AtomicU64,
+    slots: [Slot; CAPACITY],
+}
+
+// A slot is only written by whoever reserved its sequence number, and readers check
+// for writes that happened while they were copying.
+unsafe impl Sync for LogBuffer {}
+
+/// A record copied out of the buffer.
+#[derive(Clone, Copy)]
+pub struct Record {
+    pub seq: u64,
+    /// Timer ticks since boot.
+    pub ticks: u64,
+    /// `None` for console output.
+    pub level: Option<Level>,
+    /// Whether the line ends here rather than continuing in the next record.
+    pub line_end: bool,
+    text: [u8; TEXT_LEN],
+    len: usize,
+}
+
+impl Record {
+    pub fn text(&self) -> &str {
+        // Records are only ever split on character boundaries
+        core::str::from_utf8(&self.text[..self.len]).unwrap_or("?")
+    }
+}
+
+impl LogBuffer {
+    const fn new() -> Self {
+        Self {
+            next: AtomicU64::new(0),
+            slots: [EMPTY_SLOT; CAPACITY],
+        }
+    }
+
+    /// Sequence number the next record will get.
+    pub fn next_seq(&self) -> u64 {
+        self.next.load(Ordering::Acquire)
+    }
+
+    /// Sequence number of the oldest record that hasn't been overwritten.
+    pub fn first_seq(&self) -> u64 {
+        self.next_seq().saturating_sub(CAPACITY as u64)
+    }
+
+    fn slot(&self, seq: u64) -> &Slot {
+        &self.slots[(seq % CAPACITY as u64) as usize]
+    }
+
+    fn push(&self, meta: Meta, text: &[u8]) {
+        let seq = self.next.fetch_add(1, Ordering::AcqRel);
+        let slot = self.slot(seq);
+        slot.seq.store(0, Ordering::Relaxed);
+        fence(Ordering::Release);
+        unsafe {
+            ptr::write_volatile(slot.meta.get(), meta);
+            let dest = slot.text.get() as *mut u8;
+            for (i, &byte) in text.iter().enumerate() {
+                ptr::write_volatile(dest.add(i), byte);
+            }
+        }
+        slot.seq.store(seq + 1, Ordering::Release);
+    }
+
+    /// Copy out record `seq`, unless it's been overwritten or is still being written.
+    pub fn read(&self, seq: u64) -> Option<Record> {
+        let slot = self.slot(seq);
+        if slot.seq.load(Ordering::Acquire) != seq + 1 {
+            return None;
+        }
+        let mut text = [0; TEXT_LEN];
+        let meta = unsafe {
+            let meta = ptr::read_volatile(slot.meta.get());
+            let src = slot.text.get() as *const u8;
+            for (i, byte) in text.iter_mut().enumerate() {
+                *byte = ptr::read_volatile(src.add(i));
+            }
+            meta
+        };
+        fence(Ordering::Acquire);
+        if slot.seq.load(Ordering::Relaxed) != seq + 1 {
+            return None;
+        }
+        Some(Record {
+            seq,
+            ticks: meta.ticks,
+            level: level_from_u8(meta.level),
+            line_end: meta.line_end,
+            text,
+            len: (meta.len as usize).min(TEXT_LEN),
+        })
+    }
+
+    fn record(&self, ticks: u64, level: u8, args: fmt::Arguments) {
+        let mut writer = RecordWriter {
+            buffer: self,
+            ticks,
+            level,
+            text: [0; TEXT_LEN],
+            len: 0,
+        };
+        let _ = writer.write_fmt(args);
+        if writer.len > 0 {
+            writer.commit(writer.len, false);
+        }
+    }
+
+    /// Write out the records from `first` on, dmesg style, and return the sequence
+    /// number to carry on from next time.
+    pub fn dump(&self, out: &mut dyn Write, first: u64) -> Result<u64, fmt::Error> {
+        let next = self.next_seq();
+        let mut seq = first.max(self.first_seq());
+        if seq > first {
+            writeln!(out, "({} earlier records overwritten)", seq - first)?;
+        }
+        let mut line_start = true;
+        while seq < next {
+            match self.read(seq) {
+                Some(record) => {
+                    if line_start {
+                        let (secs, millis) = timestamp(record.ticks);
+                        write!(out, "{:>6} [{:>5}.{:03}] ", record.seq, secs, millis)?;
+                        if let Some(level) = record.level {
+                            write!(out, "{:<5} ", level)?;
+                        }
+                    }
+                    out.write_str(record.text())?;
+                    if record.line_end {
+                        out.write_char('\n')?;
+                    }
+                    line_start = record.line_end;
+                }
+                None => {
+                    if !line_start {
+                        out.write_char('\n')?;
+                    }
+                    writeln!(out, "{:>6} (overwritten)", seq)?;
+                    line_start = true;
+                }
+            }
+            seq += 1;
+        }
+        if !line_start {
+            out.write_char('\n')?;
+        }
+        Ok(seq)
+    }
+}
+
+impl Sink for LogBuffer {
+    fn write(&self, entry: &Entry) {
+        self.record(
+            entry.ticks,
+            entry.level as u8,
+            format_args!("{}: {}\n", entry.target, entry.args),
+        );
+    }
+}
+
+fn level_from_u8(level: u8) -> Option<Level> {
+    match level {
+        1 => Some(Level::Error),
+        2 => Some(Level::Warn),
+        3 => Some(Level::Info),
+        4 => Some(Level::Debug),
+        5 => Some(Level::Trace),
+        _ => None,
+    }
+}
+
+/// Splits formatted text into records, on line ends and when a record fills up.
+struct RecordWriter<'a> {
+    buffer: &'a LogBuffer,
+    ticks: u64,
+    level: u8,
+    text: [u8; TEXT_LEN],
+    len: usize,
+}
+
+impl RecordWriter<'_> {
+    /// Record the first `len` bytes, keeping the rest for the next record.
+    fn commit(&mut self, len: usize, line_end: bool) {
+        let meta = Meta {
+            ticks: self.ticks,
+            level: self.level,
+            len: len as u8,
+            line_end,
+        };
+        self.buffer.push(meta, &self.text[..len]);
+        self.text.copy_within(len..self.len, 0);
+        self.len -= len;
+    }
+}
+
+impl Write for RecordWriter<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for &byte in s.as_bytes() {
+            if byte == b'\n' {
+                self.commit(self.len, true);
+                continue;
+            }
+            if self.len == TEXT_LEN {
+                // Don't split a character across records
+                let mut split = self.len;
+                if is_continuation(byte) {
+                    while split > 0 && is_continuation(self.text[split - 1]) {
+                        split -= 1;
+                    }
+                    split = split.saturating_sub(1);
+                }
+                self.commit(if split == 0 { self.len } else { split }, false);
+            }
+            self.text[self.len] = byte;
+            self.len += 1;
+        }
+        Ok(())
+    }
+}
+
+fn is_continuation(byte: u8) -> bool {
+    byte & 0xc0 == 0x80
+}
+
+/// Write the whole buffer to COM1.
+pub fn dump_to_serial() {
+    struct Com1;
+
+    impl Write for Com1 {
+        fn write_str(&mut self, s: &str) -> fmt::Result {
+            crate::serial::write(crate::serial::ComPort::Com1, s.as_bytes());
+            Ok(())
+        }
+    }
+
+    let _ = BUFFER.dump(&mut Com1, 0);
+}
+
+#[test_case]
+fn test_records() {
+    static BUFFER: LogBuffer = LogBuffer::new();
+    let first = BUFFER.next_seq();
+    BUFFER.record(5, Level::Warn as u8, format_args!("a: {}\n", 1));
+    BUFFER.record(6, CONSOLE, format_args!("no newline"));
+    let record = BUFFER.read(first).unwrap();
+    assert_eq!(record.text(), "a: 1");
+    assert_eq!(record.level, Some(Level::Warn));
+    assert_eq!(record.ticks, 5);
+    assert!(record.line_end);
+    let record = BUFFER.read(first + 1).unwrap();
+    assert_eq!(record.text(), "no newline");
+    assert_eq!(record.level, None);
+    assert!(!record.line_end);
+    assert!(BUFFER.read(first + 2).is_none());
+}
+
+#[test_case]
+fn test_long_lines_split_on_characters() {
+    static BUFFER: LogBuffer = LogBuffer::new();
+    // 111 bytes then a two byte character, which mustn't be split
+    let line = "x".repeat(TEXT_LEN - 1) + "é";
+    BUFFER.record(0, CONSOLE, format_args!("{}\n", line));
+    assert_eq!(BUFFER.next_seq(), 2);
+    let first = BUFFER.read(0).unwrap();
+    assert_eq!(first.text().len(), TEXT_LEN - 1);
+    assert!(!first.line_end);
+    let second = BUFFER.read(1).unwrap();
+    assert_eq!(second.text(), "é");
+    assert!(second.line_end);
+}
+
+#[test_case]
+fn test_overwrite() {
+    static BUFFER: LogBuffer = LogBuffer::new();
+    for i in 0..CAPACITY + 10 {
+        BUFFER.record(0, CONSOLE, format_args!("{}\n", i));
+    }
+    assert_eq!(BUFFER.first_seq(), 10);
+    assert!(BUFFER.read(9).is_none());
+    assert_eq!(BUFFER.read(10).unwrap().text(), "10");
+}
@@ -0,0 +1,368 @@
+//! Kernel logging, behind the `log` crate's macros.
+//!
+//! Records are filtered by level per target, where a target is normally the module
+//! path, so `firstos::block=trace` turns on everything from the block layer. Records
+//! that pass are stamped with the time since boot and handed to every registered
+//! [`Sink`] whose own level allows them.
+//!
+//! Filters can be set at boot with the `log` command line option, a comma separated
+//! list of `level` and `target=level` entries, e.g. `log=warn,firstos::block=debug`.
+
+pub mod buffer;
+pub mod sinks;
+
+use crate::{interrupts, shell};
+use alloc::format;
+use core::fmt::{self, Write};
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+
+pub use buffer::BUFFER;
+pub use sinks::{SERIAL, VGA};
+
+/// Level for targets without a filter of their own.
+pub const DEFAULT_LEVEL: LevelFilter = LevelFilter::Info;
+
+const MAX_SINKS: usize = 8;
+const MAX_FILTERS: usize = 16;
+/// Longest target a filter can match on.
+const MAX_TARGET: usize = 64;
+
+/// A log record on its way to the sinks.
+pub struct Entry<'a> {
+    pub level: Level,
+    pub target: &'a str,
+    /// Timer ticks since boot.
+    pub ticks: u64,
+    pub args: fmt::Arguments<'a>,
+}
+
+impl Entry<'_> {
+    /// Time since boot as whole seconds and milliseconds.
+    pub fn timestamp(&self) -> (u64, u64) {
+        timestamp(self.ticks)
+    }
+}
+
+/// Convert timer ticks to whole seconds and milliseconds.
+fn timestamp(ticks: u64) -> (u64, u64) {
+    let millis = ticks * 1000 * 65536 / interrupts::PIT_FREQUENCY;
+    (millis / 1000, millis % 1000)
+}
+
+impl fmt::Display for Entry<'_> {
+    /// `[    1.234] WARN  firstos::ps2: message`
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (secs, millis) = self.timestamp();
+        write!(
+            f,
+            "[{:>5}.{:03}] {:<5} {}: {}",
+            secs, millis, self.level, self.target, self.args
+        )
+    }
+}
+
+/// Somewhere log records go.
+pub trait Sink: Sync {
+    fn write(&self, entry: &Entry);
+}
+
+#[derive(Clone, Copy)]
+struct SinkSlot {
+    sink: &'static dyn Sink,
+    level: LevelFilter,
+}
+
+static SINKS: Mutex<[Option<SinkSlot>; MAX_SINKS]> = Mutex::new([None; MAX_SINKS]);
+
+#[derive(Clone, Copy)]
+struct Filter {
+    target: [u8; MAX_TARGET],
+    len: usize,
+    level: LevelFilter,
+}
+
+impl Filter {
+    fn target(&self) -> &str {
+        // Only ever filled from a `&str` on a character boundary
+        core::str::from_utf8(&self.target[..self.len]).unwrap_or("")
+    }
+
+    /// Whether this filter covers `target`, i.e. it's the same module or a parent of it.
+    fn matches(&self, target: &str) -> bool {
+        let prefix = self.target();
+        target.starts_with(prefix)
+            && (target.len() == prefix.len() || target[prefix.len()..].starts_with("::"))
+    }
+}
+
+struct Filters {
+    default: LevelFilter,
+    filters: [Option<Filter>; MAX_FILTERS],
+}
+
+impl Filters {
+    /// The level of the most specific filter covering `target`.
+    fn level_for(&self, target: &str) -> LevelFilter {
+        self.filters
+            .iter()
+            .flatten()
+            .filter(|filter| filter.matches(target))
+            .max_by_key(|filter| filter.len)
+            .map_or(self.default, |filter| filter.level)
+    }
+
+    /// The most verbose level any target logs at.
+    fn max_level(&self) -> LevelFilter {
+        self.filters
+            .iter()
+            .flatten()
+            .map(|filter| filter.level)
+            .fold(self.default, Ord::max)
+    }
+
+    fn set(&mut self, target: &str, level: LevelFilter) -> Result<(), Error> {
+        if target.is_empty() {
+            self.default = level;
+            return Ok(());
+        }
+        if target.len() > MAX_TARGET {
+            return Err(Error::TargetTooLong);
+        }
+        let existing = self.filters.iter().position(|slot| match slot {
+            Some(filter) => filter.target() == target,
+            None => false,
+        });
+        let index = existing
+            .or_else(|| self.filters.iter().position(Option::is_none))
+            .ok_or(Error::TooManyFilters)?;
+        let mut filter = Filter {
+            target: [0; MAX_TARGET],
+            len: target.len(),
+            level,
+        };
+        filter.target[..target.len()].copy_from_slice(target.as_bytes());
+        self.filters[index] = Some(filter);
+        Ok(())
+    }
+}
+
+static FILTERS: Mutex<Filters> = Mutex::new(Filters {
+    default: DEFAULT_LEVEL,
+    filters: [None; MAX_FILTERS],
+});
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    TooManyFilters,
+    TooManySinks,
+    TargetTooLong,
+    /// A filter specification didn't parse.
+    Syntax,
+}
+
+struct Logger;
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= without_interrupts(|| FILTERS.lock().level_for(metadata.target()))
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let entry = Entry {
+            level: record.level(),
+            target: record.target(),
+            ticks: interrupts::ticks(),
+            args: *record.args(),
+        };
+        // Copy the sinks out so they can log, or be added to, themselves
+        let sinks = without_interrupts(|| *SINKS.lock());
+        for slot in sinks.iter().flatten() {
+            if entry.level <= slot.level {
+                slot.sink.write(&entry);
+            }
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+static LOGGER: Logger = Logger;
+
+/// Install the logger, sending records to the log console, COM1 and the log buffer.
+///
+/// Should come first, so nothing gets logged before there's a logger.
+pub fn init() {
+    if log::set_logger(&LOGGER).is_err() {
+        // Already done
+        return;
+    }
+    let _ = add_sink(&VGA, LevelFilter::Trace);
+    let _ = add_sink(&SERIAL, LevelFilter::Trace);
+    let _ = add_sink(&BUFFER, LevelFilter::Trace);
+    update_max_level();
+}
+
+/// Log a panic at error level to every sink, whatever the filters say.
+///
+/// # Safety
+///
+/// Interrupts must be off, so that whatever holds the sink list's lock can never run
+/// again. The lock is then released by force.
+pub unsafe fn log_panic(args: fmt::Arguments) {
+    if SINKS.is_locked() {
+        SINKS.force_unlock();
+    }
+    let sinks = *SINKS.lock();
+    let entry = Entry {
+        level: Level::Error,
+        target: "panic",
+        ticks: interrupts::ticks(),
+        args,
+    };
+    if sinks.iter().all(Option::is_none) {
+        // Panicked before `init`
+        let builtin: [&dyn Sink; 3] = [&VGA, &SERIAL, &BUFFER];
+        for sink in &builtin {
+            sink.write(&entry);
+        }
+    }
+    for slot in sinks.iter().flatten() {
+        slot.sink.write(&entry);
+    }
+}
+
+/// Send records up to `level` to `sink` as well.
+pub fn add_sink(sink: &'static dyn Sink, level: LevelFilter) -> Result<(), Error> {
+    without_interrupts(|| {
+        let mut sinks = SINKS.lock();
+        let slot = sinks
+            .iter_mut()
+            .find(|slot| slot.is_none())
+            .ok_or(Error::TooManySinks)?;
+        *slot = Some(SinkSlot { sink, level });
+        Ok(())
+    })
+}
+
+/// Log `target` and the modules under it at `level`. An empty target sets the
+/// default level.
+pub fn set_level(target: &str, level: LevelFilter) -> Result<(), Error> {
+    without_interrupts(|| FILTERS.lock().set(target, level))?;
+    update_max_level();
+    Ok(())
+}
+
+pub fn level_for(target: &str) -> LevelFilter {
+    without_interrupts(|| FILTERS.lock().level_for(target))
+}
+
+/// Apply a filter specification like `warn,firstos::block=debug`.
+pub fn parse_filters(spec: &str) -> Result<(), Error> {
+    for part in spec.split(',').filter(|part| !part.is_empty()) {
+        let (target, level) = match part.find('=') {
+            Some(i) => (&part[..i], &part[i + 1..]),
+            None => ("", part),
+        };
+        let level = level.parse().map_err(|_| Error::Syntax)?;
+        set_level(target, level)?;
+    }
+    Ok(())
+}
+
+/// Apply the `log` command line option and register the `log` and `dmesg` shell
+/// commands.
+///
+/// Needs the heap, to read the command line.
+pub fn apply_boot_options() {
+    shell::register(&LOG_COMMAND);
+    shell::register(&DMESG_COMMAND);
+    if let Some(spec) = crate::cmdline::get("log") {
+        if let Err(e) = parse_filters(spec) {
+            log::warn!("bad log filter {:?}: {:?}", spec, e);
+        }
+    }
+}
+
+static LOG_COMMAND: shell::Command = shell::Command {
+    name: "log",
+    usage: "[<level> | <target>=<level>],...",
+    help: "Show or change which log records are kept",
+    run: log_command,
+};
+
+fn log_command(args: &[&str], out: &mut dyn Write) -> Result<(), shell::Error> {
+    if args.is_empty() {
+        let (default, filters) = without_interrupts(|| {
+            let filters = FILTERS.lock();
+            (filters.default, filters.filters)
+        });
+        let _ = writeln!(out, "default: {}", default);
+        for filter in filters.iter().flatten() {
+            let _ = writeln!(out, "{}: {}", filter.target(), filter.level);
+        }
+        return Ok(());
+    }
+    for spec in args {
+        parse_filters(spec).map_err(|e| shell::Error::Failed(format!("{:?}: {:?}", spec, e)))?;
+    }
+    Ok(())
+}
+
+static DMESG_COMMAND: shell::Command = shell::Command {
+    name: "dmesg",
+    usage: "[<count>]",
+    help: "Show the kernel log buffer, or its last <count> records",
+    run: dmesg,
+};
+
+fn dmesg(args: &[&str], out: &mut dyn Write) -> Result<(), shell::Error> {
+    let first = match args {
+        [] => 0,
+        [count] => {
+            let count: u64 = count.parse().map_err(|_| shell::Error::Usage)?;
+            BUFFER
+                .next_seq()
+                .saturating_sub(count)
+                .max(BUFFER.first_seq())
+        }
+        _ => return Err(shell::Error::Usage),
+    };
+    let _ = BUFFER.dump(out, first);
+    Ok(())
+}
+
+fn update_max_level() {
+    log::set_max_level(without_interrupts(|| FILTERS.lock().max_level()));
+}
+
+#[test_case]
+fn test_filters() {
+    let mut filters = Filters {
+        default: LevelFilter::Warn,
+        filters: [None; MAX_FILTERS],
+    };
+    filters.set("firstos::block", LevelFilter::Debug).unwrap();
+    filters
+        .set("firstos::block::cache", LevelFilter::Trace)
+        .unwrap();
+    assert_eq!(filters.level_for("firstos::block"), LevelFilter::Debug);
+    assert_eq!(filters.level_for("firstos::block::mod"), LevelFilter::Debug);
+    assert_eq!(
+        filters.level_for("firstos::block::cache"),
+        LevelFilter::Trace
+    );
+    // Only whole path components match
+    assert_eq!(filters.level_for("firstos::blockchain"), LevelFilter::Warn);
+    assert_eq!(filters.level_for("firstos"), LevelFilter::Warn);
+    assert_eq!(filters.max_level(), LevelFilter::Trace);
+
+    filters.set("", LevelFilter::Off).unwrap();
+    filters.set("firstos::block", LevelFilter::Error).unwrap();
+    assert_eq!(filters.level_for("firstos"), LevelFilter::Off);
+    assert_eq!(filters.level_for("firstos::block"), LevelFilter::Error);
+}
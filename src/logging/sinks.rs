@@ -0,0 +1,24 @@
+//! Sinks writing log records out to the consoles.
+
+use super::{Entry, Sink};
+
+/// The kernel log console.
+pub static VGA: VgaSink = VgaSink;
+/// COM1.
+pub static SERIAL: SerialSink = SerialSink;
+
+pub struct VgaSink;
+
+impl Sink for VgaSink {
+    fn write(&self, entry: &Entry) {
+        crate::log_println!("{}", entry);
+    }
+}
+
+pub struct SerialSink;
+
+impl Sink for SerialSink {
+    fn write(&self, entry: &Entry) {
+        crate::serial_println!("{}", entry);
+    }
+}
@@ -5,7 +5,107 @@ use x86_64::{
     PhysAddr, VirtAddr,
 };
 
-use bootloader::bootinfo::{BootInfo, MemoryRegionType};
+use bootloader::bootinfo::{BootInfo, MemoryMap, MemoryRegionType};
+use core::ops::Range;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+/// Where physical memory is mapped, set by `init`.
+static PHYS_MEM_OFFSET: AtomicU64 = AtomicU64::new(0);
+
+/// The frame allocator once the kernel's up, set by `set_frame_allocator`.
+static FRAME_ALLOCATOR: Mutex<Option<ListFrameAllocator>> = Mutex::new(None);
+
+/// The bootloader's memory map, set by `ListFrameAllocator::init`.
+static MEMORY_MAP: Mutex<Option<&'static MemoryMap>> = Mutex::new(None);
+
+/// Legacy VGA memory, which the memory map leaves out but the bootloader maps anyway.
+const VGA_WINDOW: Range<u64> = 0xa0000..0xc0000;
+
+static TOTAL_FRAMES: AtomicUsize = AtomicUsize::new(0);
+static FREE_FRAMES: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct FrameStats {
+    /// Usable frames in the memory map.
+    pub total: usize,
+    /// Frames not handed out by the frame allocator.
+    pub free: usize,
+}
+
+pub fn frame_stats() -> FrameStats {
+    FrameStats {
+        total: TOTAL_FRAMES.load(Ordering::Relaxed),
+        free: FREE_FRAMES.load(Ordering::Relaxed),
+    }
+}
+
+/// The virtual address physical address `addr` is mapped at.
+///
+/// Only meaningful after `init`.
+pub fn phys_to_virt(addr: PhysAddr) -> VirtAddr {
+    VirtAddr::new(addr.as_u64() + PHYS_MEM_OFFSET.load(Ordering::Relaxed))
+}
+
+/// The physical address of `addr`, in the mapping of physical memory.
+pub fn virt_to_phys(addr: VirtAddr) -> PhysAddr {
+    PhysAddr::new(addr.as_u64() - PHYS_MEM_OFFSET.load(Ordering::Relaxed))
+}
+
+/// Whether all of `range` is physical memory that's there to be read, RAM or VGA memory.
+///
+/// That's the regions in the memory map, other than bad or empty ones, and the VGA
+/// window. Anything else may be missing from the mapping of physical memory.
+pub fn is_backed(range: Range<PhysAddr>) -> bool {
+    let map = match interrupts::without_interrupts(|| *MEMORY_MAP.lock()) {
+        Some(map) => map,
+        None => return false,
+    };
+    let backing = map
+        .iter()
+        .filter(|r| {
+            !matches!(
+                r.region_type,
+                MemoryRegionType::BadMemory | MemoryRegionType::Empty
+            )
+        })
+        .map(|r| r.range.start_addr()..r.range.end_addr())
+        .chain(core::iter::once(VGA_WINDOW));
+    // Regions can be split up, so step from one to the next until we're past the end
+    let mut next = range.start.as_u64();
+    while next < range.end.as_u64() {
+        match backing.clone().find(|r| r.contains(&next)) {
+            Some(region) => next = region.end,
+            None => return false,
+        }
+    }
+    true
+}
+
+/// Hand over the frame allocator used to set up the heap, for anything after that
+/// needing whole frames.
+pub fn set_frame_allocator(frame_allocator: ListFrameAllocator) {
+    interrupts::without_interrupts(|| *FRAME_ALLOCATOR.lock() = Some(frame_allocator));
+}
+
+/// A frame from the allocator given to `set_frame_allocator`, if there's one left.
+pub fn allocate_frame() -> Option<PhysFrame> {
+    interrupts::without_interrupts(|| FRAME_ALLOCATOR.lock().as_mut()?.allocate_frame())
+}
+
+/// Give back a frame from `allocate_frame`.
+///
+/// # Safety
+///
+/// The frame mustn't be used any more.
+pub unsafe fn deallocate_frame(frame: PhysFrame) {
+    interrupts::without_interrupts(|| {
+        if let Some(frame_allocator) = FRAME_ALLOCATOR.lock().as_mut() {
+            frame_allocator.deallocate_frame(frame);
+        }
+    });
+}
 
 /// Initialize a new OffsetPageTable.
 ///
@@ -14,6 +114,7 @@ use bootloader::bootinfo::{BootInfo, MemoryRegionType};
 /// This function is unsafe as the caller must guarantee that the entirety
 /// of physical memory is mapped to virtual memory at the provided `physical_memory_offset`;
 pub unsafe fn init(physical_memory_offset: VirtAddr) -> OffsetPageTable<'static> {
+    PHYS_MEM_OFFSET.store(physical_memory_offset.as_u64(), Ordering::Relaxed);
     let l4_table = active_level_4_table(physical_memory_offset);
     OffsetPageTable::new(l4_table, physical_memory_offset)
 }
@@ -60,6 +161,7 @@ impl ListFrameAllocator {
     /// really unused.
     pub unsafe fn init(boot_info: &'static BootInfo) -> Self {
         let phys_mem_offset = boot_info.physical_memory_offset;
+        *MEMORY_MAP.lock() = Some(&boot_info.memory_map);
         let regions = boot_info.memory_map.iter();
         let usable_regions = regions.filter(|r| r.region_type == MemoryRegionType::Usable);
         let addr_ranges = usable_regions.map(|r| r.range.start_addr()..r.range.end_addr());
@@ -77,6 +179,7 @@ impl ListFrameAllocator {
 
         let first_frame = &mut *first_ptr;
         let mut current = &mut *first_ptr;
+        let mut frames = 1;
 
         for frame_ptr in frame_infos {
             let frame_ptr = frame_ptr as *mut FrameInfo;
@@ -84,7 +187,10 @@ impl ListFrameAllocator {
 
             current.next = Some(&mut *frame_ptr);
             current = &mut *frame_ptr;
+            frames += 1;
         }
+        TOTAL_FRAMES.store(frames, Ordering::Relaxed);
+        FREE_FRAMES.store(frames, Ordering::Relaxed);
 
         ListFrameAllocator {
             phys_mem_offset,
@@ -99,6 +205,7 @@ unsafe impl FrameAllocator<Size4KiB> for ListFrameAllocator {
             None => None,
             Some(frame_info) => {
                 self.next_free = frame_info.next.take();
+                FREE_FRAMES.fetch_sub(1, Ordering::Relaxed);
                 let phys_addr = frame_info.start_addr() as u64 - self.phys_mem_offset;
                 unsafe {
                     Some(PhysFrame::from_start_address_unchecked(
@@ -118,5 +225,6 @@ impl FrameDeallocator<Size4KiB> for ListFrameAllocator {
             next: self.next_free.take(),
         });
         self.next_free = Some(&mut *frame_ptr);
+        FREE_FRAMES.fetch_add(1, Ordering::Relaxed);
     }
 }
@@ -0,0 +1,291 @@
+//! PS/2 mouse on IRQ12.
+//!
+//! The interrupt handler assembles the mouse's 3-byte packets, or 4-byte ones when it
+//! has a scroll wheel, and decodes them into `MouseEvent`s. Events are queued for
+//! `next_event` and also move a text-mode cursor around the console.
+
+use crate::ps2::{self, Channel};
+use crate::{interrupts, vga};
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::Mutex;
+
+const SET_DEFAULTS: u8 = 0xf6;
+const ENABLE_REPORTING: u8 = 0xf4;
+const GET_ID: u8 = 0xf2;
+const SET_SAMPLE_RATE: u8 = 0xf3;
+/// ID of a mouse that has switched to 4-byte packets with a scroll wheel.
+const ID_WHEEL: u8 = 3;
+
+/// Bit in a packet's first byte that's always set, used to find packet boundaries.
+const ALWAYS_SET: u8 = 1 << 3;
+const X_SIGN: u8 = 1 << 4;
+const Y_SIGN: u8 = 1 << 5;
+const X_OVERFLOW: u8 = 1 << 6;
+const Y_OVERFLOW: u8 = 1 << 7;
+
+/// Mouse movement per text cell, in the mouse's counts.
+const COUNTS_PER_COLUMN: i32 = 8;
+const COUNTS_PER_ROW: i32 = 16;
+const SCREEN_COLUMNS: i32 = 80;
+const SCREEN_ROWS: i32 = 25;
+
+const QUEUE_LEN: usize = 32;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Buttons {
+    pub left: bool,
+    pub right: bool,
+    pub middle: bool,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct MouseEvent {
+    /// Movement to the right.
+    pub dx: i16,
+    /// Movement up.
+    pub dy: i16,
+    /// Wheel movement, positive towards the user.
+    pub wheel: i8,
+    /// Buttons held down after this event.
+    pub buttons: Buttons,
+}
+
+/// Decode a complete 3- or 4-byte packet.
+///
+/// Movement is dropped if the mouse reports that its counters overflowed.
+fn decode(packet: &[u8]) -> MouseEvent {
+    let flags = packet[0];
+    let axis = |value: u8, sign: u8, overflow: u8| {
+        if flags & overflow != 0 {
+            0
+        } else if flags & sign != 0 {
+            value as i16 - 0x100
+        } else {
+            value as i16
+        }
+    };
+    let wheel = match packet.get(3) {
+        // Only the low four bits are the wheel, as a signed number
+        Some(&z) => ((z << 4) as i8) >> 4,
+        None => 0,
+    };
+    MouseEvent {
+        dx: axis(packet[1], X_SIGN, X_OVERFLOW),
+        dy: axis(packet[2], Y_SIGN, Y_OVERFLOW),
+        wheel,
+        buttons: Buttons {
+            left: flags & (1 << 0) != 0,
+            right: flags & (1 << 1) != 0,
+            middle: flags & (1 << 2) != 0,
+        },
+    }
+}
+
+/// Gathers bytes from the mouse into packets.
+struct Assembler {
+    bytes: [u8; 4],
+    len: usize,
+    /// Bytes in a packet, 3 or 4.
+    size: usize,
+}
+
+impl Assembler {
+    const fn new(size: usize) -> Self {
+        Self {
+            bytes: [0; 4],
+            len: 0,
+            size,
+        }
+    }
+
+    fn push(&mut self, byte: u8) -> Option<MouseEvent> {
+        if self.len == 0 && byte & ALWAYS_SET == 0 {
+            // Out of sync, so this can't be the start of a packet
+            return None;
+        }
+        self.bytes[self.len] = byte;
+        self.len += 1;
+        if self.len < self.size {
+            return None;
+        }
+        self.len = 0;
+        Some(decode(&self.bytes[..self.size]))
+    }
+}
+
+struct Queue {
+    events: [MouseEvent; QUEUE_LEN],
+    start: usize,
+    len: usize,
+}
+
+/// Where the mouse cursor is, in counts from the top left of the screen.
+struct Pointer {
+    x: i32,
+    y: i32,
+}
+
+struct State {
+    assembler: Assembler,
+    queue: Queue,
+    pointer: Pointer,
+}
+
+/// Everything the interrupt handler touches. Only locked with interrupts disabled
+/// outside the handler.
+static STATE: Mutex<State> = Mutex::new(State {
+    assembler: Assembler::new(3),
+    queue: Queue {
+        events: [MouseEvent {
+            dx: 0,
+            dy: 0,
+            wheel: 0,
+            buttons: Buttons {
+                left: false,
+                right: false,
+                middle: false,
+            },
+        }; QUEUE_LEN],
+        start: 0,
+        len: 0,
+    },
+    pointer: Pointer {
+        x: SCREEN_COLUMNS / 2 * COUNTS_PER_COLUMN,
+        y: SCREEN_ROWS / 2 * COUNTS_PER_ROW,
+    },
+});
+
+static PRESENT: AtomicBool = AtomicBool::new(false);
+static HAS_WHEEL: AtomicBool = AtomicBool::new(false);
+
+/// Set up the mouse on the controller's second port and start taking interrupts from it.
+pub fn init() -> Result<(), ps2::Error> {
+    ps2::reset_device(Channel::Second)?;
+    ps2::device_command(Channel::Second, &[SET_DEFAULTS])?;
+    // This sequence of sample rates is the magic knock that turns on the scroll wheel
+    ps2::device_command(
+        Channel::Second,
+        &[
+            SET_SAMPLE_RATE,
+            200,
+            SET_SAMPLE_RATE,
+            100,
+            SET_SAMPLE_RATE,
+            80,
+        ],
+    )?;
+    let mut id = [0];
+    ps2::device_command_reply(Channel::Second, &[GET_ID], &mut id)?;
+    let wheel = id[0] == ID_WHEEL;
+
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        STATE.lock().assembler = Assembler::new(if wheel { 4 } else { 3 });
+    });
+    HAS_WHEEL.store(wheel, Ordering::Relaxed);
+    ps2::device_command(Channel::Second, &[ENABLE_REPORTING])?;
+    PRESENT.store(true, Ordering::Relaxed);
+    interrupts::unmask_irq(interrupts::InterruptIndex::Mouse.irq());
+    Ok(())
+}
+
+/// Whether `init` found a mouse.
+pub fn is_present() -> bool {
+    PRESENT.load(Ordering::Relaxed)
+}
+
+/// Whether the mouse has a scroll wheel.
+pub fn has_wheel() -> bool {
+    HAS_WHEEL.load(Ordering::Relaxed)
+}
+
+/// Take the oldest queued event.
+pub fn next_event() -> Option<MouseEvent> {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let queue = &mut STATE.lock().queue;
+        if queue.len == 0 {
+            return None;
+        }
+        let event = queue.events[queue.start];
+        queue.start = (queue.start + 1) % QUEUE_LEN;
+        queue.len -= 1;
+        Some(event)
+    })
+}
+
+/// The text cell under the mouse cursor, as `(row, column)`.
+pub fn position() -> (usize, usize) {
+    x86_64::instructions::interrupts::without_interrupts(|| STATE.lock().pointer.cell())
+}
+
+impl Pointer {
+    fn cell(&self) -> (usize, usize) {
+        (
+            (self.y / COUNTS_PER_ROW) as usize,
+            (self.x / COUNTS_PER_COLUMN) as usize,
+        )
+    }
+
+    fn apply(&mut self, event: &MouseEvent) {
+        let max_x = SCREEN_COLUMNS * COUNTS_PER_COLUMN - 1;
+        let max_y = SCREEN_ROWS * COUNTS_PER_ROW - 1;
+        self.x = (self.x + event.dx as i32).max(0).min(max_x);
+        // Screen rows count down while the mouse counts up
+        self.y = (self.y - event.dy as i32).max(0).min(max_y);
+    }
+}
+
+/// Handle a byte from the mouse. Called from the interrupt handler.
+pub(crate) fn push_byte(byte: u8) {
+    let mut state = STATE.lock();
+    let event = match state.assembler.push(byte) {
+        Some(event) => event,
+        None => return,
+    };
+
+    let queue = &mut state.queue;
+    if queue.len == QUEUE_LEN {
+        // Drop the oldest event rather than the newest
+        queue.start = (queue.start + 1) % QUEUE_LEN;
+        queue.len -= 1;
+    }
+    let end = (queue.start + queue.len) % QUEUE_LEN;
+    queue.events[end] = event;
+    queue.len += 1;
+
+    state.pointer.apply(&event);
+    vga::try_set_mouse_cursor(Some(state.pointer.cell()));
+}
+
+#[test_case]
+fn test_decode() {
+    // Left button, moved right 5 and down 3
+    let event = decode(&[0b0010_1001, 5, 0xfd]);
+    assert_eq!(event.dx, 5);
+    assert_eq!(event.dy, -3);
+    assert_eq!(event.wheel, 0);
+    assert!(event.buttons.left && !event.buttons.right && !event.buttons.middle);
+
+    // Overflowed X, wheel one step up
+    let event = decode(&[0b0111_1010, 0x80, 0xff, 0x0f]);
+    assert_eq!(event.dx, 0);
+    assert_eq!(event.dy, -1);
+    assert_eq!(event.wheel, -1);
+    assert!(event.buttons.right);
+}
+
+#[test_case]
+fn test_assembler_resyncs() {
+    let mut assembler = Assembler::new(3);
+    // A stray byte without the always-set bit is skipped
+    assert_eq!(assembler.push(0x00), None);
+    assert_eq!(assembler.push(0x08), None);
+    assert_eq!(assembler.push(1), None);
+    let event = assembler.push(2).unwrap();
+    assert_eq!((event.dx, event.dy), (1, 2));
+
+    let mut assembler = Assembler::new(4);
+    for &byte in &[0x08, 0, 0] {
+        assert_eq!(assembler.push(byte), None);
+    }
+    assert_eq!(assembler.push(0x01).unwrap().wheel, 1);
+}
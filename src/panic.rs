@@ -0,0 +1,69 @@
+//! Getting panic messages out, however broken things are.
+//!
+//! A panic can come while the consoles, serial ports or logger are locked, by the very
+//! code that panicked or by whatever an exception interrupted. With interrupts off
+//! nothing else can run, so those locks will never be released and the panic path takes
+//! them by force. Panicking again while reporting a panic falls back to writing straight
+//! to the hardware.
+
+use crate::{hlt_loop, logging, serial, vga};
+use core::fmt::{self, Write};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use x86_64::instructions::interrupts;
+
+/// How many panics are in progress.
+static PANICKING: AtomicUsize = AtomicUsize::new(0);
+
+/// Report a panic to the log, both consoles and COM1, then halt.
+pub fn handle(info: &PanicInfo) -> ! {
+    interrupts::disable();
+    match PANICKING.fetch_add(1, Ordering::SeqCst) {
+        0 => {}
+        1 => {
+            let mut out = Emergency {
+                line: [0; LINE_LEN],
+                len: 0,
+            };
+            let _ = write!(out, "panic while panicking: {}", info);
+            serial::write_emergency(b"\n");
+            let line = core::str::from_utf8(&out.line[..out.len]).unwrap_or("");
+            vga::write_emergency(line);
+            hlt_loop();
+        }
+        // Even the emergency path panicked
+        _ => hlt_loop(),
+    }
+
+    unsafe {
+        vga::prepare_for_panic();
+        serial::prepare_for_panic();
+        logging::log_panic(format_args!("{}", info));
+    }
+    logging::buffer::dump_to_serial();
+    vga::switch_console(vga::LOG_CONSOLE);
+    hlt_loop();
+}
+
+/// As much of a message as fits on one row of the screen.
+const LINE_LEN: usize = 80;
+
+/// Writes straight to COM1, keeping the start of the message for the screen.
+struct Emergency {
+    line: [u8; LINE_LEN],
+    len: usize,
+}
+
+impl Write for Emergency {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        serial::write_emergency(s.as_bytes());
+        for c in s.chars() {
+            if self.len + c.len_utf8() > LINE_LEN {
+                break;
+            }
+            c.encode_utf8(&mut self.line[self.len..]);
+            self.len += c.len_utf8();
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,272 @@
+//! The 8042 PS/2 controller.
+//!
+//! Everything here polls the controller, so it runs with interrupts disabled to keep
+//! the keyboard and mouse interrupt handlers from eating the replies.
+
+use x86_64::instructions::interrupts;
+use x86_64::instructions::port::Port;
+
+const DATA_PORT: u16 = 0x60;
+/// Status register on read, command register on write.
+const STATUS_PORT: u16 = 0x64;
+
+const STATUS_OUTPUT_FULL: u8 = 1 << 0;
+const STATUS_INPUT_FULL: u8 = 1 << 1;
+
+const COMMAND_READ_CONFIG: u8 = 0x20;
+const COMMAND_WRITE_CONFIG: u8 = 0x60;
+const COMMAND_DISABLE_SECOND: u8 = 0xa7;
+const COMMAND_ENABLE_SECOND: u8 = 0xa8;
+const COMMAND_TEST_SECOND: u8 = 0xa9;
+const COMMAND_SELF_TEST: u8 = 0xaa;
+const COMMAND_TEST_FIRST: u8 = 0xab;
+const COMMAND_DISABLE_FIRST: u8 = 0xad;
+const COMMAND_ENABLE_FIRST: u8 = 0xae;
+/// Send the next data byte to the second port instead of the first.
+const COMMAND_WRITE_SECOND: u8 = 0xd4;
+/// Pulse the CPU reset line.
+const COMMAND_RESET_CPU: u8 = 0xfe;
+
+/// Configuration byte bit enabling IRQ1 for the first port.
+pub const CONFIG_FIRST_IRQ: u8 = 1 << 0;
+/// Configuration byte bit enabling IRQ12 for the second port.
+pub const CONFIG_SECOND_IRQ: u8 = 1 << 1;
+/// Configuration byte bit that stops the second port's clock, i.e. disables it.
+const CONFIG_SECOND_CLOCK_OFF: u8 = 1 << 5;
+/// Configuration byte bit translating set 2 scancodes from the keyboard into set 1.
+pub const CONFIG_TRANSLATION: u8 = 1 << 6;
+
+const SELF_TEST_PASSED: u8 = 0x55;
+const PORT_TEST_PASSED: u8 = 0x00;
+
+/// Device command to reset and run its self-test.
+pub const DEVICE_RESET: u8 = 0xff;
+/// What a device sends after a successful reset.
+pub const DEVICE_RESET_PASSED: u8 = 0xaa;
+
+/// Keyboard command to get or set the scancode set.
+pub const KEYBOARD_SCANCODE_SET: u8 = 0xf0;
+
+const ACK: u8 = 0xfa;
+const RESEND: u8 = 0xfe;
+
+/// Polls of the status register before giving up on the controller.
+const TIMEOUT: usize = 100_000;
+/// Devices can take hundreds of milliseconds to reply after a reset.
+const RESET_TIMEOUT: usize = 10_000_000;
+const RETRIES: usize = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// The controller never became ready.
+    Timeout,
+    /// The device replied with something other than an acknowledgement.
+    NoAck(u8),
+    /// The controller failed its self-test, replying with this.
+    SelfTest(u8),
+    /// A port failed its interface test, with this error code.
+    PortTest(u8),
+    /// A device failed to reset, replying with this.
+    Reset(u8),
+}
+
+type Result<T> = core::result::Result<T, Error>;
+
+/// One of the controller's two ports. The keyboard is normally on the first and the
+/// mouse on the second.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Channel {
+    First,
+    Second,
+}
+
+/// Which ports came up in `init`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Ports {
+    pub first: bool,
+    pub second: bool,
+}
+
+/// Reset the controller into a known state.
+///
+/// Runs the controller's self-test and both port tests, resets the keyboard, and
+/// enables IRQ1 and IRQ12 for the ports that work. The keyboard is left sending set 2
+/// scancodes with translation to set 1 turned on.
+pub fn init() -> Result<Ports> {
+    interrupts::without_interrupts(|| unsafe {
+        command(COMMAND_DISABLE_FIRST)?;
+        command(COMMAND_DISABLE_SECOND)?;
+        flush();
+
+        let config = read_config_unlocked()? & !(CONFIG_FIRST_IRQ | CONFIG_SECOND_IRQ);
+        write_config_unlocked(config & !CONFIG_TRANSLATION)?;
+
+        command(COMMAND_SELF_TEST)?;
+        let reply = read_data(TIMEOUT)?;
+        if reply != SELF_TEST_PASSED {
+            return Err(Error::SelfTest(reply));
+        }
+        // The self-test can reset the controller on some hardware
+        write_config_unlocked(config & !CONFIG_TRANSLATION)?;
+
+        // The second port's clock only turns on when it's enabled if there is one
+        command(COMMAND_ENABLE_SECOND)?;
+        let dual = read_config_unlocked()? & CONFIG_SECOND_CLOCK_OFF == 0;
+        command(COMMAND_DISABLE_SECOND)?;
+
+        let mut ports = Ports {
+            first: port_test(COMMAND_TEST_FIRST)?,
+            second: dual && port_test(COMMAND_TEST_SECOND)?,
+        };
+        if ports.first {
+            command(COMMAND_ENABLE_FIRST)?;
+            ports.first = reset(Channel::First).is_ok();
+        }
+        if ports.second {
+            command(COMMAND_ENABLE_SECOND)?;
+        }
+
+        // Enabling the ports changed the clock bits, so start from a fresh copy
+        let mut config = read_config_unlocked()? | CONFIG_TRANSLATION;
+        if ports.first {
+            config |= CONFIG_FIRST_IRQ;
+        }
+        if ports.second {
+            config |= CONFIG_SECOND_IRQ;
+        }
+        write_config_unlocked(config)?;
+        Ok(ports)
+    })
+}
+
+/// Read the controller configuration byte.
+pub fn read_config() -> Result<u8> {
+    interrupts::without_interrupts(|| unsafe { read_config_unlocked() })
+}
+
+/// Write the controller configuration byte.
+pub fn write_config(config: u8) -> Result<()> {
+    interrupts::without_interrupts(|| unsafe { write_config_unlocked(config) })
+}
+
+/// Send `bytes` to the device on `channel`, each of which must be acknowledged.
+pub fn device_command(channel: Channel, bytes: &[u8]) -> Result<()> {
+    device_command_reply(channel, bytes, &mut [])
+}
+
+/// Send `bytes` to the device on `channel`, then read `reply.len()` bytes of reply.
+pub fn device_command_reply(channel: Channel, bytes: &[u8], reply: &mut [u8]) -> Result<()> {
+    interrupts::without_interrupts(|| unsafe {
+        for &byte in bytes {
+            send(channel, byte)?;
+        }
+        for b in reply {
+            *b = read_data(TIMEOUT)?;
+        }
+        Ok(())
+    })
+}
+
+/// Reboot the machine through the controller's CPU reset line. Returns if the
+/// controller didn't respond.
+pub fn reset_cpu() {
+    interrupts::without_interrupts(|| unsafe {
+        let _ = command(COMMAND_RESET_CPU);
+    })
+}
+
+/// Reset the device on `channel`.
+pub fn reset_device(channel: Channel) -> Result<()> {
+    interrupts::without_interrupts(|| unsafe { reset(channel) })
+}
+
+unsafe fn reset(channel: Channel) -> Result<()> {
+    send(channel, DEVICE_RESET)?;
+    let reply = read_data(RESET_TIMEOUT)?;
+    if reply != DEVICE_RESET_PASSED {
+        return Err(Error::Reset(reply));
+    }
+    // Mice follow up with their device ID
+    if channel == Channel::Second {
+        read_data(TIMEOUT)?;
+    }
+    Ok(())
+}
+
+unsafe fn port_test(command_byte: u8) -> Result<bool> {
+    command(command_byte)?;
+    let reply = read_data(TIMEOUT)?;
+    if reply != PORT_TEST_PASSED {
+        log::warn!("port test failed: {:?}", Error::PortTest(reply));
+    }
+    Ok(reply == PORT_TEST_PASSED)
+}
+
+/// Throw away anything sitting in the output buffer.
+unsafe fn flush() {
+    let mut status = Port::<u8>::new(STATUS_PORT);
+    let mut data = Port::<u8>::new(DATA_PORT);
+    for _ in 0..TIMEOUT {
+        if status.read() & STATUS_OUTPUT_FULL == 0 {
+            return;
+        }
+        data.read();
+    }
+}
+
+unsafe fn read_config_unlocked() -> Result<u8> {
+    command(COMMAND_READ_CONFIG)?;
+    read_data(TIMEOUT)
+}
+
+unsafe fn write_config_unlocked(config: u8) -> Result<()> {
+    command(COMMAND_WRITE_CONFIG)?;
+    write_data(config)
+}
+
+unsafe fn wait_for(mask: u8, set: bool, polls: usize) -> Result<()> {
+    let mut status = Port::<u8>::new(STATUS_PORT);
+    for _ in 0..polls {
+        if (status.read() & mask != 0) == set {
+            return Ok(());
+        }
+    }
+    Err(Error::Timeout)
+}
+
+unsafe fn command(command: u8) -> Result<()> {
+    wait_for(STATUS_INPUT_FULL, false, TIMEOUT)?;
+    Port::new(STATUS_PORT).write(command);
+    Ok(())
+}
+
+unsafe fn read_data(polls: usize) -> Result<u8> {
+    wait_for(STATUS_OUTPUT_FULL, true, polls)?;
+    Ok(Port::new(DATA_PORT).read())
+}
+
+unsafe fn write_data(byte: u8) -> Result<()> {
+    wait_for(STATUS_INPUT_FULL, false, TIMEOUT)?;
+    Port::new(DATA_PORT).write(byte);
+    Ok(())
+}
+
+/// Send one byte to the device on `channel`, resending it if asked to.
+unsafe fn send(channel: Channel, byte: u8) -> Result<()> {
+    let mut reply = RESEND;
+    for _ in 0..RETRIES {
+        if channel == Channel::Second {
+            command(COMMAND_WRITE_SECOND)?;
+        }
+        write_data(byte)?;
+        reply = read_data(TIMEOUT)?;
+        if reply != RESEND {
+            break;
+        }
+    }
+    if reply == ACK {
+        Ok(())
+    } else {
+        Err(Error::NoAck(reply))
+    }
+}
@@ -1,3 +1,5 @@
+use alloc::{vec, vec::Vec};
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(u32)]
 pub enum ExitCode {
@@ -7,8 +9,96 @@ pub enum ExitCode {
 
 pub fn exit(exit_code: ExitCode) {
     use x86_64::instructions::port::Port;
+    // Make sure the host sees all our output first
+    crate::serial::flush_all();
     unsafe {
         let mut port = Port::new(0xf4);
         port.write(exit_code as u32);
     }
 }
+
+/// Read the contents of a file from QEMU's firmware configuration device, as passed
+/// with `-fw_cfg name=<name>,...`.
+///
+/// Returns `None` if there's no such file or we're not running under QEMU.
+pub fn fw_cfg_file(name: &str) -> Option<Vec<u8>> {
+    use x86_64::instructions::interrupts;
+    interrupts::without_interrupts(|| unsafe {
+        let mut fw_cfg = FwCfg::new();
+        let (select, size) = fw_cfg.find(name)?;
+        let mut contents = vec![0; size];
+        fw_cfg.select(select);
+        fw_cfg.read(&mut contents);
+        Some(contents)
+    })
+}
+
+/// Like `fw_cfg_file`, but reads into `buf` so it works without the heap. Returns how
+/// much was read, which is less than the file's size if `buf` was too small.
+pub fn fw_cfg_read(name: &str, buf: &mut [u8]) -> Option<usize> {
+    use x86_64::instructions::interrupts;
+    interrupts::without_interrupts(|| unsafe {
+        let mut fw_cfg = FwCfg::new();
+        let (select, size) = fw_cfg.find(name)?;
+        let len = size.min(buf.len());
+        fw_cfg.select(select);
+        fw_cfg.read(&mut buf[..len]);
+        Some(len)
+    })
+}
+
+const FW_CFG_SIGNATURE: u16 = 0x0000;
+const FW_CFG_FILE_DIR: u16 = 0x0019;
+
+/// The legacy I/O port interface to fw_cfg.
+struct FwCfg {
+    selector: x86_64::instructions::port::Port<u16>,
+    data: x86_64::instructions::port::Port<u8>,
+}
+
+impl FwCfg {
+    fn new() -> Self {
+        use x86_64::instructions::port::Port;
+        Self {
+            selector: Port::new(0x510),
+            data: Port::new(0x511),
+        }
+    }
+
+    unsafe fn select(&mut self, key: u16) {
+        self.selector.write(key);
+    }
+
+    unsafe fn read(&mut self, buf: &mut [u8]) {
+        for b in buf {
+            *b = self.data.read();
+        }
+    }
+
+    /// Look up a file, returning its selector key and size.
+    unsafe fn find(&mut self, name: &str) -> Option<(u16, usize)> {
+        self.select(FW_CFG_SIGNATURE);
+        let mut signature = [0; 4];
+        self.read(&mut signature);
+        if &signature != b"QEMU" {
+            return None;
+        }
+
+        self.select(FW_CFG_FILE_DIR);
+        let mut count = [0; 4];
+        self.read(&mut count);
+        for _ in 0..u32::from_be_bytes(count) {
+            // struct FWCfgFile { be32 size; be16 select; u16 reserved; char name[56]; }
+            let mut entry = [0; 64];
+            self.read(&mut entry);
+            let name_len = entry[8..].iter().position(|&b| b == 0).unwrap_or(56);
+            if &entry[8..8 + name_len] != name.as_bytes() {
+                continue;
+            }
+            let size = u32::from_be_bytes([entry[0], entry[1], entry[2], entry[3]]);
+            let select = u16::from_be_bytes([entry[4], entry[5]]);
+            return Some((select, size as usize));
+        }
+        None
+    }
+}
@@ -0,0 +1,107 @@
+//! Fixed-size byte queue for handing data from an interrupt handler to the rest of
+//! the kernel.
+//!
+//! Lock-free for a single producer and a single consumer, so an interrupt handler
+//! can push without ever spinning on a lock held by the code it interrupted.
+
+use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+pub struct ByteRing<const N: usize> {
+    buf: UnsafeCell<[u8; N]>,
+    /// Total bytes ever pushed. Only the producer writes this.
+    head: AtomicUsize,
+    /// Total bytes ever popped. Only the consumer writes this.
+    tail: AtomicUsize,
+    /// Bytes dropped because the queue was full.
+    dropped: AtomicUsize,
+}
+
+// Safety: the producer only writes slots the consumer has finished with and the
+// consumer only reads slots the producer has published through `head`.
+unsafe impl<const N: usize> Sync for ByteRing<N> {}
+
+impl<const N: usize> ByteRing<N> {
+    pub const fn new() -> Self {
+        Self {
+            buf: UnsafeCell::new([0; N]),
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+            dropped: AtomicUsize::new(0),
+        }
+    }
+
+    /// Queue `byte`, returning `false` and dropping it if the queue is full.
+    ///
+    /// Must only be called from one context at a time.
+    pub fn push(&self, byte: u8) -> bool {
+        let head = self.head.load(Ordering::Relaxed);
+        let tail = self.tail.load(Ordering::Acquire);
+        if head.wrapping_sub(tail) >= N {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+            return false;
+        }
+        unsafe {
+            (*self.buf.get())[head % N] = byte;
+        }
+        self.head.store(head.wrapping_add(1), Ordering::Release);
+        true
+    }
+
+    /// Take the oldest queued byte.
+    ///
+    /// Must only be called from one context at a time.
+    pub fn pop(&self) -> Option<u8> {
+        let tail = self.tail.load(Ordering::Relaxed);
+        let head = self.head.load(Ordering::Acquire);
+        if head == tail {
+            return None;
+        }
+        let byte = unsafe { (*self.buf.get())[tail % N] };
+        self.tail.store(tail.wrapping_add(1), Ordering::Release);
+        Some(byte)
+    }
+
+    pub fn len(&self) -> usize {
+        let head = self.head.load(Ordering::Acquire);
+        head.wrapping_sub(self.tail.load(Ordering::Acquire))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Number of bytes lost to overflow since boot.
+    pub fn dropped(&self) -> usize {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+#[test_case]
+fn test_ring_fifo_order() {
+    let ring: ByteRing<4> = ByteRing::new();
+    assert!(ring.is_empty());
+    for b in 1..=3 {
+        assert!(ring.push(b));
+    }
+    assert_eq!(ring.len(), 3);
+    assert_eq!(ring.pop(), Some(1));
+    assert_eq!(ring.pop(), Some(2));
+    assert_eq!(ring.pop(), Some(3));
+    assert_eq!(ring.pop(), None);
+}
+
+#[test_case]
+fn test_ring_overflow_and_wraparound() {
+    let ring: ByteRing<4> = ByteRing::new();
+    for round in 0..10u8 {
+        for b in 0..4 {
+            assert!(ring.push(round * 4 + b));
+        }
+        assert!(!ring.push(0xff), "push into a full ring should fail");
+        for b in 0..4 {
+            assert_eq!(ring.pop(), Some(round * 4 + b));
+        }
+    }
+    assert_eq!(ring.dropped(), 10);
+}
@@ -0,0 +1,415 @@
+//! Serial ports COM1 to COM4.
+//!
+//! Ports are set up on first use and written to synchronously, so output works from
+//! the moment we boot. Once `init` has run, every port that's present gets receive and
+//! transmit ring buffers serviced from IRQ4 (COM1 and COM3) and IRQ3 (COM2 and COM4).
+
+pub mod uart;
+
+use crate::ring::ByteRing;
+use core::fmt;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use spin::Mutex;
+use uart::{Config, ConfigError, Uart};
+use x86_64::instructions::interrupts;
+
+#[macro_export]
+macro_rules! serial_print {
+    ($($arg:tt)*) => ($crate::serial::_print(format_args!($($arg)*)));
+}
+
+#[macro_export]
+macro_rules! serial_println {
+    () => ($crate::serial_print!("\n"));
+    ($($arg:tt)*) => ($crate::serial_print!("{}\n", format_args!($($arg)*)));
+    ($fmt:expr, $($arg:tt)*) => ($crate::serial_print!(
+        concat!($fmt, "\n"), $($arg)*));
+}
+
+#[doc(hidden)]
+pub fn _print(args: fmt::Arguments) {
+    use core::fmt::Write;
+    interrupts::without_interrupts(|| {
+        print_port().lock().write_fmt(args).unwrap();
+    });
+}
+
+/// Index into `ComPort::ALL` of the port `serial_print!` writes to.
+static PRINT_PORT: AtomicUsize = AtomicUsize::new(ComPort::Com1 as usize);
+
+/// The port `serial_print!` writes to, COM1 unless changed with `set_print_port`.
+pub fn print_port() -> ComPort {
+    ComPort::ALL[PRINT_PORT.load(Ordering::Relaxed)]
+}
+
+/// Send `serial_print!` output to `port`, to keep COM1 free for something else.
+pub fn set_print_port(port: ComPort) {
+    PRINT_PORT.store(port as usize, Ordering::Relaxed);
+}
+
+const RX_BUFFER: usize = 256;
+const TX_BUFFER: usize = 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ComPort {
+    Com1,
+    Com2,
+    Com3,
+    Com4,
+}
+
+impl ComPort {
+    pub const ALL: [ComPort; 4] = [ComPort::Com1, ComPort::Com2, ComPort::Com3, ComPort::Com4];
+
+    pub const fn base(self) -> u16 {
+        match self {
+            ComPort::Com1 => 0x3f8,
+            ComPort::Com2 => 0x2f8,
+            ComPort::Com3 => 0x3e8,
+            ComPort::Com4 => 0x2e8,
+        }
+    }
+
+    /// The IRQ line the port interrupts on. Each one is shared by two ports.
+    pub fn irq(self) -> u8 {
+        match self {
+            ComPort::Com1 | ComPort::Com3 => 4,
+            ComPort::Com2 | ComPort::Com4 => 3,
+        }
+    }
+
+    /// Name of the port on the command line.
+    pub fn name(self) -> &'static str {
+        match self {
+            ComPort::Com1 => "com1",
+            ComPort::Com2 => "com2",
+            ComPort::Com3 => "com3",
+            ComPort::Com4 => "com4",
+        }
+    }
+
+    fn lock(self) -> spin::MutexGuard<'static, Port> {
+        PORTS[self as usize].lock()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Unprobed,
+    Absent,
+    /// Present, with interrupts off.
+    Polled,
+    Interrupts,
+}
+
+struct Port {
+    uart: Uart,
+    state: State,
+    config: Config,
+    rx: ByteRing<RX_BUFFER>,
+    tx: ByteRing<TX_BUFFER>,
+    /// Whether the transmit-empty interrupt is on because `tx` has data.
+    transmitting: bool,
+}
+
+/// Only locked with interrupts disabled, apart from in the interrupt handler.
+static PORTS: [Mutex<Port>; 4] = [
+    Mutex::new(Port::new(ComPort::Com1)),
+    Mutex::new(Port::new(ComPort::Com2)),
+    Mutex::new(Port::new(ComPort::Com3)),
+    Mutex::new(Port::new(ComPort::Com4)),
+];
+
+impl Port {
+    const fn new(port: ComPort) -> Self {
+        Self {
+            uart: unsafe { Uart::new(port.base()) },
+            state: State::Unprobed,
+            config: Config::DEFAULT,
+            rx: ByteRing::new(),
+            tx: ByteRing::new(),
+            transmitting: false,
+        }
+    }
+
+    /// Probe and set up the UART the first time the port is used.
+    fn probe(&mut self) {
+        if self.state == State::Unprobed {
+            self.state = match self.uart.init(&self.config) {
+                Ok(true) => State::Polled,
+                _ => State::Absent,
+            };
+        }
+    }
+
+    fn enable_interrupts(&mut self) -> bool {
+        self.probe();
+        if self.state != State::Polled {
+            return self.state == State::Interrupts;
+        }
+        self.uart
+            .set_interrupts(uart::INTERRUPT_RX | uart::INTERRUPT_LINE_STATUS);
+        self.state = State::Interrupts;
+        true
+    }
+
+    fn write_byte(&mut self, byte: u8) {
+        self.probe();
+        match self.state {
+            State::Interrupts => {
+                while !self.tx.push(byte) {
+                    // Out of room, so send the oldest byte ourselves
+                    if let Some(oldest) = self.tx.pop() {
+                        self.uart.write_blocking(oldest);
+                    }
+                }
+                if !self.transmitting {
+                    self.fill_fifo();
+                    self.transmitting = true;
+                    let enabled = self.uart.interrupts();
+                    self.uart.set_interrupts(enabled | uart::INTERRUPT_TX_EMPTY);
+                }
+            }
+            State::Polled => self.uart.write_blocking(byte),
+            State::Unprobed | State::Absent => {}
+        }
+    }
+
+    /// Move queued output into the transmit FIFO if it's empty.
+    fn fill_fifo(&mut self) {
+        if !self.uart.tx_empty() {
+            return;
+        }
+        for _ in 0..uart::TX_FIFO_SIZE {
+            match self.tx.pop() {
+                Some(byte) => self.uart.write_unchecked(byte),
+                None => break,
+            }
+        }
+    }
+
+    fn read_byte(&mut self) -> Option<u8> {
+        self.probe();
+        match self.state {
+            State::Interrupts => self.rx.pop(),
+            State::Polled => self.uart.try_read(),
+            State::Unprobed | State::Absent => None,
+        }
+    }
+
+    fn has_input(&mut self) -> bool {
+        self.probe();
+        match self.state {
+            State::Interrupts => !self.rx.is_empty(),
+            State::Polled => self.uart.data_ready(),
+            State::Unprobed | State::Absent => false,
+        }
+    }
+
+    /// Send everything queued and wait for it to leave the UART.
+    fn flush(&mut self) {
+        if self.state == State::Interrupts {
+            while let Some(byte) = self.tx.pop() {
+                self.uart.write_blocking(byte);
+            }
+        }
+        if matches!(self.state, State::Interrupts | State::Polled) {
+            while !self.uart.tx_empty() {
+                core::hint::spin_loop();
+            }
+        }
+    }
+
+    /// Handle whatever the UART is interrupting for.
+    fn service(&mut self) {
+        if self.state != State::Interrupts {
+            return;
+        }
+        // Bounded in case the UART keeps asserting something we don't handle
+        for _ in 0..8 {
+            if !self.uart.interrupt_pending() {
+                break;
+            }
+            while let Some(byte) = self.uart.try_read() {
+                // Drop input nobody's reading rather than block the interrupt
+                self.rx.push(byte);
+            }
+            self.uart.clear_status();
+            if self.transmitting {
+                self.fill_fifo();
+                if self.tx.is_empty() {
+                    self.transmitting = false;
+                    let enabled = self.uart.interrupts();
+                    self.uart
+                        .set_interrupts(enabled & !uart::INTERRUPT_TX_EMPTY);
+                }
+            }
+        }
+    }
+}
+
+impl fmt::Write for Port {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.bytes() {
+            self.write_byte(byte);
+        }
+        Ok(())
+    }
+}
+
+/// Make the ports usable from the panic handler, whatever it interrupted.
+///
+/// Queued output is sent and ports go back to polled mode, since with interrupts off
+/// the transmit interrupt would never come.
+///
+/// # Safety
+///
+/// Interrupts must be off, so that whatever holds a port lock can never run again.
+/// The locks are then released by force.
+pub unsafe fn prepare_for_panic() {
+    for mutex in &PORTS {
+        if mutex.is_locked() {
+            mutex.force_unlock();
+        }
+        let mut port = mutex.lock();
+        port.flush();
+        if port.state == State::Interrupts {
+            port.uart.set_interrupts(0);
+            port.state = State::Polled;
+            port.transmitting = false;
+        }
+    }
+}
+
+/// Write `bytes` straight to COM1's UART, ignoring the port's lock and state. For when
+/// nothing else can be trusted.
+pub fn write_emergency(bytes: &[u8]) {
+    let mut uart = unsafe { Uart::new(ComPort::Com1.base()) };
+    for &byte in bytes {
+        uart.write_blocking(byte);
+    }
+}
+
+/// Switch every port that's present over to interrupt-driven input and output.
+pub fn init() {
+    let mut irqs = [false; 16];
+    interrupts::without_interrupts(|| {
+        for &port in &ComPort::ALL {
+            if port.lock().enable_interrupts() {
+                irqs[port.irq() as usize] = true;
+            }
+        }
+    });
+    for (irq, _) in irqs.iter().enumerate().filter(|(_, &used)| used) {
+        crate::interrupts::unmask_irq(irq as u8);
+    }
+}
+
+/// Service the ports on `irq`. Called from the interrupt handler.
+pub(crate) fn handle_interrupt(irq: u8) {
+    for &port in ComPort::ALL.iter().filter(|port| port.irq() == irq) {
+        port.lock().service();
+    }
+}
+
+/// Apply `serial.com1=115200,8n1` style command line options. Needs the heap.
+pub fn apply_boot_options() {
+    for &port in &ComPort::ALL {
+        let key = alloc::format!("serial.{}", port.name());
+        let value = match crate::cmdline::get(&key) {
+            Some(value) => value,
+            None => continue,
+        };
+        if let Err(e) = Config::parse(value).and_then(|config| configure(port, config)) {
+            log::warn!("bad {} setting {:?}: {:?}", port.name(), value, e);
+        }
+    }
+}
+
+/// Change the baud rate and framing of `port`.
+pub fn configure(port: ComPort, config: Config) -> Result<(), ConfigError> {
+    interrupts::without_interrupts(|| {
+        let mut port = port.lock();
+        port.probe();
+        // Let output queued at the old settings go out at them
+        port.flush();
+        port.uart.configure(&config)?;
+        port.config = config;
+        Ok(())
+    })
+}
+
+pub fn config(port: ComPort) -> Config {
+    interrupts::without_interrupts(|| port.lock().config)
+}
+
+/// Whether there's a UART at `port`.
+pub fn is_present(port: ComPort) -> bool {
+    interrupts::without_interrupts(|| {
+        let mut port = port.lock();
+        port.probe();
+        port.state != State::Absent
+    })
+}
+
+/// Queue `bytes` for sending on `port`.
+pub fn write(port: ComPort, bytes: &[u8]) {
+    interrupts::without_interrupts(|| {
+        let mut port = port.lock();
+        for &byte in bytes {
+            port.write_byte(byte);
+        }
+    })
+}
+
+/// Take the next received byte, if there is one.
+pub fn read_byte(port: ComPort) -> Option<u8> {
+    interrupts::without_interrupts(|| port.lock().read_byte())
+}
+
+/// Read as many received bytes as fit in `buf` without waiting, returning how many.
+pub fn read(port: ComPort, buf: &mut [u8]) -> usize {
+    interrupts::without_interrupts(|| {
+        let mut port = port.lock();
+        let mut len = 0;
+        while len < buf.len() {
+            match port.read_byte() {
+                Some(byte) => buf[len] = byte,
+                None => break,
+            }
+            len += 1;
+        }
+        len
+    })
+}
+
+/// Wait for the next received byte. Must be called with interrupts enabled.
+pub fn read_byte_blocking(port: ComPort) -> u8 {
+    loop {
+        if let Some(byte) = read_byte(port) {
+            return byte;
+        }
+        // Check again with interrupts off so a byte arriving now still wakes us. Ports
+        // without interrupts get checked again on the next timer tick.
+        interrupts::disable();
+        if port.lock().has_input() {
+            interrupts::enable();
+        } else {
+            interrupts::enable_and_hlt();
+        }
+    }
+}
+
+/// Whether `port` has received bytes waiting to be read.
+pub fn has_input(port: ComPort) -> bool {
+    interrupts::without_interrupts(|| port.lock().has_input())
+}
+
+/// Wait until everything written to any port has been sent.
+pub fn flush_all() {
+    interrupts::without_interrupts(|| {
+        for &port in &ComPort::ALL {
+            port.lock().flush();
+        }
+    })
+}
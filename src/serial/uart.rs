@@ -0,0 +1,270 @@
+//! Register-level driver for the 16550 UART.
+
+use x86_64::instructions::port::Port;
+
+/// Clock rate of the UART divided by 16, i.e. the fastest baud rate.
+const MAX_BAUD: u32 = 115_200;
+
+// Register offsets from the base port
+const DATA: u16 = 0;
+const INTERRUPT_ENABLE: u16 = 1;
+/// Interrupt identification on read, FIFO control on write.
+const INTERRUPT_ID: u16 = 2;
+const FIFO_CONTROL: u16 = 2;
+const LINE_CONTROL: u16 = 3;
+const MODEM_CONTROL: u16 = 4;
+const LINE_STATUS: u16 = 5;
+const MODEM_STATUS: u16 = 6;
+
+/// Line control bit that maps the divisor latch over the data and interrupt enable
+/// registers.
+const DIVISOR_LATCH: u8 = 1 << 7;
+
+pub const INTERRUPT_RX: u8 = 1 << 0;
+pub const INTERRUPT_TX_EMPTY: u8 = 1 << 1;
+pub const INTERRUPT_LINE_STATUS: u8 = 1 << 2;
+
+const LINE_STATUS_DATA_READY: u8 = 1 << 0;
+const LINE_STATUS_TX_EMPTY: u8 = 1 << 5;
+
+/// DTR, RTS and OUT2, which gates the interrupt line on PCs.
+const MODEM_CONTROL_NORMAL: u8 = 0x0b;
+const MODEM_CONTROL_LOOPBACK: u8 = 0x1e;
+
+/// Enable and clear the FIFOs, interrupting once 14 bytes have arrived.
+const FIFO_ENABLE: u8 = 0xc7;
+/// Bytes the transmit FIFO holds.
+pub const TX_FIFO_SIZE: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Parity {
+    None,
+    Odd,
+    Even,
+    Mark,
+    Space,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Config {
+    pub baud: u32,
+    /// 5 to 8.
+    pub data_bits: u8,
+    pub parity: Parity,
+    /// 1 or 2.
+    pub stop_bits: u8,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigError {
+    /// The baud rate isn't 115200 divided by a whole number.
+    Baud(u32),
+    DataBits(u8),
+    StopBits(u8),
+    /// A configuration string didn't look like `115200,8n1`.
+    Syntax,
+}
+
+impl Config {
+    /// 38400 baud, 8N1.
+    pub const DEFAULT: Config = Config {
+        baud: 38400,
+        data_bits: 8,
+        parity: Parity::None,
+        stop_bits: 1,
+    };
+
+    /// Parse a configuration like `115200` or `9600,7e2`.
+    pub fn parse(s: &str) -> Result<Self, ConfigError> {
+        let mut parts = s.splitn(2, ',');
+        let baud = parts.next().unwrap_or("");
+        let mut config = Config {
+            baud: baud.parse().map_err(|_| ConfigError::Syntax)?,
+            ..Config::default()
+        };
+        if let Some(frame) = parts.next() {
+            let frame = frame.as_bytes();
+            if frame.len() != 3 {
+                return Err(ConfigError::Syntax);
+            }
+            config.data_bits = frame[0].wrapping_sub(b'0');
+            config.parity = match frame[1].to_ascii_lowercase() {
+                b'n' => Parity::None,
+                b'o' => Parity::Odd,
+                b'e' => Parity::Even,
+                b'm' => Parity::Mark,
+                b's' => Parity::Space,
+                _ => return Err(ConfigError::Syntax),
+            };
+            config.stop_bits = frame[2].wrapping_sub(b'0');
+        }
+        config.divisor()?;
+        config.line_control()?;
+        Ok(config)
+    }
+
+    fn divisor(&self) -> Result<u16, ConfigError> {
+        if self.baud == 0 || MAX_BAUD % self.baud != 0 {
+            return Err(ConfigError::Baud(self.baud));
+        }
+        Ok((MAX_BAUD / self.baud) as u16)
+    }
+
+    fn line_control(&self) -> Result<u8, ConfigError> {
+        if !(5..=8).contains(&self.data_bits) {
+            return Err(ConfigError::DataBits(self.data_bits));
+        }
+        let stop = match self.stop_bits {
+            1 => 0,
+            2 => 1 << 2,
+            n => return Err(ConfigError::StopBits(n)),
+        };
+        let parity = match self.parity {
+            Parity::None => 0b000,
+            Parity::Odd => 0b001,
+            Parity::Even => 0b011,
+            Parity::Mark => 0b101,
+            Parity::Space => 0b111,
+        } << 3;
+        Ok((self.data_bits - 5) | stop | parity)
+    }
+}
+
+pub struct Uart {
+    base: u16,
+}
+
+impl Uart {
+    /// # Safety
+    ///
+    /// `base` must be the base I/O port of a 16550 compatible UART, or nothing at all.
+    pub const unsafe fn new(base: u16) -> Self {
+        Self { base }
+    }
+
+    unsafe fn read(&self, register: u16) -> u8 {
+        Port::new(self.base + register).read()
+    }
+
+    unsafe fn write(&self, register: u16, value: u8) {
+        Port::new(self.base + register).write(value)
+    }
+
+    /// Reset the UART to `config` with interrupts off, returning whether it passed a
+    /// loopback test. A UART that fails is left alone.
+    pub fn init(&mut self, config: &Config) -> Result<bool, ConfigError> {
+        let divisor = config.divisor()?;
+        let line_control = config.line_control()?;
+        unsafe {
+            self.write(INTERRUPT_ENABLE, 0);
+            self.write(MODEM_CONTROL, MODEM_CONTROL_LOOPBACK);
+            self.write(DATA, 0xae);
+            if self.read(DATA) != 0xae {
+                return Ok(false);
+            }
+            self.program(divisor, line_control);
+            self.write(FIFO_CONTROL, FIFO_ENABLE);
+            self.write(MODEM_CONTROL, MODEM_CONTROL_NORMAL);
+        }
+        Ok(true)
+    }
+
+    /// Change the line settings, keeping the FIFOs and interrupts as they are.
+    pub fn configure(&mut self, config: &Config) -> Result<(), ConfigError> {
+        let divisor = config.divisor()?;
+        let line_control = config.line_control()?;
+        unsafe { self.program(divisor, line_control) };
+        Ok(())
+    }
+
+    unsafe fn program(&mut self, divisor: u16, line_control: u8) {
+        let enabled = self.read(INTERRUPT_ENABLE);
+        self.write(LINE_CONTROL, DIVISOR_LATCH);
+        self.write(DATA, divisor as u8);
+        self.write(INTERRUPT_ENABLE, (divisor >> 8) as u8);
+        self.write(LINE_CONTROL, line_control);
+        self.write(INTERRUPT_ENABLE, enabled);
+    }
+
+    pub fn interrupts(&self) -> u8 {
+        unsafe { self.read(INTERRUPT_ENABLE) }
+    }
+
+    /// Set which of the `INTERRUPT_*` sources raise an interrupt.
+    pub fn set_interrupts(&mut self, mask: u8) {
+        unsafe { self.write(INTERRUPT_ENABLE, mask) }
+    }
+
+    /// Whether the UART has an interrupt waiting to be handled.
+    pub fn interrupt_pending(&self) -> bool {
+        unsafe { self.read(INTERRUPT_ID) & 1 == 0 }
+    }
+
+    /// Clear line and modem status conditions, which otherwise keep the interrupt raised.
+    pub fn clear_status(&mut self) {
+        unsafe {
+            self.read(LINE_STATUS);
+            self.read(MODEM_STATUS);
+        }
+    }
+
+    pub fn data_ready(&self) -> bool {
+        unsafe { self.read(LINE_STATUS) & LINE_STATUS_DATA_READY != 0 }
+    }
+
+    /// Whether the transmit FIFO is empty.
+    pub fn tx_empty(&self) -> bool {
+        unsafe { self.read(LINE_STATUS) & LINE_STATUS_TX_EMPTY != 0 }
+    }
+
+    pub fn try_read(&mut self) -> Option<u8> {
+        if self.data_ready() {
+            Some(unsafe { self.read(DATA) })
+        } else {
+            None
+        }
+    }
+
+    /// Write a byte without checking there's room for it.
+    pub fn write_unchecked(&mut self, byte: u8) {
+        unsafe { self.write(DATA, byte) }
+    }
+
+    /// Wait for the transmit FIFO to empty, then write `byte`.
+    pub fn write_blocking(&mut self, byte: u8) {
+        while !self.tx_empty() {
+            core::hint::spin_loop();
+        }
+        self.write_unchecked(byte);
+    }
+}
+
+#[test_case]
+fn test_parse_config() {
+    assert_eq!(
+        Config::parse("115200"),
+        Ok(Config {
+            baud: 115200,
+            ..Config::default()
+        })
+    );
+    assert_eq!(
+        Config::parse("9600,7E2"),
+        Ok(Config {
+            baud: 9600,
+            data_bits: 7,
+            parity: Parity::Even,
+            stop_bits: 2,
+        })
+    );
+    assert_eq!(Config::parse("1000"), Err(ConfigError::Baud(1000)));
+    assert_eq!(Config::parse("9600,9n1"), Err(ConfigError::DataBits(9)));
+    assert_eq!(Config::parse("9600,8x1"), Err(ConfigError::Syntax));
+    assert_eq!(Config::parse("fast"), Err(ConfigError::Syntax));
+}
@@ -0,0 +1,296 @@
+//! Commands that come with the shell.
+
+use super::{Command, Error};
+use crate::{allocator, interrupts, memory, ps2};
+use alloc::format;
+use core::fmt::Write;
+use x86_64::PhysAddr;
+
+/// Most bytes `peek` dumps at once.
+const MAX_PEEK: u64 = 4096;
+
+static BUILTINS: [Command; 10] = [
+    Command {
+        name: "help",
+        usage: "",
+        help: "List commands",
+        run: help,
+    },
+    Command {
+        name: "mem",
+        usage: "",
+        help: "Show heap, slab and physical frame usage",
+        run: mem,
+    },
+    Command {
+        name: "uptime",
+        usage: "",
+        help: "Show time since boot",
+        run: uptime,
+    },
+    Command {
+        name: "echo",
+        usage: "[word]...",
+        help: "Print the arguments",
+        run: echo,
+    },
+    Command {
+        name: "clear",
+        usage: "",
+        help: "Clear the screen",
+        run: clear,
+    },
+    Command {
+        name: "panic",
+        usage: "[message]...",
+        help: "Panic the kernel",
+        run: panic,
+    },
+    Command {
+        name: "reboot",
+        usage: "",
+        help: "Restart the machine",
+        run: reboot,
+    },
+    Command {
+        name: "int3",
+        usage: "",
+        help: "Raise a breakpoint exception",
+        run: int3,
+    },
+    Command {
+        name: "peek",
+        usage: "<address> [length]",
+        help: "Dump physical memory",
+        run: peek,
+    },
+    Command {
+        name: "poke",
+        usage: "<address> <byte>...",
+        help: "Write bytes to physical memory",
+        run: poke,
+    },
+];
+
+pub(super) fn register_all() {
+    for command in &BUILTINS {
+        super::register(command);
+    }
+}
+
+/// Parse a decimal number, or hexadecimal with a `0x` prefix.
+fn parse_number(s: &str) -> Result<u64, Error> {
+    let parsed = match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => s.parse(),
+    };
+    parsed.map_err(|_| Error::Failed(format!("invalid number {:?}", s)))
+}
+
+/// Check that the `len` bytes at physical `address` are there to be read or written.
+fn physical_range(address: u64, len: u64) -> Result<PhysAddr, Error> {
+    let bad = || Error::Failed(format!("{:#x} isn't mapped physical memory", address));
+    let start = PhysAddr::try_new(address).map_err(|_| bad())?;
+    let end = address
+        .checked_add(len)
+        .and_then(|end| PhysAddr::try_new(end).ok())
+        .ok_or_else(bad)?;
+    if !memory::is_backed(start..end) {
+        return Err(bad());
+    }
+    Ok(start)
+}
+
+fn help(_args: &[&str], out: &mut dyn Write) -> Result<(), Error> {
+    for command in super::commands() {
+        let _ = writeln!(
+            out,
+            "{:<8} {:<20} {}",
+            command.name, command.usage, command.help
+        );
+    }
+    Ok(())
+}
+
+fn mem(_args: &[&str], out: &mut dyn Write) -> Result<(), Error> {
+    let heap = allocator::stats();
+    let frames = memory::frame_stats();
+    let _ = writeln!(
+        out,
+        "heap:   {} of {} bytes in use, peak {}",
+        heap.in_use,
+        allocator::HEAP_SIZE,
+        heap.peak
+    );
+    let _ = writeln!(
+        out,
+        "        {} allocations, {} frees, {} failed",
+        heap.allocations, heap.deallocations, heap.failures
+    );
+    let _ = writeln!(
+        out,
+        "frames: {} of {} free ({} KiB)",
+        frames.free,
+        frames.total,
+        frames.free * 4
+    );
+    allocator::slab::for_each(|cache| {
+        let stats = cache.stats();
+        let _ = writeln!(
+            out,
+            "slab:   {} {} of {} objects of {} bytes, {} partial {} full {} empty slabs",
+            cache.name(),
+            stats.in_use,
+            (stats.partial + stats.full + stats.empty) * stats.per_slab,
+            stats.object_size,
+            stats.partial,
+            stats.full,
+            stats.empty
+        );
+    });
+    Ok(())
+}
+
+fn uptime(_args: &[&str], out: &mut dyn Write) -> Result<(), Error> {
+    let ticks = interrupts::ticks();
+    let tenths = ticks * 10 * 65536 / interrupts::PIT_FREQUENCY;
+    let _ = writeln!(out, "up {}.{}s ({} ticks)", tenths / 10, tenths % 10, ticks);
+    Ok(())
+}
+
+fn echo(args: &[&str], out: &mut dyn Write) -> Result<(), Error> {
+    for (i, arg) in args.iter().enumerate() {
+        if i > 0 {
+            let _ = out.write_char(' ');
+        }
+        let _ = out.write_str(arg);
+    }
+    let _ = out.write_char('\n');
+    Ok(())
+}
+
+fn clear(_args: &[&str], out: &mut dyn Write) -> Result<(), Error> {
+    let _ = out.write_str("\x1b[2J\x1b[H");
+    Ok(())
+}
+
+fn panic(args: &[&str], _out: &mut dyn Write) -> Result<(), Error> {
+    if args.is_empty() {
+        panic!("requested from the shell");
+    }
+    panic!("{}", args.join(" "));
+}
+
+fn reboot(_args: &[&str], _out: &mut dyn Write) -> Result<(), Error> {
+    ps2::reset_cpu();
+    // The controller didn't take us down, so triple fault instead
+    use alloc::boxed::Box;
+    use x86_64::structures::idt::InterruptDescriptorTable;
+    let empty: &'static InterruptDescriptorTable =
+        Box::leak(Box::new(InterruptDescriptorTable::new()));
+    empty.load();
+    x86_64::instructions::interrupts::int3();
+    unreachable!();
+}
+
+fn int3(_args: &[&str], _out: &mut dyn Write) -> Result<(), Error> {
+    x86_64::instructions::interrupts::int3();
+    Ok(())
+}
+
+fn peek(args: &[&str], out: &mut dyn Write) -> Result<(), Error> {
+    let (address, length) = match args {
+        [address] => (parse_number(address)?, 64),
+        [address, length] => (parse_number(address)?, parse_number(length)?),
+        _ => return Err(Error::Usage),
+    };
+    if length > MAX_PEEK {
+        return Err(Error::Failed(format!(
+            "at most {} bytes at a time",
+            MAX_PEEK
+        )));
+    }
+    let start = memory::phys_to_virt(physical_range(address, length)?).as_ptr::<u8>();
+    let mut line = [0; 16];
+    for offset in (0..length).step_by(16) {
+        let len = (length - offset).min(16) as usize;
+        for (i, b) in line[..len].iter_mut().enumerate() {
+            *b = unsafe { start.add(offset as usize + i).read_volatile() };
+        }
+        let _ = write!(out, "{:016x}:", address + offset);
+        for b in &line[..len] {
+            let _ = write!(out, " {:02x}", b);
+        }
+        let _ = write!(out, "{:width$}  ", "", width = (16 - len) * 3);
+        for &b in &line[..len] {
+            let c = if b.is_ascii_graphic() || b == b' ' {
+                b as char
+            } else {
+                '.'
+            };
+            let _ = out.write_char(c);
+        }
+        let _ = out.write_char('\n');
+    }
+    Ok(())
+}
+
+fn poke(args: &[&str], _out: &mut dyn Write) -> Result<(), Error> {
+    let (address, bytes) = match args.split_first() {
+        Some((address, bytes)) if !bytes.is_empty() => (parse_number(address)?, bytes),
+        _ => return Err(Error::Usage),
+    };
+    let start = physical_range(address, bytes.len() as u64)?;
+    let start = memory::phys_to_virt(start).as_mut_ptr::<u8>();
+    for (i, byte) in bytes.iter().enumerate() {
+        let value = parse_number(byte)?;
+        if value > 0xff {
+            return Err(Error::Failed(format!("{} doesn't fit in a byte", byte)));
+        }
+        unsafe { start.add(i).write_volatile(value as u8) };
+    }
+    Ok(())
+}
+
+#[test_case]
+fn test_parse_number() {
+    assert_eq!(parse_number("42"), Ok(42));
+    assert_eq!(parse_number("0xb8000"), Ok(0xb8000));
+    assert!(parse_number("0xzz").is_err());
+    assert!(parse_number("").is_err());
+}
+
+#[test_case]
+fn test_peek_poke() {
+    use alloc::string::String;
+    // Round trip through the VGA buffer, which is always there
+    let address = 0xb8000 + 24 * 160;
+    let saved = unsafe {
+        memory::phys_to_virt(PhysAddr::new(address))
+            .as_ptr::<u16>()
+            .read_volatile()
+    };
+    let mut out = String::new();
+    poke(&["0xb8f00", "0x41", "0x1f"], &mut out).unwrap();
+    peek(&["0xb8f00", "2"], &mut out).unwrap();
+    assert!(out.starts_with("00000000000b8f00: 41 1f"));
+    assert!(out.trim_end().ends_with("A."));
+    unsafe {
+        memory::phys_to_virt(PhysAddr::new(address))
+            .as_mut_ptr::<u16>()
+            .write_volatile(saved)
+    };
+}
+
+#[test_case]
+fn test_peek_poke_bad_address() {
+    use alloc::string::String;
+    let mut out = String::new();
+    // Past 52 bits of physical address, past the end of RAM, and wrapping around
+    assert!(peek(&["0xffffffffffff0000"], &mut out).is_err());
+    assert!(peek(&["0x7f0000000000"], &mut out).is_err());
+    assert!(peek(&["0xfffffffffffffff0", "32"], &mut out).is_err());
+    assert!(poke(&["0xffffffffffff0000", "0"], &mut out).is_err());
+    assert!(poke(&["0x7f0000000000", "0"], &mut out).is_err());
+    assert!(out.is_empty());
+}
@@ -0,0 +1,256 @@
+//! Interactive kernel shell.
+//!
+//! Lines are read from the keyboard and from COM1, each with its own line editor, and
+//! run as commands with their output sent back where the line came from. Commands live
+//! in a registry any module can add to with [`register`].
+
+mod builtins;
+pub mod terminal;
+
+use crate::keyboard::{self, KeyPress, LineEditor};
+use crate::serial::{self, ComPort};
+use crate::vga;
+use alloc::{string::String, vec::Vec};
+use core::fmt::{self, Write};
+use spin::Mutex;
+use terminal::TerminalDecoder;
+
+pub const PROMPT: &str = "> ";
+
+pub struct Command {
+    pub name: &'static str,
+    /// Arguments the command takes, shown by `help` and on usage errors.
+    pub usage: &'static str,
+    /// One line description for `help`.
+    pub help: &'static str,
+    /// Run the command with `args`, not including the command name.
+    pub run: fn(args: &[&str], out: &mut dyn Write) -> Result<(), Error>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Error {
+    /// The arguments were wrong, so the shell prints the command's usage.
+    Usage,
+    Failed(String),
+}
+
+/// Registered commands, sorted by name.
+static COMMANDS: Mutex<Vec<&'static Command>> = Mutex::new(Vec::new());
+
+/// Add `command` to the shell, replacing any existing command with the same name.
+///
+/// Requires the heap.
+pub fn register(command: &'static Command) {
+    let mut commands = COMMANDS.lock();
+    match commands.binary_search_by_key(&command.name, |c| c.name) {
+        Ok(i) => commands[i] = command,
+        Err(i) => commands.insert(i, command),
+    }
+}
+
+pub fn find(name: &str) -> Option<&'static Command> {
+    let commands = COMMANDS.lock();
+    commands
+        .binary_search_by_key(&name, |c| c.name)
+        .ok()
+        .map(|i| commands[i])
+}
+
+/// All registered commands, sorted by name.
+pub fn commands() -> Vec<&'static Command> {
+    COMMANDS.lock().clone()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TokenizeError {
+    UnterminatedQuote,
+    TrailingBackslash,
+}
+
+/// Split `line` into words at whitespace.
+///
+/// Single or double quotes keep whitespace inside a word and a backslash takes the
+/// next character literally, outside single quotes.
+pub fn tokenize(line: &str) -> Result<Vec<String>, TokenizeError> {
+    let mut words = Vec::new();
+    let mut word: Option<String> = None;
+    let mut quote = None;
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        match (c, quote) {
+            ('\\', q) if q != Some('\'') => {
+                let escaped = chars.next().ok_or(TokenizeError::TrailingBackslash)?;
+                word.get_or_insert_with(String::new).push(escaped);
+            }
+            (c, Some(q)) if c == q => quote = None,
+            (c, Some(_)) => word.get_or_insert_with(String::new).push(c),
+            ('\'', None) | ('"', None) => {
+                quote = Some(c);
+                // Make sure "" still counts as a word
+                word.get_or_insert_with(String::new);
+            }
+            (c, None) if c.is_whitespace() => words.extend(word.take()),
+            (c, None) => word.get_or_insert_with(String::new).push(c),
+        }
+    }
+    if quote.is_some() {
+        return Err(TokenizeError::UnterminatedQuote);
+    }
+    words.extend(word);
+    Ok(words)
+}
+
+/// Run the command in `line`, writing its output to `out`.
+pub fn execute(line: &str, out: &mut dyn Write) {
+    let words = match tokenize(line) {
+        Ok(words) => words,
+        Err(e) => {
+            let _ = writeln!(out, "syntax error: {:?}", e);
+            return;
+        }
+    };
+    let (name, args) = match words.split_first() {
+        Some(split) => split,
+        None => return,
+    };
+    let command = match find(name) {
+        Some(command) => command,
+        None => {
+            let _ = writeln!(out, "{}: command not found", name);
+            return;
+        }
+    };
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    let _ = match (command.run)(&args, out) {
+        Ok(()) => Ok(()),
+        Err(Error::Usage) => writeln!(out, "usage: {} {}", command.name, command.usage),
+        Err(Error::Failed(message)) => writeln!(out, "{}: {}", command.name, message),
+    };
+}
+
+/// Where a session reads from and writes to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Terminal {
+    Vga,
+    Serial,
+}
+
+impl Write for Terminal {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        match self {
+            Terminal::Vga => vga::print_interactive(format_args!("{}", s)),
+            Terminal::Serial => {
+                for byte in s.bytes() {
+                    let bytes: &[u8] = match byte {
+                        // Terminals want a carriage return before each line feed
+                        b'\n' => b"\r\n",
+                        // and only move the cursor back on backspace
+                        0x08 => b"\x08 \x08",
+                        _ => core::slice::from_ref(&byte),
+                    };
+                    serial::write(ComPort::Com1, bytes);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+struct Session {
+    terminal: Terminal,
+    editor: LineEditor,
+}
+
+impl Session {
+    fn new(terminal: Terminal) -> Self {
+        Self {
+            terminal,
+            editor: LineEditor::new(),
+        }
+    }
+
+    fn feed(&mut self, press: KeyPress) {
+        if let Some(line) = self.editor.feed(press, &mut self.terminal) {
+            execute(&line, &mut self.terminal);
+            let _ = self.terminal.write_str(PROMPT);
+        }
+    }
+}
+
+/// Run the shell on the VGA console and COM1. Requires the heap.
+pub fn run() -> ! {
+    use x86_64::instructions::interrupts;
+
+    builtins::register_all();
+    let mut vga = Session::new(Terminal::Vga);
+    let mut serial = Session::new(Terminal::Serial);
+    let mut decoder = TerminalDecoder::new();
+    let _ = vga.terminal.write_str(PROMPT);
+    let _ = serial.terminal.write_str(PROMPT);
+
+    loop {
+        let mut idle = true;
+        while let Some(press) = keyboard::next_key() {
+            idle = false;
+            vga.feed(press);
+        }
+        while let Some(byte) = serial::read_byte(ComPort::Com1) {
+            idle = false;
+            if let Some(press) = decoder.push(byte) {
+                serial.feed(press);
+            }
+        }
+        if idle {
+            // Check again with interrupts off so input arriving now still wakes us
+            interrupts::disable();
+            if keyboard::pending() || serial::has_input(ComPort::Com1) {
+                interrupts::enable();
+            } else {
+                interrupts::enable_and_hlt();
+            }
+        }
+    }
+}
+
+#[test_case]
+fn test_tokenize() {
+    let words = tokenize(r#"  echo "hello  world" it\'s 'a \ b' "" x"#).unwrap();
+    assert_eq!(words, ["echo", "hello  world", "it's", "a \\ b", "", "x"]);
+    assert_eq!(tokenize(""), Ok(Vec::new()));
+    assert_eq!(tokenize("\"abc"), Err(TokenizeError::UnterminatedQuote));
+    assert_eq!(tokenize("abc\\"), Err(TokenizeError::TrailingBackslash));
+}
+
+#[test_case]
+fn test_execute() {
+    fn fail(args: &[&str], out: &mut dyn Write) -> Result<(), Error> {
+        match args {
+            [] => Err(Error::Usage),
+            [arg] => {
+                let _ = write!(out, "{}", arg);
+                Ok(())
+            }
+            _ => Err(Error::Failed("too many".into())),
+        }
+    }
+    static TEST: Command = Command {
+        name: "shell-test",
+        usage: "<arg>",
+        help: "",
+        run: fail,
+    };
+    register(&TEST);
+
+    let mut out = String::new();
+    execute("shell-test 'one arg'", &mut out);
+    assert_eq!(out, "one arg");
+    out.clear();
+    execute("shell-test", &mut out);
+    assert_eq!(out, "usage: shell-test <arg>\n");
+    out.clear();
+    execute("shell-test a b", &mut out);
+    assert_eq!(out, "shell-test: too many\n");
+    out.clear();
+    execute("no-such-command", &mut out);
+    assert_eq!(out, "no-such-command: command not found\n");
+}
@@ -0,0 +1,73 @@
+//! Turns bytes from a serial terminal into key presses for the line editor.
+
+use crate::keyboard::{KeyPress, Modifiers};
+use crate::vga::ansi::{Action, Parser};
+use pc_keyboard::{DecodedKey, KeyCode};
+
+pub struct TerminalDecoder {
+    parser: Parser,
+    /// Whether the last byte was a carriage return, so a following line feed is
+    /// part of the same Enter.
+    after_cr: bool,
+}
+
+impl TerminalDecoder {
+    pub const fn new() -> Self {
+        Self {
+            parser: Parser::new(),
+            after_cr: false,
+        }
+    }
+
+    /// Feed the next byte from the terminal, returning a key press once one is complete.
+    pub fn push(&mut self, byte: u8) -> Option<KeyPress> {
+        let after_cr = core::mem::replace(&mut self.after_cr, byte == b'\r');
+        let key = match self.parser.advance(byte)? {
+            Action::Print(byte) if byte.is_ascii() => DecodedKey::Unicode(byte as char),
+            Action::Print(_) => return None,
+            Action::Control(b'\n') if after_cr => return None,
+            Action::Control(b'\r') | Action::Control(b'\n') => DecodedKey::Unicode('\n'),
+            // Most terminals send DEL for the backspace key
+            Action::Control(0x7f) => DecodedKey::Unicode('\u{8}'),
+            Action::Control(byte) => DecodedKey::Unicode(byte as char),
+            Action::Esc(_) => return None,
+            Action::Csi(csi) => DecodedKey::RawKey(match (csi.final_byte, csi.param(0, 0)) {
+                (b'A', _) => KeyCode::ArrowUp,
+                (b'B', _) => KeyCode::ArrowDown,
+                (b'C', _) => KeyCode::ArrowRight,
+                (b'D', _) => KeyCode::ArrowLeft,
+                (b'H', _) | (b'~', 1) | (b'~', 7) => KeyCode::Home,
+                (b'F', _) | (b'~', 4) | (b'~', 8) => KeyCode::End,
+                (b'~', 3) => KeyCode::Delete,
+                _ => return None,
+            }),
+        };
+        Some(KeyPress {
+            key,
+            modifiers: Modifiers::default(),
+        })
+    }
+}
+
+#[test_case]
+fn test_terminal_decoder() {
+    use alloc::vec::Vec;
+    let mut decoder = TerminalDecoder::new();
+    let keys: Vec<_> = b"a\x7f\r\n\x1b[D\x1b[3~\x03\n"
+        .iter()
+        .filter_map(|&b| decoder.push(b))
+        .map(|press| press.key)
+        .collect();
+    assert_eq!(
+        keys,
+        [
+            DecodedKey::Unicode('a'),
+            DecodedKey::Unicode('\u{8}'),
+            DecodedKey::Unicode('\n'),
+            DecodedKey::RawKey(KeyCode::ArrowLeft),
+            DecodedKey::RawKey(KeyCode::Delete),
+            DecodedKey::Unicode('\u{3}'),
+            DecodedKey::Unicode('\n'),
+        ]
+    );
+}
@@ -0,0 +1,125 @@
+//! The test runner's own arguments: whatever follows `--` on the kernel command line.
+//! They're read like `cargo test`'s:
+//!
+//! - `<filter>`: only run tests whose name contains it; given more than once, tests
+//!   matching any of them
+//! - `--skip <filter>`: don't run tests whose name contains it
+//! - `--exact`: filters have to match the whole name
+//! - `--list`: list the tests that would run, without running them
+//!
+//! Names are the ones in the results, like `firstos::vga::test_println_simple`.
+
+use crate::cmdline;
+
+/// Room for the arguments. They're kept for the whole run, outside of the heap, which
+/// not every test binary sets up.
+const ARGS_LEN: usize = 512;
+
+pub struct Args {
+    buf: [u8; ARGS_LEN],
+    len: usize,
+}
+
+impl Args {
+    /// The arguments after `--` in `cmdline`, or as many of them as fit.
+    pub fn new(cmdline: &str) -> Args {
+        let mut args = Args {
+            buf: [0; ARGS_LEN],
+            len: 0,
+        };
+        for word in cmdline::passthrough(cmdline) {
+            let end = args.len + word.len();
+            if end >= ARGS_LEN {
+                break;
+            }
+            args.buf[args.len..end].copy_from_slice(word.as_bytes());
+            args.buf[end] = b' ';
+            args.len = end + 1;
+        }
+        args
+    }
+
+    fn words(&self) -> impl Iterator<Item = &str> {
+        // Whole words copied out of a `str`
+        core::str::from_utf8(&self.buf[..self.len])
+            .unwrap_or("")
+            .split_whitespace()
+    }
+
+    pub fn list(&self) -> bool {
+        self.words().any(|word| word == "--list")
+    }
+
+    /// The first option the runner doesn't know, if any.
+    pub fn unknown(&self) -> Option<&str> {
+        let mut words = self.words();
+        while let Some(word) = words.next() {
+            match word {
+                "--skip" => {
+                    words.next();
+                }
+                "--exact" | "--list" => {}
+                option if option.starts_with("--") => return Some(option),
+                _ => {}
+            }
+        }
+        None
+    }
+
+    /// Whether the test called `name` should run.
+    pub fn selects(&self, name: &str) -> bool {
+        let exact = self.words().any(|word| word == "--exact");
+        let matches = |filter: &str| {
+            if exact {
+                name == filter
+            } else {
+                name.contains(filter)
+            }
+        };
+        let mut filtered = false;
+        let mut included = false;
+        let mut words = self.words();
+        while let Some(word) = words.next() {
+            match word {
+                "--skip" => {
+                    if words.next().map_or(false, matches) {
+                        return false;
+                    }
+                }
+                option if option.starts_with("--") => {}
+                filter => {
+                    filtered = true;
+                    included |= matches(filter);
+                }
+            }
+        }
+        included || !filtered
+    }
+}
+
+#[test_case]
+fn test_no_filters() {
+    let args = Args::new("test.format=tap");
+    assert!(args.selects("firstos::vga::test_println"));
+    assert!(!args.list());
+    assert_eq!(args.unknown(), None);
+}
+
+#[test_case]
+fn test_filters() {
+    let args = Args::new("log=warn -- vga:: block --skip test_println_many");
+    assert!(args.selects("firstos::vga::test_println"));
+    assert!(args.selects("firstos::block::cache::test_evict"));
+    assert!(!args.selects("firstos::vga::test_println_many"));
+    assert!(!args.selects("firstos::serial::test_write"));
+}
+
+#[test_case]
+fn test_exact_and_options() {
+    let args = Args::new("-- --exact a::b --list --skip --bogus --nope");
+    assert!(args.selects("a::b"));
+    assert!(!args.selects("a::bc"));
+    assert!(args.list());
+    // `--bogus` is what `--skip` skips
+    assert_eq!(args.unknown(), Some("--nope"));
+}
@@ -0,0 +1,492 @@
+//! The harness running `#[test_case]`s, in the kernel and the integration tests.
+//!
+//! Plain `#[test_case]` functions just have to return. For anything else, declare the
+//! test with [`kernel_test!`](crate::kernel_test):
+//!
+//! ```ignore
+//! kernel_test! {
+//!     #[should_panic]
+//!     #[timeout_ms = 100]
+//!     fn overflows() {
+//!         let _ = u8::MAX + one();
+//!     }
+//! }
+//! ```
+//!
+//! A panicking test doesn't end the run. The panic handler calls
+//! [`test_panic_handler`], which abandons the test's stack and carries on with the next
+//! one. Anything the test had allocated is leaked, and locks it held stay held, apart
+//! from the consoles and serial ports, which are released by force.
+//!
+//! Tests that run for longer than their timeout are failed by a watchdog on the timer
+//! interrupt, so it needs `crate::init` to have been called and can't stop a test that
+//! turns interrupts off.
+
+pub mod args;
+pub mod report;
+
+use crate::serial::{self, ComPort};
+use crate::{cmdline, hlt_loop, interrupts, qemu, serial_println, vga};
+use args::Args;
+use core::fmt;
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicU64, Ordering};
+use report::{Counts, Format, Outcome};
+use spin::Mutex;
+use x86_64::instructions::interrupts as cpu_interrupts;
+
+/// Timeout for tests that don't set their own.
+pub const DEFAULT_TIMEOUT_MS: u64 = 5000;
+
+/// Something the runner can run.
+pub trait Testable {
+    fn name(&self) -> &'static str;
+    fn run(&self);
+
+    fn should_panic(&self) -> bool {
+        false
+    }
+
+    fn ignore(&self) -> bool {
+        false
+    }
+
+    /// How long the test may take in milliseconds, or 0 for no limit.
+    fn timeout_ms(&self) -> u64 {
+        DEFAULT_TIMEOUT_MS
+    }
+}
+
+impl<T> Testable for T
+where
+    T: Fn(),
+{
+    fn name(&self) -> &'static str {
+        core::any::type_name::<T>()
+    }
+
+    fn run(&self) {
+        self();
+    }
+}
+
+/// A test with options, as declared by [`kernel_test!`](crate::kernel_test).
+pub struct TestCase {
+    pub name: &'static str,
+    pub func: fn(),
+    /// Passes only if it panics.
+    pub should_panic: bool,
+    /// Skipped, but still listed.
+    pub ignore: bool,
+    /// How long the test may take in milliseconds, or 0 for no limit.
+    pub timeout_ms: u64,
+}
+
+impl TestCase {
+    /// Options for a test that sets none. Fill in `name` and `func` over this.
+    pub const DEFAULT: TestCase = TestCase {
+        name: "",
+        func: no_test,
+        should_panic: false,
+        ignore: false,
+        timeout_ms: DEFAULT_TIMEOUT_MS,
+    };
+}
+
+fn no_test() {}
+
+impl Testable for TestCase {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn run(&self) {
+        (self.func)();
+    }
+
+    fn should_panic(&self) -> bool {
+        self.should_panic
+    }
+
+    fn ignore(&self) -> bool {
+        self.ignore
+    }
+
+    fn timeout_ms(&self) -> u64 {
+        self.timeout_ms
+    }
+}
+
+/// Declare a `#[test_case]` with options, given as attributes on the function:
+///
+/// - `#[should_panic]`: the test passes only if it panics
+/// - `#[ignore]`: skip the test
+/// - `#[timeout_ms = <ms>]`: fail the test if it runs for longer, 0 for no limit
+#[macro_export]
+macro_rules! kernel_test {
+    ($(#[$option:ident $(= $value:expr)?])* fn $name:ident() $body:block) => {
+        #[test_case]
+        #[allow(non_upper_case_globals)]
+        static $name: $crate::testing::TestCase = $crate::testing::TestCase {
+            name: concat!(module_path!(), "::", stringify!($name)),
+            func: {
+                fn $name() $body
+                $name
+            },
+            $($option: $crate::kernel_test!(@value $($value)?),)*
+            ..$crate::testing::TestCase::DEFAULT
+        };
+    };
+    (@value) => {
+        true
+    };
+    (@value $value:expr) => {
+        $value
+    };
+}
+
+/// A test run in progress.
+struct Run {
+    tests: &'static [&'static dyn Testable],
+    format: Format,
+    args: Args,
+    next: usize,
+    /// Index of the test running now.
+    current: Option<usize>,
+    /// Tick the current test started on.
+    started: u64,
+    counts: Counts,
+    /// Where the runner's stack was before running any tests, to go back to after a
+    /// panic.
+    stack: u64,
+    /// Whether interrupts were on, to put them back after a panic.
+    interrupts: bool,
+}
+
+// Tests only ever run on the one CPU.
+unsafe impl Send for Run {}
+
+impl Run {
+    /// Count and report how the current test went.
+    fn finish_test(&mut self, outcome: Outcome) {
+        let current = match self.current.take() {
+            Some(current) => current,
+            None => return,
+        };
+        match outcome {
+            Outcome::Passed => self.counts.passed += 1,
+            Outcome::Failed(_) => self.counts.failed += 1,
+            Outcome::Ignored => self.counts.ignored += 1,
+        }
+        // Numbered among the tests that run, for TAP
+        let number = self.counts.passed + self.counts.failed + self.counts.ignored;
+        let ticks = interrupts::ticks() - self.started;
+        let millis = ticks * 1000 * 65536 / interrupts::PIT_FREQUENCY;
+        let name = self.tests[current].name();
+        let _ = report::test_finished(&mut Report, self.format, number, name, outcome, millis);
+    }
+}
+
+/// Only locked with interrupts off, since the watchdog can panic out of the timer
+/// interrupt.
+static RUN: Mutex<Option<Run>> = Mutex::new(None);
+
+/// Tick at which the running test times out, or 0 if there's no limit.
+static DEADLINE: AtomicU64 = AtomicU64::new(0);
+/// Timeout of the running test, for the failure message.
+static WATCHDOG_MS: AtomicU64 = AtomicU64::new(0);
+/// Set to the timeout by the watchdog when it fails a test.
+static TIMED_OUT: AtomicU64 = AtomicU64::new(0);
+
+/// Most of the kernel command line we look at. It's read into a buffer on the stack,
+/// as not every test binary sets up the heap.
+const CMDLINE_LEN: usize = 1024;
+
+/// Run `tests`, then exit QEMU with whether they all passed.
+///
+/// Results go to COM1 in the format given by the `test.format` command line option:
+/// `human` (the default), `tap` or `junit`. With the machine readable formats anything
+/// else printed with `serial_print!` goes to COM2 instead, which the bootimage
+/// `test-args` in `Cargo.toml` write to `target/test-com2.log`.
+///
+/// Which tests run is up to the arguments after `--` on the command line, as described
+/// in [`args`].
+pub fn test_runner(tests: &[&dyn Testable]) {
+    let stack: u64;
+    unsafe {
+        asm!("mov {}, rsp", out(reg) stack, options(nomem, nostack, preserves_flags));
+    }
+    // Tests are statics, and the slice of them lives in `test_main`, whose frame is
+    // never left: the run ends by exiting QEMU.
+    let tests = unsafe {
+        core::mem::transmute::<&[&dyn Testable], &'static [&'static dyn Testable]>(tests)
+    };
+
+    let mut cmdline = [0; CMDLINE_LEN];
+    let len = qemu::fw_cfg_read(cmdline::FW_CFG_FILE, &mut cmdline).unwrap_or(0);
+    let cmdline = core::str::from_utf8(&cmdline[..len]).unwrap_or("");
+    let cmdline = cmdline.trim_end_matches('\0');
+    let mut format = Format::Human;
+    for (key, value) in cmdline::parse(cmdline) {
+        if key == "test.format" {
+            match Format::from_name(value) {
+                Some(f) => format = f,
+                None => serial_println!("unknown test format {:?}", value),
+            }
+        }
+    }
+    let args = Args::new(cmdline);
+    if let Some(option) = args.unknown() {
+        serial_println!("unknown test runner option {:?}", option);
+    }
+
+    let selected = || {
+        tests
+            .iter()
+            .map(|test| test.name())
+            .filter(|&name| args.selects(name))
+    };
+    if args.list() {
+        let _ = report::list(&mut Report, &mut selected());
+        qemu::exit(qemu::ExitCode::Success);
+        hlt_loop();
+    }
+    if format.is_machine_readable() {
+        serial::set_print_port(ComPort::Com2);
+    }
+
+    let count = selected().count();
+    let _ = report::start(&mut Report, format, count);
+    let run = Run {
+        tests,
+        format,
+        args,
+        next: 0,
+        current: None,
+        started: 0,
+        counts: Counts {
+            filtered: tests.len() - count,
+            ..Counts::default()
+        },
+        stack,
+        interrupts: cpu_interrupts::are_enabled(),
+    };
+    cpu_interrupts::without_interrupts(|| *RUN.lock() = Some(run));
+    run_remaining();
+}
+
+/// Run the tests that haven't been yet, then exit.
+extern "C" fn run_remaining() -> ! {
+    loop {
+        let next = cpu_interrupts::without_interrupts(|| {
+            let mut run = RUN.lock();
+            let run = run.as_mut().expect("no test run in progress");
+            while !run.args.selects(run.tests.get(run.next)?.name()) {
+                run.next += 1;
+            }
+            let test = run.tests[run.next];
+            run.current = Some(run.next);
+            run.next += 1;
+            run.started = interrupts::ticks();
+            Some((test, run.format))
+        });
+        let (test, format) = match next {
+            Some(next) => next,
+            None => break,
+        };
+
+        let _ = report::test_started(&mut Report, format, test.name());
+        if test.ignore() {
+            finish_test(Outcome::Ignored);
+            continue;
+        }
+        #[cfg(feature = "heap_debug")]
+        let mark = crate::allocator::checking().mark();
+        start_watchdog(test.timeout_ms());
+        test.run();
+        stop_watchdog();
+        if test.should_panic() {
+            finish_test(Outcome::Failed(&"test did not panic"));
+        } else {
+            finish_test(Outcome::Passed);
+        }
+        #[cfg(feature = "heap_debug")]
+        list_allocations(mark);
+    }
+
+    let failed = cpu_interrupts::without_interrupts(|| {
+        let run = RUN.lock();
+        let run = run.as_ref().expect("no test run in progress");
+        let _ = report::finish(&mut Report, run.format, &run.counts);
+        run.counts.failed
+    });
+    qemu::exit(if failed == 0 {
+        qemu::ExitCode::Success
+    } else {
+        qemu::ExitCode::Failure
+    });
+    hlt_loop();
+}
+
+/// List what the test allocated and didn't free, which is either leaked or kept for
+/// later, like a `lazy_static`.
+#[cfg(feature = "heap_debug")]
+fn list_allocations(mark: u64) {
+    crate::allocator::checking().for_each_live(mark, |allocation| {
+        serial_println!("    still allocated: {}", allocation);
+    });
+}
+
+fn finish_test(outcome: Outcome) {
+    cpu_interrupts::without_interrupts(|| {
+        let mut run = RUN.lock();
+        run.as_mut()
+            .expect("no test run in progress")
+            .finish_test(outcome);
+    });
+}
+
+/// Handle a panic: during a test run it fails the running test, or passes it if it
+/// should panic, and carries on with the next. Otherwise it reports the panic and
+/// exits QEMU.
+pub fn test_panic_handler(info: &PanicInfo) -> ! {
+    cpu_interrupts::disable();
+    stop_watchdog();
+    // The test might have been holding the consoles or COM1
+    unsafe {
+        vga::prepare_for_panic();
+        serial::prepare_for_panic();
+    }
+    let timed_out = TIMED_OUT.swap(0, Ordering::SeqCst);
+
+    // Nothing else can run now, so if the lock is held, it was by whatever panicked
+    if RUN.is_locked() {
+        unsafe { RUN.force_unlock() };
+    }
+    let mut guard = RUN.lock();
+    let run = match guard.as_mut() {
+        Some(run) if run.current.is_some() => run,
+        other => {
+            // Outside of a test, or a test binary with its own harness
+            let format = other.map_or(Format::Human, |run| run.format);
+            let _ = report::bail_out(&mut Report, format, info);
+            qemu::exit(qemu::ExitCode::Failure);
+            hlt_loop();
+        }
+    };
+    let should_panic = run.tests[run.current.unwrap()].should_panic();
+    if timed_out > 0 {
+        run.finish_test(Outcome::Failed(&TimedOut(timed_out)));
+    } else if should_panic {
+        run.finish_test(Outcome::Passed);
+    } else {
+        run.finish_test(Outcome::Failed(info));
+    }
+    let (stack, interrupts) = (run.stack, run.interrupts);
+    drop(guard);
+    unsafe { resume(stack, interrupts) }
+}
+
+struct TimedOut(u64);
+
+impl fmt::Display for TimedOut {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "timed out after {} ms", self.0)
+    }
+}
+
+/// Writes test results to COM1.
+struct Report;
+
+impl fmt::Write for Report {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        serial::write(ComPort::Com1, s.as_bytes());
+        Ok(())
+    }
+}
+
+/// Abandon the current stack, going back to where the runner was, and run the rest of
+/// the tests.
+unsafe fn resume(stack: u64, interrupts: bool) -> ! {
+    // Interrupts only go back on once we're off the old stack, which may be the
+    // double fault stack, so the timer can't land on it.
+    asm!(
+        "mov rsp, {stack}",
+        "and rsp, -16",
+        "test {interrupts}, {interrupts}",
+        "jz 2f",
+        "sti",
+        "2:",
+        "call {run}",
+        stack = in(reg) stack,
+        interrupts = in(reg_byte) interrupts as u8,
+        run = sym run_remaining,
+        options(noreturn)
+    );
+}
+
+fn start_watchdog(timeout_ms: u64) {
+    if timeout_ms == 0 {
+        return;
+    }
+    let ticks = (timeout_ms * interrupts::PIT_FREQUENCY + 65_535_999) / 65_536_000;
+    TIMED_OUT.store(0, Ordering::SeqCst);
+    WATCHDOG_MS.store(timeout_ms, Ordering::SeqCst);
+    // One more, as the current tick is already partly over
+    DEADLINE.store(interrupts::ticks() + ticks + 1, Ordering::SeqCst);
+}
+
+fn stop_watchdog() {
+    DEADLINE.store(0, Ordering::SeqCst);
+}
+
+/// Called on every timer interrupt: fail the running test if it's out of time.
+pub(crate) fn check_watchdog(ticks: u64) {
+    let deadline = DEADLINE.load(Ordering::SeqCst);
+    if deadline != 0 && ticks >= deadline {
+        stop_watchdog();
+        let timeout_ms = WATCHDOG_MS.load(Ordering::SeqCst);
+        TIMED_OUT.store(timeout_ms, Ordering::SeqCst);
+        panic!("test timed out after {} ms", timeout_ms);
+    }
+}
+
+#[test_case]
+fn test_plain_tests_have_defaults() {
+    fn example() {}
+    let test: &dyn Testable = &example;
+    assert!(test.name().ends_with("example"));
+    assert!(!test.should_panic());
+    assert!(!test.ignore());
+    assert_eq!(test.timeout_ms(), DEFAULT_TIMEOUT_MS);
+}
+
+crate::kernel_test! {
+    #[should_panic]
+    fn test_should_panic() {
+        panic!("as expected");
+    }
+}
+
+crate::kernel_test! {
+    #[ignore]
+    fn test_ignored() {
+        panic!("ignored tests don't run");
+    }
+}
+
+#[test_case]
+fn test_test_case_options() {
+    fn example() {}
+    let test = TestCase {
+        name: "example",
+        func: example,
+        should_panic: true,
+        ..TestCase::DEFAULT
+    };
+    let test: &dyn Testable = &test;
+    assert_eq!(test.name(), "example");
+    assert!(test.should_panic());
+    assert!(!test.ignore());
+    assert_eq!(test.timeout_ms(), DEFAULT_TIMEOUT_MS);
+}
@@ -0,0 +1,287 @@
+//! Test results, written out as they come in for people or for CI.
+//!
+//! - `human`: a line per test, as `cargo test` would print
+//! - `tap`: [TAP version 13](https://testanything.org/tap-version-13-specification.html)
+//! - `junit`: JUnit XML, as understood by most CI servers
+
+use core::fmt::{self, Display, Write};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Human,
+    Tap,
+    Junit,
+}
+
+impl Format {
+    pub fn name(self) -> &'static str {
+        match self {
+            Format::Human => "human",
+            Format::Tap => "tap",
+            Format::Junit => "junit",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Format> {
+        [Format::Human, Format::Tap, Format::Junit]
+            .iter()
+            .copied()
+            .find(|format| format.name() == name)
+    }
+
+    /// Whether the output is meant for a program, so nothing else may be mixed in.
+    pub fn is_machine_readable(self) -> bool {
+        self != Format::Human
+    }
+}
+
+pub enum Outcome<'a> {
+    Passed,
+    Failed(&'a dyn Display),
+    Ignored,
+}
+
+/// Before any tests run.
+pub fn start(out: &mut dyn Write, format: Format, count: usize) -> fmt::Result {
+    match format {
+        Format::Human => writeln!(out, "Running {} tests", count),
+        Format::Tap => writeln!(out, "TAP version 13\n1..{}", count),
+        Format::Junit => {
+            writeln!(out, r#"<?xml version="1.0" encoding="UTF-8"?>"#)?;
+            writeln!(out, "<testsuites>")?;
+            writeln!(out, r#"<testsuite name="firstos" tests="{}">"#, count)
+        }
+    }
+}
+
+/// Before test `name` runs, so whatever it prints comes after this.
+pub fn test_started(out: &mut dyn Write, format: Format, name: &str) -> fmt::Result {
+    match format {
+        Format::Human => write!(out, "{}...\t", name),
+        Format::Tap | Format::Junit => Ok(()),
+    }
+}
+
+/// After the `number`th test, counting from 1, finished.
+pub fn test_finished(
+    out: &mut dyn Write,
+    format: Format,
+    number: usize,
+    name: &str,
+    outcome: Outcome,
+    millis: u64,
+) -> fmt::Result {
+    match format {
+        Format::Human => match outcome {
+            Outcome::Passed => writeln!(out, "[ok]"),
+            Outcome::Failed(message) => writeln!(out, "[failed]\n\nError: {}\n", message),
+            Outcome::Ignored => writeln!(out, "[ignored]"),
+        },
+        Format::Tap => match outcome {
+            Outcome::Passed => {
+                writeln!(out, "ok {} - {}", number, name)?;
+                tap_diagnostics(out, None, millis)
+            }
+            Outcome::Failed(message) => {
+                writeln!(out, "not ok {} - {}", number, name)?;
+                tap_diagnostics(out, Some(message), millis)
+            }
+            Outcome::Ignored => writeln!(out, "ok {} - {} # SKIP ignored", number, name),
+        },
+        Format::Junit => {
+            let (class, test) = match name.rfind("::") {
+                Some(i) => (&name[..i], &name[i + 2..]),
+                None => ("", name),
+            };
+            write!(
+                out,
+                r#"  <testcase classname="{}" name="{}" time="{}.{:03}""#,
+                Escaped(class),
+                Escaped(test),
+                millis / 1000,
+                millis % 1000
+            )?;
+            match outcome {
+                Outcome::Passed => writeln!(out, "/>"),
+                Outcome::Failed(message) => {
+                    writeln!(out, ">")?;
+                    write!(out, r#"    <failure message=""#)?;
+                    write!(EscapeWriter(out), "{}", message)?;
+                    writeln!(out, "\"/>\n  </testcase>")
+                }
+                Outcome::Ignored => writeln!(out, ">\n    <skipped/>\n  </testcase>"),
+            }
+        }
+    }
+}
+
+/// How many tests ended which way.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Counts {
+    pub passed: usize,
+    pub failed: usize,
+    pub ignored: usize,
+    /// Not run, or reported at all, as the runner's arguments left them out.
+    pub filtered: usize,
+}
+
+/// After all tests have run.
+pub fn finish(out: &mut dyn Write, format: Format, counts: &Counts) -> fmt::Result {
+    let Counts {
+        passed,
+        failed,
+        ignored,
+        filtered,
+    } = *counts;
+    match format {
+        Format::Human => writeln!(
+            out,
+            "\ntest result: {}. {} passed; {} failed; {} ignored; {} filtered out",
+            if failed == 0 { "ok" } else { "FAILED" },
+            passed,
+            failed,
+            ignored,
+            filtered
+        ),
+        Format::Tap => writeln!(
+            out,
+            "# passed {}\n# failed {}\n# ignored {}\n# filtered out {}",
+            passed, failed, ignored, filtered
+        ),
+        Format::Junit => {
+            writeln!(out, "</testsuite>")?;
+            writeln!(
+                out,
+                "<!-- {} passed; {} failed; {} ignored; {} filtered out -->",
+                passed, failed, ignored, filtered
+            )?;
+            writeln!(out, "</testsuites>")
+        }
+    }
+}
+
+/// Instead of running anything, with `--list`.
+pub fn list(out: &mut dyn Write, names: &mut dyn Iterator<Item = &str>) -> fmt::Result {
+    let mut count = 0;
+    for name in names {
+        writeln!(out, "{}: test", name)?;
+        count += 1;
+    }
+    writeln!(out, "\n{} tests", count)
+}
+
+/// After a panic outside of any test, which ends the run.
+pub fn bail_out(out: &mut dyn Write, format: Format, message: &dyn Display) -> fmt::Result {
+    match format {
+        Format::Human => writeln!(out, "[failed]\n\nError: {}\n", message),
+        Format::Tap => {
+            write!(out, "Bail out! ")?;
+            write!(OneLineWriter(out), "{}", message)?;
+            writeln!(out)
+        }
+        Format::Junit => {
+            write!(out, "<system-err>")?;
+            write!(EscapeWriter(out), "{}", message)?;
+            writeln!(out, "</system-err>\n</testsuite>\n</testsuites>")
+        }
+    }
+}
+
+/// A TAP YAML block with the failure message, if any, and duration.
+fn tap_diagnostics(out: &mut dyn Write, message: Option<&dyn Display>, millis: u64) -> fmt::Result {
+    writeln!(out, "  ---")?;
+    if let Some(message) = message {
+        write!(out, "  message: |\n    ")?;
+        write!(IndentWriter(out), "{}", message)?;
+        writeln!(out)?;
+    }
+    writeln!(out, "  duration_ms: {}", millis)?;
+    writeln!(out, "  ...")
+}
+
+/// Indents continuation lines to fit in a YAML block scalar.
+struct IndentWriter<'a>(&'a mut dyn Write);
+
+impl Write for IndentWriter<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for (i, line) in s.split('\n').enumerate() {
+            if i > 0 {
+                self.0.write_str("\n    ")?;
+            }
+            self.0.write_str(line)?;
+        }
+        Ok(())
+    }
+}
+
+/// Joins lines, for TAP lines that can't be continued.
+struct OneLineWriter<'a>(&'a mut dyn Write);
+
+impl Write for OneLineWriter<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for (i, line) in s.split('\n').enumerate() {
+            if i > 0 {
+                self.0.write_char(' ')?;
+            }
+            self.0.write_str(line)?;
+        }
+        Ok(())
+    }
+}
+
+/// Escapes text for an XML attribute value.
+struct EscapeWriter<'a>(&'a mut dyn Write);
+
+impl Write for EscapeWriter<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for c in s.chars() {
+            match c {
+                '&' => self.0.write_str("&amp;")?,
+                '<' => self.0.write_str("&lt;")?,
+                '>' => self.0.write_str("&gt;")?,
+                '"' => self.0.write_str("&quot;")?,
+                '\n' => self.0.write_str("&#10;")?,
+                // Not allowed in XML 1.0 at all
+                c if c.is_control() && c != '\t' => self.0.write_char('?')?,
+                c => self.0.write_char(c)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+struct Escaped<'a>(&'a str);
+
+impl Display for Escaped<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        EscapeWriter(f).write_str(self.0)
+    }
+}
+
+#[test_case]
+fn test_tap() {
+    use alloc::string::String;
+    let mut out = String::new();
+    start(&mut out, Format::Tap, 2).unwrap();
+    let outcome = Outcome::Failed(&"assertion failed\nat here");
+    test_finished(&mut out, Format::Tap, 1, "a::b", outcome, 55).unwrap();
+    test_finished(&mut out, Format::Tap, 2, "a::c", Outcome::Ignored, 0).unwrap();
+    assert_eq!(
+        out,
+        "TAP version 13\n1..2\nnot ok 1 - a::b\n  ---\n  message: |\n    \
+         assertion failed\n    at here\n  duration_ms: 55\n  ...\nok 2 - a::c # SKIP ignored\n"
+    );
+}
+
+#[test_case]
+fn test_junit() {
+    use alloc::string::String;
+    let mut out = String::new();
+    let outcome = Outcome::Failed(&"1 < 2 & \"x\"");
+    test_finished(&mut out, Format::Junit, 1, "a::b::c", outcome, 1055).unwrap();
+    assert_eq!(
+        out,
+        "  <testcase classname=\"a::b\" name=\"c\" time=\"1.055\">\n    \
+         <failure message=\"1 &lt; 2 &amp; &quot;x&quot;\"/>\n  </testcase>\n"
+    );
+}
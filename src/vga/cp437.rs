@@ -0,0 +1,98 @@
+//! Unicode to code page 437 translation.
+//!
+//! The VGA text mode font is CP437, which has glyphs for a good chunk of Latin-1,
+//! box drawing, block elements, some Greek and maths symbols, and the dingbats
+//! that live in the C0 control range.
+
+/// Glyph shown for characters CP437 has no equivalent for.
+pub const REPLACEMENT: u8 = 0xfe;
+
+/// Unicode equivalents of CP437 0x01-0x1f.
+const LOW: [char; 31] = [
+    '☺', '☻', '♥', '♦', '♣', '♠', '•', '◘', '○', '◙', '♂', '♀', '♪', '♫', '☼', '►', //
+    '◄', '↕', '‼', '¶', '§', '▬', '↨', '↑', '↓', '→', '←', '∟', '↔', '▲', '▼',
+];
+
+/// Unicode equivalents of CP437 0x80-0xff.
+const HIGH: [char; 128] = [
+    'Ç', 'ü', 'é', 'â', 'ä', 'à', 'å', 'ç', 'ê', 'ë', 'è', 'ï', 'î', 'ì', 'Ä', 'Å', //
+    'É', 'æ', 'Æ', 'ô', 'ö', 'ò', 'û', 'ù', 'ÿ', 'Ö', 'Ü', '¢', '£', '¥', '₧', 'ƒ', //
+    'á', 'í', 'ó', 'ú', 'ñ', 'Ñ', 'ª', 'º', '¿', '⌐', '¬', '½', '¼', '¡', '«', '»', //
+    '░', '▒', '▓', '│', '┤', '╡', '╢', '╖', '╕', '╣', '║', '╗', '╝', '╜', '╛', '┐', //
+    '└', '┴', '┬', '├', '─', '┼', '╞', '╟', '╚', '╔', '╩', '╦', '╠', '═', '╬', '╧', //
+    '╨', '╤', '╥', '╙', '╘', '╒', '╓', '╫', '╪', '┘', '┌', '█', '▄', '▌', '▐', '▀', //
+    'α', 'ß', 'Γ', 'π', 'Σ', 'σ', 'µ', 'τ', 'Φ', 'Θ', 'Ω', 'δ', '∞', 'φ', 'ε', '∩', //
+    '≡', '±', '≥', '≤', '⌠', '⌡', '÷', '≈', '°', '∙', '·', '√', 'ⁿ', '²', '■', '\u{a0}',
+];
+
+/// Look-alikes that share a glyph with an entry in the tables above.
+const ALIASES: [(char, u8); 8] = [
+    ('β', 0xe1),
+    ('μ', 0xe6),
+    ('\u{2126}', 0xea),
+    ('∑', 0xe4),
+    ('∈', 0xee),
+    ('ϕ', 0xed),
+    ('∅', 0xed),
+    ('⋅', 0xfa),
+];
+
+/// Translate `c` to the CP437 glyph that displays it, if there is one.
+pub fn from_char(c: char) -> Option<u8> {
+    match c {
+        ' '..='~' => return Some(c as u8),
+        '⌂' => return Some(0x7f),
+        _ => {}
+    }
+    if let Some(i) = HIGH.iter().position(|&h| h == c) {
+        return Some(0x80 + i as u8);
+    }
+    if let Some(i) = LOW.iter().position(|&l| l == c) {
+        return Some(0x01 + i as u8);
+    }
+    ALIASES.iter().find(|&&(a, _)| a == c).map(|&(_, g)| g)
+}
+
+/// Like `from_char`, but substitutes `REPLACEMENT` for unmappable characters.
+pub fn glyph(c: char) -> u8 {
+    from_char(c).unwrap_or(REPLACEMENT)
+}
+
+#[test_case]
+fn test_ascii_is_identity() {
+    for b in 0x20..=0x7e {
+        assert_eq!(from_char(b as u8 as char), Some(b as u8));
+    }
+}
+
+#[test_case]
+fn test_common_glyphs() {
+    assert_eq!(from_char('é'), Some(0x82));
+    assert_eq!(from_char('│'), Some(0xb3));
+    assert_eq!(from_char('░'), Some(0xb0));
+    assert_eq!(from_char('°'), Some(0xf8));
+    assert_eq!(from_char('☺'), Some(0x01));
+    assert_eq!(from_char('▼'), Some(0x1f));
+    assert_eq!(from_char('⌂'), Some(0x7f));
+    assert_eq!(from_char('β'), from_char('ß'));
+}
+
+#[test_case]
+fn test_aliases_are_new() {
+    for &(alias, glyph) in ALIASES.iter() {
+        assert!(
+            !LOW.contains(&alias) && !HIGH.contains(&alias),
+            "{:?}",
+            alias
+        );
+        assert_eq!(from_char(alias), Some(glyph));
+    }
+    assert_eq!(from_char('\u{2126}'), from_char('Ω'));
+}
+
+#[test_case]
+fn test_unmappable() {
+    assert_eq!(from_char('€'), None);
+    assert_eq!(from_char('😀'), None);
+    assert_eq!(glyph('€'), REPLACEMENT);
+}
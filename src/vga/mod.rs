@@ -0,0 +1,1230 @@
+pub mod cp437;
+pub mod scrollback;
+
+pub use firstos_console::ansi;
+use firstos_console::utf8;
+
+use scrollback::Scrollback;
+
+use core::fmt;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use spin::Mutex;
+
+#[macro_export]
+macro_rules! print {
+    ($($arg:tt)*) => ($crate::vga::_print(format_args!($($arg)*)));
+}
+
+#[macro_export]
+macro_rules! println {
+    () => ($crate::print!("\n"));
+    ($($arg:tt)*) => ($crate::print!("{}\n", format_args!($($arg)*)));
+}
+
+#[doc(hidden)]
+pub fn _print(args: fmt::Arguments) {
+    crate::logging::buffer::record_console(args);
+    print_interactive(args);
+}
+
+/// Print to the main console like `print!`, but without keeping a copy in the kernel
+/// log buffer. For things like echoing input, which would only clutter the log.
+pub fn print_interactive(args: fmt::Arguments) {
+    use core::fmt::Write;
+    use x86_64::instructions::interrupts;
+    interrupts::without_interrupts(|| {
+        CONSOLES[MAIN_CONSOLE].lock().write_fmt(args).unwrap();
+    });
+}
+
+#[macro_export]
+macro_rules! log_print {
+    ($($arg:tt)*) => ($crate::vga::_log_print(format_args!($($arg)*)));
+}
+
+#[macro_export]
+macro_rules! log_println {
+    () => ($crate::log_print!("\n"));
+    ($($arg:tt)*) => ($crate::log_print!("{}\n", format_args!($($arg)*)));
+}
+
+/// Print to the kernel log console.
+#[doc(hidden)]
+pub fn _log_print(args: fmt::Arguments) {
+    use core::fmt::Write;
+    use x86_64::instructions::interrupts;
+    interrupts::without_interrupts(|| {
+        CONSOLES[LOG_CONSOLE].lock().write_fmt(args).unwrap();
+    });
+}
+
+#[macro_export]
+macro_rules! eprint {
+    ($($arg:tt)*) => ($crate::vga::_eprint(format_args!($($arg)*)));
+}
+
+#[macro_export]
+macro_rules! eprintln {
+    () => ($crate::eprint!("\n"));
+    ($($arg:tt)*) => ($crate::eprint!("{}\n", format_args!($($arg)*)));
+}
+
+pub fn _eprint(args: fmt::Arguments) {
+    use core::fmt::Write;
+    use x86_64::instructions::interrupts;
+    crate::logging::buffer::record_console(args);
+    interrupts::without_interrupts(|| {
+        let mut wr = CONSOLES[MAIN_CONSOLE].lock();
+        let orig_attrs = wr.attrs;
+        wr.set_color(Color::White, Color::Red);
+        wr.write_fmt(args).unwrap();
+        wr.attrs = orig_attrs;
+    });
+}
+
+/// Make the consoles usable from the panic handler, whatever it interrupted.
+///
+/// # Safety
+///
+/// Interrupts must be off, so that whatever holds a console lock can never run again.
+/// The locks are then released by force, and any half-written escape sequence or
+/// character is dropped so it doesn't swallow the panic message.
+pub unsafe fn prepare_for_panic() {
+    for console in &CONSOLES {
+        if console.is_locked() {
+            console.force_unlock();
+        }
+        let mut wr = console.lock();
+        wr.parser = ansi::Parser::new();
+        wr.utf8 = utf8::Decoder::new();
+    }
+}
+
+/// Write `s` straight into the bottom row of the screen, white on red, without going
+/// through a console. For when nothing else can be trusted.
+pub fn write_emergency(s: &str) {
+    let color = ColorCode::new(Color::White, Color::Red);
+    let row = unsafe { VGA_BUFFER.add((BUFFER_HEIGHT - 1) * BUFFER_WIDTH) };
+    let mut chars = s.chars().filter(|&c| c != '\n');
+    for col in 0..BUFFER_WIDTH {
+        let ascii = match chars.next() {
+            Some(c) if c.is_ascii() && !c.is_ascii_control() => c as u8,
+            Some(_) => b'?',
+            None => b' ',
+        };
+        let cell = Char { ascii, color };
+        unsafe { row.add(col).write_volatile(cell.into()) };
+    }
+}
+
+/// Number of virtual consoles, selected with Alt+F1 onwards.
+pub const CONSOLE_COUNT: usize = 6;
+/// Console that `print!` writes to.
+pub const MAIN_CONSOLE: usize = 0;
+/// Console dedicated to kernel log output, so it doesn't interleave with interactive
+/// output.
+pub const LOG_CONSOLE: usize = CONSOLE_COUNT - 1;
+
+/// The virtual consoles. Only the active one is mirrored to the VGA buffer.
+pub static CONSOLES: [Mutex<Writer>; CONSOLE_COUNT] = [
+    Mutex::new(Writer::new(0)),
+    Mutex::new(Writer::new(1)),
+    Mutex::new(Writer::new(2)),
+    Mutex::new(Writer::new(3)),
+    Mutex::new(Writer::new(4)),
+    Mutex::new(Writer::new(5)),
+];
+
+/// Index of the console shown on screen.
+static ACTIVE: AtomicUsize = AtomicUsize::new(MAIN_CONSOLE);
+
+/// Turn on the hardware cursor and draw the active console.
+pub fn init() {
+    use x86_64::instructions::interrupts;
+    interrupts::without_interrupts(|| {
+        let mut wr = CONSOLES[active_console()].lock();
+        wr.enable_cursor();
+        wr.redraw();
+    });
+}
+
+pub fn active_console() -> usize {
+    ACTIVE.load(Ordering::Relaxed)
+}
+
+/// Show console `console` on screen.
+pub fn switch_console(console: usize) {
+    use x86_64::instructions::interrupts;
+    if console >= CONSOLE_COUNT {
+        return;
+    }
+    interrupts::without_interrupts(|| {
+        {
+            // Make sure the old console isn't halfway through drawing to the screen
+            let _old = CONSOLES[active_console()].lock();
+            ACTIVE.store(console, Ordering::Relaxed);
+        }
+        CONSOLES[console].lock().redraw();
+    });
+}
+
+/// Start keeping `depth` lines of history on `console`. Requires the heap.
+pub fn enable_scrollback(console: usize, depth: usize) {
+    use x86_64::instructions::interrupts;
+    interrupts::without_interrupts(|| {
+        CONSOLES[console].lock().enable_scrollback(depth);
+    });
+}
+
+/// Show the previous page of history.
+pub fn page_up() {
+    use x86_64::instructions::interrupts;
+    interrupts::without_interrupts(|| {
+        CONSOLES[active_console()]
+            .lock()
+            .scroll_view((BUFFER_HEIGHT - 1) as isize);
+    });
+}
+
+/// Show the next page of history, returning to live output at the end.
+pub fn page_down() {
+    use x86_64::instructions::interrupts;
+    interrupts::without_interrupts(|| {
+        CONSOLES[active_console()]
+            .lock()
+            .scroll_view(-((BUFFER_HEIGHT - 1) as isize));
+    });
+}
+
+/// Screen offset of the mouse cursor, or `NO_MOUSE_CURSOR` when it's hidden.
+static MOUSE_CURSOR: AtomicUsize = AtomicUsize::new(NO_MOUSE_CURSOR);
+const NO_MOUSE_CURSOR: usize = usize::MAX;
+
+/// Draw the mouse cursor over the cell at `(row, col)`, or hide it with `None`.
+///
+/// Meant to be called from the mouse interrupt handler, so rather than wait for the
+/// screen it gives up and returns `false` while something else is drawing.
+pub fn try_set_mouse_cursor(position: Option<(usize, usize)>) -> bool {
+    let wr = match CONSOLES[active_console()].try_lock() {
+        Some(wr) => wr,
+        None => return false,
+    };
+    if !wr.is_active() {
+        // Lost a race with switch_console
+        return false;
+    }
+    let new = position.map_or(NO_MOUSE_CURSOR, |(row, col)| {
+        row.min(BUFFER_HEIGHT - 1) * BUFFER_WIDTH + col.min(BUFFER_WIDTH - 1)
+    });
+    let old = MOUSE_CURSOR.swap(new, Ordering::Relaxed);
+    for &offset in &[old, new] {
+        if offset != NO_MOUSE_CURSOR {
+            unsafe { put_screen(offset, wr.displayed(offset)) };
+        }
+    }
+    true
+}
+
+/// Write `cell` to the screen, with colours swapped if the mouse cursor is over it.
+unsafe fn put_screen(offset: usize, cell: u16) {
+    let cell = if offset == MOUSE_CURSOR.load(Ordering::Relaxed) {
+        let attr = (cell >> 8) as u8;
+        // Swap foreground and background, leaving the blink bit off
+        let attr = ((attr & 0x07) << 4) | ((attr >> 4) & 0x0f);
+        ((attr as u16) << 8) | (cell & 0xff)
+    } else {
+        cell
+    };
+    VGA_BUFFER.add(offset).write_volatile(cell);
+}
+
+#[allow(dead_code)]
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+#[repr(u8)]
+pub enum Color {
+    Black = 0,
+    Blue = 1,
+    Green = 2,
+    Cyan = 3,
+    Red = 4,
+    Magenta = 5,
+    Brown = 6,
+    LightGray = 7,
+    DarkGray = 8,
+    LightBlue = 9,
+    LightGreen = 10,
+    LightCyan = 11,
+    LightRed = 12,
+    Pink = 13,
+    Yellow = 14,
+    White = 15,
+}
+
+#[repr(transparent)]
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+struct ColorCode(u8);
+
+impl ColorCode {
+    const fn new(fg: Color, bg: Color) -> Self {
+        Self((bg as u8) << 4 | (fg as u8))
+    }
+}
+
+impl Into<u8> for ColorCode {
+    fn into(self) -> u8 {
+        self.0
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+#[repr(C)]
+struct Char {
+    ascii: u8,
+    color: ColorCode,
+}
+
+impl Into<u16> for Char {
+    fn into(self) -> u16 {
+        let color: u8 = self.color.into();
+        ((color as u16) << 8) | (self.ascii as u16)
+    }
+}
+
+impl From<u16> for Char {
+    fn from(u: u16) -> Self {
+        Char {
+            ascii: (u & 0x00FF) as u8,
+            color: ColorCode((u >> 8) as u8),
+        }
+    }
+}
+
+const VGA_BUFFER: *mut u16 = 0xb8000 as *mut _;
+
+const BUFFER_HEIGHT: usize = 25;
+const BUFFER_WIDTH: usize = 80;
+const TAB_WIDTH: usize = 8;
+
+/// CRT controller index and data registers.
+const CRTC_INDEX: u16 = 0x3d4;
+const CRTC_DATA: u16 = 0x3d5;
+const CRTC_CURSOR_START: u8 = 0x0a;
+const CRTC_CURSOR_END: u8 = 0x0b;
+const CRTC_CURSOR_HIGH: u8 = 0x0e;
+const CRTC_CURSOR_LOW: u8 = 0x0f;
+
+/// Colors and rendition set through SGR escape sequences.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Attributes {
+    fg: Color,
+    bg: Color,
+    bold: bool,
+    reverse: bool,
+}
+
+impl Attributes {
+    const DEFAULT: Attributes = Attributes {
+        fg: Color::Yellow,
+        bg: Color::Black,
+        bold: false,
+        reverse: false,
+    };
+
+    const fn color_code(&self) -> ColorCode {
+        let fg = if self.bold { self.fg.bright() } else { self.fg };
+        if self.reverse {
+            ColorCode::new(self.bg, fg)
+        } else {
+            ColorCode::new(fg, self.bg)
+        }
+    }
+}
+
+impl Color {
+    /// Map an ANSI color number (0-7) onto the VGA palette.
+    fn from_ansi(n: u16, bright: bool) -> Color {
+        let color = match n {
+            0 => Color::Black,
+            1 => Color::Red,
+            2 => Color::Green,
+            3 => Color::Brown,
+            4 => Color::Blue,
+            5 => Color::Magenta,
+            6 => Color::Cyan,
+            _ => Color::LightGray,
+        };
+        if bright {
+            color.bright()
+        } else {
+            color
+        }
+    }
+
+    /// The VGA color nearest a 24-bit one: a channel at half or more counts as on,
+    /// and one nearly full on makes the color bright.
+    fn from_rgb(r: u16, g: u16, b: u16) -> Color {
+        let n = (r >= 0x80) as u16 | ((g >= 0x80) as u16) << 1 | ((b >= 0x80) as u16) << 2;
+        Color::from_ansi(n, r.max(g).max(b) >= 0xc0)
+    }
+
+    /// The high intensity variant of this color.
+    const fn bright(self) -> Color {
+        match self {
+            Color::Black => Color::DarkGray,
+            Color::Blue => Color::LightBlue,
+            Color::Green => Color::LightGreen,
+            Color::Cyan => Color::LightCyan,
+            Color::Red => Color::LightRed,
+            Color::Magenta => Color::Pink,
+            Color::Brown => Color::Yellow,
+            Color::LightGray => Color::White,
+            bright => bright,
+        }
+    }
+}
+
+/// A blank cell in the default colors.
+const BLANK: Char = Char {
+    ascii: b' ',
+    color: Attributes::DEFAULT.color_code(),
+};
+
+/// A text console.
+///
+/// Each console draws into its own off-screen buffer, which is mirrored to the VGA
+/// buffer while the console is active.
+///
+/// Output is run through an ANSI escape sequence parser, so colors (SGR), cursor
+/// movement, erasing, saving and restoring the cursor and scroll regions behave as
+/// they would on a serial terminal.
+pub struct Writer {
+    /// Our index in `CONSOLES`.
+    index: usize,
+    cells: [Char; BUFFER_WIDTH * BUFFER_HEIGHT],
+    row: usize,
+    column: usize,
+    attrs: Attributes,
+    parser: ansi::Parser,
+    utf8: utf8::Decoder,
+    /// Position saved by `ESC 7` / `CSI s`.
+    saved: (usize, usize),
+    /// First and last rows, inclusive, that scroll when output reaches the bottom.
+    scroll_top: usize,
+    scroll_bottom: usize,
+    scrollback: Option<Scrollback>,
+}
+
+impl Writer {
+    pub const fn new(index: usize) -> Self {
+        Writer {
+            index,
+            cells: [BLANK; BUFFER_WIDTH * BUFFER_HEIGHT],
+            row: 0,
+            column: 0,
+            attrs: Attributes::DEFAULT,
+            parser: ansi::Parser::new(),
+            utf8: utf8::Decoder::new(),
+            saved: (0, 0),
+            scroll_top: 0,
+            scroll_bottom: BUFFER_HEIGHT - 1,
+            scrollback: None,
+        }
+    }
+
+    /// Current (row, column) of the cursor.
+    pub fn position(&self) -> (usize, usize) {
+        (self.row, self.column)
+    }
+
+    /// Move the cursor to (`row`, `col`), clamped to the screen.
+    pub fn set_position(&mut self, row: usize, col: usize) {
+        self.snap_to_live();
+        self.row = row.min(BUFFER_HEIGHT - 1);
+        self.column = col.min(BUFFER_WIDTH - 1);
+        self.update_cursor();
+    }
+
+    pub fn set_color(&mut self, fg: Color, bg: Color) {
+        self.attrs = Attributes {
+            fg,
+            bg,
+            bold: false,
+            reverse: false,
+        };
+    }
+
+    pub fn write_byte(&mut self, byte: u8) {
+        self.snap_to_live();
+        self.feed(byte);
+        self.update_cursor();
+    }
+
+    pub fn write_string(&mut self, s: &str) {
+        self.snap_to_live();
+        for byte in s.bytes() {
+            self.feed(byte);
+        }
+        self.update_cursor();
+    }
+
+    /// Write `s` starting at (`row`, `col`), then move the cursor to the end of it.
+    pub fn write_at(&mut self, row: usize, col: usize, s: &str) {
+        self.set_position(row, col);
+        self.write_string(s);
+    }
+
+    /// Blank the whole screen and move the cursor to the top left corner.
+    pub fn clear_screen(&mut self) {
+        self.snap_to_live();
+        for row in 0..BUFFER_HEIGHT {
+            self.clear_row(row);
+        }
+        self.row = 0;
+        self.column = 0;
+        self.update_cursor();
+    }
+
+    /// Keep the last `depth` lines that scroll off the top of the screen.
+    pub fn enable_scrollback(&mut self, depth: usize) {
+        self.snap_to_live();
+        self.scrollback = Some(Scrollback::new(depth));
+    }
+
+    /// Whether the screen is showing history rather than live output.
+    pub fn viewing_history(&self) -> bool {
+        self.scrollback.as_ref().map_or(false, |sb| sb.offset > 0)
+    }
+
+    /// Move the view `lines` further back into history, or forwards if negative.
+    ///
+    /// Any output snaps the view back to the live screen.
+    pub fn scroll_view(&mut self, lines: isize) {
+        let sb = match self.scrollback.as_mut() {
+            Some(sb) => sb,
+            None => return,
+        };
+        let offset = (sb.offset as isize + lines).max(0) as usize;
+        let offset = offset.min(sb.len());
+        if offset == 0 {
+            self.snap_to_live();
+            return;
+        }
+        sb.offset = offset;
+        self.render_history();
+    }
+
+    /// Draw the screen as it looked `offset` lines ago.
+    fn render_history(&mut self) {
+        if self.scrollback.is_none() || !self.is_active() {
+            return;
+        }
+        for offset in 0..BUFFER_WIDTH * BUFFER_HEIGHT {
+            unsafe { put_screen(offset, self.displayed(offset)) };
+        }
+        // Park the hardware cursor off screen while it doesn't mean anything
+        let hidden = (BUFFER_WIDTH * BUFFER_HEIGHT) as u16;
+        unsafe {
+            crtc_write(CRTC_CURSOR_LOW, (hidden & 0xff) as u8);
+            crtc_write(CRTC_CURSOR_HIGH, (hidden >> 8) as u8);
+        }
+    }
+
+    /// Put the live screen back if we're showing history.
+    fn snap_to_live(&mut self) {
+        let sb = match self.scrollback.as_mut() {
+            Some(sb) if sb.offset > 0 => sb,
+            _ => return,
+        };
+        sb.offset = 0;
+        self.redraw();
+    }
+
+    fn is_active(&self) -> bool {
+        active_console() == self.index
+    }
+
+    /// Copy this console to the screen if it's the active one.
+    fn redraw(&mut self) {
+        if !self.is_active() {
+            return;
+        }
+        if self.viewing_history() {
+            self.render_history();
+            return;
+        }
+        for (i, &ch) in self.cells.iter().enumerate() {
+            unsafe { put_screen(i, ch.into()) };
+        }
+        self.update_cursor();
+    }
+
+    /// Run `byte` through the escape sequence parser and act on the result.
+    ///
+    /// Bytes outside ASCII are decoded as UTF-8 and drawn with the matching CP437 glyph.
+    fn feed(&mut self, byte: u8) {
+        if self.utf8.pending() && byte & 0xc0 != 0x80 {
+            // The previous sequence was cut short
+            self.utf8.reset();
+            self.put_glyph(cp437::REPLACEMENT);
+        }
+        if byte >= 0x80 {
+            match self.utf8.push(byte) {
+                Some(Ok(c)) => self.put_glyph(cp437::glyph(c)),
+                Some(Err(_)) => self.put_glyph(cp437::REPLACEMENT),
+                None => {}
+            }
+            return;
+        }
+
+        match self.parser.advance(byte) {
+            Some(ansi::Action::Print(byte)) => self.put_glyph(byte),
+            Some(ansi::Action::Control(byte)) => match byte {
+                b'\n' | b'\r' | b'\t' | 0x08 => self.put_byte(byte),
+                // Bell
+                0x07 => {}
+                _ => self.put_glyph(cp437::REPLACEMENT),
+            },
+            Some(ansi::Action::Esc(b'7')) => self.saved = (self.row, self.column),
+            Some(ansi::Action::Esc(b'8')) => self.restore_position(),
+            Some(ansi::Action::Esc(b'c')) => {
+                let scrollback = self.scrollback.take();
+                *self = Writer::new(self.index);
+                self.scrollback = scrollback;
+                self.clear_screen();
+            }
+            Some(ansi::Action::Esc(_)) => {}
+            Some(ansi::Action::Csi(csi)) => self.csi(&csi),
+            None => {}
+        }
+    }
+
+    fn csi(&mut self, csi: &ansi::Csi) {
+        if csi.private {
+            // DEC private modes, e.g. cursor visibility, aren't supported
+            return;
+        }
+        let n = csi.param(0, 1) as usize;
+        // A pending wrap is cancelled by any cursor movement
+        let column = self.column.min(BUFFER_WIDTH - 1);
+        match csi.final_byte {
+            b'A' => self.row = self.row.saturating_sub(n),
+            b'B' => self.row = (self.row + n).min(BUFFER_HEIGHT - 1),
+            b'C' => self.column = (column + n).min(BUFFER_WIDTH - 1),
+            b'D' => self.column = column.saturating_sub(n),
+            b'E' => {
+                self.row = (self.row + n).min(BUFFER_HEIGHT - 1);
+                self.column = 0;
+            }
+            b'F' => {
+                self.row = self.row.saturating_sub(n);
+                self.column = 0;
+            }
+            b'G' => self.column = (n - 1).min(BUFFER_WIDTH - 1),
+            b'd' => self.row = (n - 1).min(BUFFER_HEIGHT - 1),
+            b'H' | b'f' => {
+                self.row = (csi.param(0, 1) as usize - 1).min(BUFFER_HEIGHT - 1);
+                self.column = (csi.param(1, 1) as usize - 1).min(BUFFER_WIDTH - 1);
+            }
+            b'J' => self.erase_in_display(csi.param(0, 0)),
+            b'K' => self.erase_in_line(csi.param(0, 0)),
+            b'S' => {
+                for _ in 0..n {
+                    self.scroll_up();
+                }
+            }
+            b'T' => {
+                for _ in 0..n {
+                    self.scroll_down();
+                }
+            }
+            b'm' => self.select_graphic_rendition(csi.params()),
+            b'r' => {
+                let top = csi.param(0, 1) as usize - 1;
+                let bottom =
+                    (csi.param(1, BUFFER_HEIGHT as u16) as usize - 1).min(BUFFER_HEIGHT - 1);
+                if top < bottom {
+                    self.scroll_top = top;
+                    self.scroll_bottom = bottom;
+                    self.row = 0;
+                    self.column = 0;
+                }
+            }
+            b's' => self.saved = (self.row, self.column),
+            b'u' => self.restore_position(),
+            _ => {}
+        }
+    }
+
+    /// Apply an SGR sequence's parameters to our attributes.
+    fn select_graphic_rendition(&mut self, params: &[u16]) {
+        if params.is_empty() {
+            self.attrs = Attributes::DEFAULT;
+            return;
+        }
+        let mut i = 0;
+        while i < params.len() {
+            match params[i] {
+                0 => self.attrs = Attributes::DEFAULT,
+                1 => self.attrs.bold = true,
+                22 => self.attrs.bold = false,
+                7 => self.attrs.reverse = true,
+                27 => self.attrs.reverse = false,
+                p @ 30..=37 => self.attrs.fg = Color::from_ansi(p - 30, false),
+                39 => self.attrs.fg = Attributes::DEFAULT.fg,
+                p @ 40..=47 => self.attrs.bg = Color::from_ansi(p - 40, false),
+                49 => self.attrs.bg = Attributes::DEFAULT.bg,
+                p @ 90..=97 => self.attrs.fg = Color::from_ansi(p - 90, true),
+                p @ 100..=107 => self.attrs.bg = Color::from_ansi(p - 100, true),
+                // 256 color palette: only the first 16 entries map onto VGA colors
+                p @ 38 | p @ 48 if params.get(i + 1) == Some(&5) => {
+                    if let Some(&n) = params.get(i + 2) {
+                        if n < 16 {
+                            let color = Color::from_ansi(n % 8, n >= 8);
+                            if p == 38 {
+                                self.attrs.fg = color;
+                            } else {
+                                self.attrs.bg = color;
+                            }
+                        }
+                    }
+                    i += 2;
+                }
+                // 24-bit color, brought down to the nearest of the 16
+                p @ 38 | p @ 48 if params.get(i + 1) == Some(&2) => {
+                    if let Some(rgb) = params.get(i + 2..i + 5) {
+                        let color = Color::from_rgb(rgb[0], rgb[1], rgb[2]);
+                        if p == 38 {
+                            self.attrs.fg = color;
+                        } else {
+                            self.attrs.bg = color;
+                        }
+                    }
+                    i += 4;
+                }
+                _ => {}
+            }
+            i += 1;
+        }
+    }
+
+    fn restore_position(&mut self) {
+        let (row, col) = self.saved;
+        self.row = row;
+        self.column = col;
+    }
+
+    fn erase_in_display(&mut self, mode: u16) {
+        let column = self.column.min(BUFFER_WIDTH - 1);
+        match mode {
+            0 => {
+                self.clear_columns(self.row, column, BUFFER_WIDTH);
+                for row in self.row + 1..BUFFER_HEIGHT {
+                    self.clear_row(row);
+                }
+            }
+            1 => {
+                for row in 0..self.row {
+                    self.clear_row(row);
+                }
+                self.clear_columns(self.row, 0, column + 1);
+            }
+            _ => {
+                for row in 0..BUFFER_HEIGHT {
+                    self.clear_row(row);
+                }
+            }
+        }
+    }
+
+    fn erase_in_line(&mut self, mode: u16) {
+        let column = self.column.min(BUFFER_WIDTH - 1);
+        match mode {
+            0 => self.clear_columns(self.row, column, BUFFER_WIDTH),
+            1 => self.clear_columns(self.row, 0, column + 1),
+            _ => self.clear_row(self.row),
+        }
+    }
+
+    fn put_byte(&mut self, byte: u8) {
+        match byte {
+            b'\n' => self.new_line(),
+            b'\r' => self.column = 0,
+            0x08 => self.backspace(),
+            b'\t' => {
+                let next_stop = (self.column / TAB_WIDTH + 1) * TAB_WIDTH;
+                while self.column < next_stop.min(BUFFER_WIDTH) {
+                    self.put_byte(b' ');
+                }
+            }
+            byte => self.put_glyph(byte),
+        }
+    }
+
+    /// Draw CP437 glyph `glyph` at the cursor, even if it's in the control range.
+    fn put_glyph(&mut self, glyph: u8) {
+        if self.column >= BUFFER_WIDTH {
+            self.new_line();
+        }
+        self.write_char_at(
+            self.row,
+            self.column,
+            Char {
+                ascii: glyph,
+                color: self.attrs.color_code(),
+            },
+        );
+        self.column += 1;
+    }
+
+    fn write_char_at(&mut self, row: usize, col: usize, ch: Char) {
+        let offset = row * BUFFER_WIDTH + col;
+        self.cells[offset] = ch;
+        if self.is_active() && !self.viewing_history() {
+            unsafe {
+                put_screen(offset, ch.into());
+            }
+        }
+    }
+
+    /// What the cell at screen offset `offset` currently shows, live or from history.
+    fn displayed(&self, offset: usize) -> u16 {
+        let (row, col) = (offset / BUFFER_WIDTH, offset % BUFFER_WIDTH);
+        if let Some(sb) = self.scrollback.as_ref().filter(|sb| sb.offset > 0) {
+            let line = sb.len() - sb.offset + row;
+            if line < sb.len() {
+                return sb.line(line)[col];
+            }
+            return self.cells[(line - sb.len()) * BUFFER_WIDTH + col].into();
+        }
+        self.cells[offset].into()
+    }
+
+    fn read_char_at(&self, row: usize, col: usize) -> Char {
+        self.cells[row * BUFFER_WIDTH + col]
+    }
+
+    fn blank(&self) -> Char {
+        Char {
+            ascii: b' ',
+            color: self.attrs.color_code(),
+        }
+    }
+
+    /// Erase the character before the cursor, moving back onto the previous line if
+    /// we're at the start of one.
+    fn backspace(&mut self) {
+        if self.column > 0 {
+            self.column -= 1;
+        } else if self.row > 0 {
+            self.row -= 1;
+            self.column = BUFFER_WIDTH - 1;
+        } else {
+            return;
+        }
+        self.write_char_at(self.row, self.column, self.blank());
+    }
+
+    fn new_line(&mut self) {
+        if self.row == self.scroll_bottom {
+            self.scroll_up();
+        } else if self.row < BUFFER_HEIGHT - 1 {
+            self.row += 1;
+        }
+        self.column = 0;
+    }
+
+    /// Move every row in the scroll region up by one, discarding its top row.
+    fn scroll_up(&mut self) {
+        if self.scroll_top == 0 {
+            let mut line = [0; BUFFER_WIDTH];
+            for (col, cell) in line.iter_mut().enumerate() {
+                *cell = self.read_char_at(0, col).into();
+            }
+            if let Some(sb) = self.scrollback.as_mut() {
+                sb.push(line);
+            }
+        }
+        for row in self.scroll_top + 1..=self.scroll_bottom {
+            for col in 0..BUFFER_WIDTH {
+                let ch = self.read_char_at(row, col);
+                self.write_char_at(row - 1, col, ch);
+            }
+        }
+        self.clear_row(self.scroll_bottom);
+    }
+
+    /// Move every row in the scroll region down by one, discarding its bottom row.
+    fn scroll_down(&mut self) {
+        for row in (self.scroll_top..self.scroll_bottom).rev() {
+            for col in 0..BUFFER_WIDTH {
+                let ch = self.read_char_at(row, col);
+                self.write_char_at(row + 1, col, ch);
+            }
+        }
+        self.clear_row(self.scroll_top);
+    }
+
+    fn clear_row(&mut self, row: usize) {
+        self.clear_columns(row, 0, BUFFER_WIDTH);
+    }
+
+    /// Blank columns `start..end` of `row`.
+    fn clear_columns(&mut self, row: usize, start: usize, end: usize) {
+        let blank = self.blank();
+        for col in start..end {
+            self.write_char_at(row, col, blank);
+        }
+    }
+
+    /// Turn on the hardware cursor as an underline in the bottom two scanlines.
+    pub fn enable_cursor(&mut self) {
+        unsafe {
+            let start = crtc_read(CRTC_CURSOR_START);
+            crtc_write(CRTC_CURSOR_START, (start & 0xc0) | 14);
+            let end = crtc_read(CRTC_CURSOR_END);
+            crtc_write(CRTC_CURSOR_END, (end & 0xe0) | 15);
+        }
+    }
+
+    /// Move the blinking hardware cursor to our position.
+    fn update_cursor(&self) {
+        if !self.is_active() {
+            return;
+        }
+        // Past the last column the next character wraps, so show the cursor there
+        let (row, col) = if self.column >= BUFFER_WIDTH {
+            ((self.row + 1).min(BUFFER_HEIGHT - 1), 0)
+        } else {
+            (self.row, self.column)
+        };
+        let pos = (row * BUFFER_WIDTH + col) as u16;
+        unsafe {
+            crtc_write(CRTC_CURSOR_LOW, (pos & 0xff) as u8);
+            crtc_write(CRTC_CURSOR_HIGH, (pos >> 8) as u8);
+        }
+    }
+}
+
+/// Read CRT controller register `index`.
+///
+/// # Safety
+///
+/// Performs raw port I/O on the VGA controller.
+unsafe fn crtc_read(index: u8) -> u8 {
+    use x86_64::instructions::port::Port;
+    Port::new(CRTC_INDEX).write(index);
+    Port::new(CRTC_DATA).read()
+}
+
+/// Write `value` to CRT controller register `index`.
+///
+/// # Safety
+///
+/// Performs raw port I/O on the VGA controller.
+unsafe fn crtc_write(index: u8, value: u8) {
+    use x86_64::instructions::port::Port;
+    Port::new(CRTC_INDEX).write(index);
+    Port::new(CRTC_DATA).write(value);
+}
+
+impl fmt::Write for Writer {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.write_string(s);
+        Ok(())
+    }
+}
+
+#[test_case]
+fn test_char_from_to_u16() {
+    let c = Char {
+        ascii: b'!',
+        color: ColorCode::new(Color::Red, Color::Blue),
+    };
+    let c16: u16 = c.into();
+    assert_eq!(c, Char::from(c16));
+}
+
+#[test_case]
+fn test_println() {
+    println!("println should not panic");
+}
+
+#[test_case]
+fn test_scrolling() {
+    for _ in 0..200 {
+        println!("test_scrolling output");
+    }
+}
+
+#[test_case]
+fn test_println_output() {
+    use core::fmt::Write;
+    use x86_64::instructions::interrupts;
+    let s = "sentinel value";
+    interrupts::without_interrupts(|| {
+        let mut wr = CONSOLES[MAIN_CONSOLE].lock();
+        writeln!(wr, "\n{}", s).expect("writeln failed");
+        let (row, _) = wr.position();
+        for (i, c) in s.chars().enumerate() {
+            let screen_char = wr.read_char_at(row - 1, i).ascii;
+            assert_eq!(char::from(screen_char), c);
+        }
+    });
+}
+
+#[test_case]
+fn test_line_wraps_after_last_column() {
+    use x86_64::instructions::interrupts;
+    interrupts::without_interrupts(|| {
+        let mut wr = CONSOLES[MAIN_CONSOLE].lock();
+        wr.write_at(3, 0, "");
+        for _ in 0..BUFFER_WIDTH {
+            wr.write_byte(b'a');
+        }
+        assert_eq!(wr.position(), (3, BUFFER_WIDTH));
+        wr.write_byte(b'b');
+        assert_eq!(wr.position(), (4, 1));
+        assert_eq!(wr.read_char_at(3, BUFFER_WIDTH - 1).ascii, b'a');
+        assert_eq!(wr.read_char_at(4, 0).ascii, b'b');
+    });
+}
+
+#[test_case]
+fn test_control_characters() {
+    use x86_64::instructions::interrupts;
+    interrupts::without_interrupts(|| {
+        let mut wr = CONSOLES[MAIN_CONSOLE].lock();
+        wr.write_at(5, 0, "abc\x08\x08x");
+        assert_eq!(wr.position(), (5, 2));
+        assert_eq!(wr.read_char_at(5, 1).ascii, b'x');
+        assert_eq!(wr.read_char_at(5, 2).ascii, b' ');
+
+        wr.write_string("\rz\t");
+        assert_eq!(wr.read_char_at(5, 0).ascii, b'z');
+        assert_eq!(wr.position(), (5, TAB_WIDTH));
+
+        // Backspace at the start of a line moves onto the previous one
+        wr.write_at(6, 0, "\x08");
+        assert_eq!(wr.position(), (5, BUFFER_WIDTH - 1));
+    });
+}
+
+#[test_case]
+fn test_hardware_cursor_follows_writer() {
+    use x86_64::instructions::interrupts;
+    interrupts::without_interrupts(|| {
+        let mut wr = CONSOLES[MAIN_CONSOLE].lock();
+        wr.write_at(10, 20, "hi");
+        let pos = unsafe {
+            (crtc_read(CRTC_CURSOR_HIGH) as usize) << 8 | crtc_read(CRTC_CURSOR_LOW) as usize
+        };
+        assert_eq!(pos, 10 * BUFFER_WIDTH + 22);
+    });
+}
+
+#[test_case]
+fn test_clear_screen() {
+    use x86_64::instructions::interrupts;
+    interrupts::without_interrupts(|| {
+        let mut wr = CONSOLES[MAIN_CONSOLE].lock();
+        wr.write_at(12, 12, "to be cleared");
+        wr.clear_screen();
+        assert_eq!(wr.position(), (0, 0));
+        for row in 0..BUFFER_HEIGHT {
+            for col in 0..BUFFER_WIDTH {
+                assert_eq!(wr.read_char_at(row, col).ascii, b' ');
+            }
+        }
+    });
+}
+
+#[test_case]
+fn test_sgr_colors() {
+    use x86_64::instructions::interrupts;
+    interrupts::without_interrupts(|| {
+        let mut wr = CONSOLES[MAIN_CONSOLE].lock();
+        wr.write_at(7, 0, "\x1b[31;44mr\x1b[1mb\x1b[0;93;101mx\x1b[7mv\x1b[0mn");
+        let color = |col| wr.read_char_at(7, col).color;
+        assert_eq!(color(0), ColorCode::new(Color::Red, Color::Blue));
+        assert_eq!(color(1), ColorCode::new(Color::LightRed, Color::Blue));
+        assert_eq!(color(2), ColorCode::new(Color::Yellow, Color::LightRed));
+        assert_eq!(color(3), ColorCode::new(Color::LightRed, Color::Yellow));
+        assert_eq!(color(4), Attributes::DEFAULT.color_code());
+        assert_eq!(wr.read_char_at(7, 4).ascii, b'n');
+    });
+}
+
+#[test_case]
+fn test_sgr_truecolor() {
+    use x86_64::instructions::interrupts;
+    interrupts::without_interrupts(|| {
+        let mut wr = CONSOLES[MAIN_CONSOLE].lock();
+        // The color's values aren't taken as codes of their own, like 1 and 7 here
+        wr.write_at(
+            7,
+            0,
+            "\x1b[38;2;1;7;0ma\x1b[48;2;0;0;255;31mb\x1b[0;38;2;128;0mc\x1b[0m",
+        );
+        let default = Attributes::DEFAULT;
+        let color = |col| wr.read_char_at(7, col).color;
+        assert_eq!(color(0), ColorCode::new(Color::Black, default.bg));
+        assert_eq!(color(1), ColorCode::new(Color::Red, Color::LightBlue));
+        // Cut short, so nothing to set
+        assert_eq!(color(2), default.color_code());
+        assert_eq!(wr.read_char_at(7, 2).ascii, b'c');
+    });
+}
+
+#[test_case]
+fn test_cursor_movement_sequences() {
+    use x86_64::instructions::interrupts;
+    interrupts::without_interrupts(|| {
+        let mut wr = CONSOLES[MAIN_CONSOLE].lock();
+        wr.write_string("\x1b[3;10H");
+        assert_eq!(wr.position(), (2, 9));
+        wr.write_string("\x1b[2A\x1b[4C");
+        assert_eq!(wr.position(), (0, 13));
+        wr.write_string("\x1b[B\x1b[20D");
+        assert_eq!(wr.position(), (1, 0));
+        wr.write_string("\x1b[s\x1b[20;30H\x1b[u");
+        assert_eq!(wr.position(), (1, 0));
+        wr.write_string("\x1b[40G\x1b7\x1b[H\x1b8");
+        assert_eq!(wr.position(), (1, 39));
+    });
+}
+
+#[test_case]
+fn test_erase_sequences() {
+    use x86_64::instructions::interrupts;
+    interrupts::without_interrupts(|| {
+        let mut wr = CONSOLES[MAIN_CONSOLE].lock();
+        wr.write_at(8, 0, "0123456789\x1b[5G\x1b[K");
+        assert_eq!(wr.read_char_at(8, 3).ascii, b'3');
+        assert_eq!(wr.read_char_at(8, 4).ascii, b' ');
+
+        wr.write_at(8, 0, "0123456789\x1b[5G\x1b[1K");
+        assert_eq!(wr.read_char_at(8, 4).ascii, b' ');
+        assert_eq!(wr.read_char_at(8, 5).ascii, b'5');
+
+        wr.write_at(9, 0, "below\x1b[9;1H\x1b[J");
+        assert_eq!(wr.read_char_at(9, 0).ascii, b' ');
+    });
+}
+
+#[test_case]
+fn test_scroll_region() {
+    use x86_64::instructions::interrupts;
+    interrupts::without_interrupts(|| {
+        let mut wr = CONSOLES[MAIN_CONSOLE].lock();
+        wr.clear_screen();
+        wr.write_string("header\x1b[2;4r");
+        wr.write_string("\x1b[2;1Ha\nb\nc\nd");
+        // `a` scrolled out of rows 2-4 while the header stayed put
+        assert_eq!(wr.read_char_at(0, 0).ascii, b'h');
+        assert_eq!(wr.read_char_at(1, 0).ascii, b'b');
+        assert_eq!(wr.read_char_at(3, 0).ascii, b'd');
+        assert_eq!(wr.position(), (3, 1));
+        wr.write_string("\x1b[r");
+        assert_eq!(wr.scroll_bottom, BUFFER_HEIGHT - 1);
+    });
+}
+
+#[test_case]
+fn test_unicode_output() {
+    use x86_64::instructions::interrupts;
+    interrupts::without_interrupts(|| {
+        let mut wr = CONSOLES[MAIN_CONSOLE].lock();
+        wr.write_at(11, 0, "é│░°€☺");
+        let glyphs: [u8; 6] = [0x82, 0xb3, 0xb0, 0xf8, cp437::REPLACEMENT, 0x01];
+        for (col, &g) in glyphs.iter().enumerate() {
+            assert_eq!(wr.read_char_at(11, col).ascii, g);
+        }
+        assert_eq!(wr.position(), (11, glyphs.len()));
+    });
+}
+
+#[test_case]
+fn test_utf8_split_across_writes() {
+    use x86_64::instructions::interrupts;
+    interrupts::without_interrupts(|| {
+        let mut wr = CONSOLES[MAIN_CONSOLE].lock();
+        wr.set_position(12, 0);
+        for &b in "─".as_bytes() {
+            wr.write_byte(b);
+        }
+        // A truncated sequence is replaced and the following byte still printed
+        wr.write_byte(0xe2);
+        wr.write_byte(b'x');
+        assert_eq!(wr.read_char_at(12, 0).ascii, 0xc4);
+        assert_eq!(wr.read_char_at(12, 1).ascii, cp437::REPLACEMENT);
+        assert_eq!(wr.read_char_at(12, 2).ascii, b'x');
+    });
+}
+
+#[test_case]
+fn test_scrollback_view() {
+    use x86_64::instructions::interrupts;
+    let shown = |wr: &Writer, row: usize, col: usize| {
+        Char::from(wr.displayed(row * BUFFER_WIDTH + col)).ascii
+    };
+    interrupts::without_interrupts(|| {
+        let mut wr = CONSOLES[MAIN_CONSOLE].lock();
+        let before = wr.scrollback.take();
+        wr.enable_scrollback(2 * BUFFER_HEIGHT);
+        wr.clear_screen();
+        for i in 0..(BUFFER_HEIGHT + 10) {
+            wr.write_string(if i % 2 == 0 { "even\n" } else { "odd\n" });
+        }
+        // Lines 0-10 scrolled off; line 11 is at the top of the screen
+        assert_eq!(wr.scrollback.as_ref().unwrap().len(), 11);
+        assert_eq!(shown(&wr, 0, 0), b'o');
+
+        wr.scroll_view(1);
+        assert!(wr.viewing_history());
+        assert_eq!(shown(&wr, 0, 0), b'e');
+        assert_eq!(shown(&wr, 1, 0), b'o');
+        // The live screen underneath is untouched
+        assert_eq!(wr.read_char_at(0, 0).ascii, b'o');
+
+        // Can't scroll back further than the history we have
+        wr.scroll_view(1000);
+        assert_eq!(wr.scrollback.as_ref().unwrap().offset, 11);
+        assert_eq!(shown(&wr, 0, 0), b'e');
+
+        // Writing snaps back to the live screen
+        wr.write_string("x");
+        assert!(!wr.viewing_history());
+        assert_eq!(shown(&wr, 0, 0), b'o');
+        assert_eq!(shown(&wr, BUFFER_HEIGHT - 1, 0), b'x');
+        wr.scrollback = before;
+    });
+}
+
+#[test_case]
+fn test_virtual_consoles() {
+    use x86_64::instructions::interrupts;
+    let vga_char = |row: usize, col: usize| unsafe {
+        Char::from(VGA_BUFFER.add(row * BUFFER_WIDTH + col).read_volatile()).ascii
+    };
+    interrupts::without_interrupts(|| {
+        CONSOLES[MAIN_CONSOLE].lock().write_at(0, 0, "main");
+        let mut other = CONSOLES[1].lock();
+        other.clear_screen();
+        other.write_at(0, 0, "other");
+        assert_eq!(other.read_char_at(0, 0).ascii, b'o');
+    });
+    // Only the active console reaches the screen
+    assert_eq!(vga_char(0, 0), b'm');
+
+    switch_console(1);
+    assert_eq!(active_console(), 1);
+    assert_eq!(vga_char(0, 0), b'o');
+    println!("printed while console 1 is active");
+    assert_eq!(vga_char(0, 0), b'o');
+
+    switch_console(MAIN_CONSOLE);
+    assert_eq!(
+        vga_char(0, 0),
+        CONSOLES[MAIN_CONSOLE].lock().read_char_at(0, 0).ascii
+    );
+}
@@ -0,0 +1,56 @@
+//! History of lines that have scrolled off the top of the screen.
+
+use super::BUFFER_WIDTH;
+use alloc::vec::Vec;
+
+/// Default number of lines kept, about 16 KiB of heap per console.
+pub const DEFAULT_DEPTH: usize = 100;
+
+pub type Line = [u16; BUFFER_WIDTH];
+
+pub struct Scrollback {
+    /// Ring of saved lines, allocated up front so pushing never allocates.
+    lines: Vec<Line>,
+    depth: usize,
+    /// Index of the oldest line once `lines` has wrapped.
+    start: usize,
+    /// How many lines back from live output we're showing; 0 when live.
+    pub(super) offset: usize,
+}
+
+impl Scrollback {
+    pub fn new(depth: usize) -> Self {
+        Self {
+            lines: Vec::with_capacity(depth),
+            depth,
+            start: 0,
+            offset: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.lines.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lines.is_empty()
+    }
+
+    /// Save `line`, dropping the oldest one if we're full.
+    pub fn push(&mut self, line: Line) {
+        if self.depth == 0 {
+            return;
+        }
+        if self.lines.len() < self.depth {
+            self.lines.push(line);
+        } else {
+            self.lines[self.start] = line;
+            self.start = (self.start + 1) % self.depth;
+        }
+    }
+
+    /// The `i`th saved line, oldest first.
+    pub fn line(&self, i: usize) -> &Line {
+        &self.lines[(self.start + i) % self.lines.len()]
+    }
+}
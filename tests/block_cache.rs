@@ -0,0 +1,166 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(firstos::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+use alloc::{boxed::Box, sync::Arc, vec::Vec};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use firstos::block::cache::{BlockCache, CachedDevice};
+use firstos::block::{BlockDevice, BlockError, RamDisk};
+use spin::Mutex;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use firstos::allocator;
+    use firstos::memory::{self, ListFrameAllocator};
+    use x86_64::VirtAddr;
+
+    firstos::init();
+
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { ListFrameAllocator::init(&boot_info) };
+    allocator::init(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    firstos::test_panic_handler(info)
+}
+
+const BLOCK_SIZE: usize = 512;
+
+#[derive(Default)]
+struct Counters {
+    reads: u64,
+    writes: u64,
+}
+
+/// A RAM disk that counts the I/O actually reaching it.
+struct CountingDisk {
+    disk: Arc<Mutex<RamDisk>>,
+    counters: Arc<Mutex<Counters>>,
+}
+
+impl BlockDevice for CountingDisk {
+    fn block_size(&self) -> usize {
+        self.disk.lock().block_size()
+    }
+
+    fn block_count(&self) -> u64 {
+        self.disk.lock().block_count()
+    }
+
+    fn read_block(&mut self, block: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        self.counters.lock().reads += 1;
+        self.disk.lock().read_block(block, buf)
+    }
+
+    fn write_block(&mut self, block: u64, buf: &[u8]) -> Result<(), BlockError> {
+        self.counters.lock().writes += 1;
+        self.disk.lock().write_block(block, buf)
+    }
+}
+
+fn counting_disk(blocks: usize) -> (CountingDisk, Arc<Mutex<RamDisk>>, Arc<Mutex<Counters>>) {
+    let disk = Arc::new(Mutex::new(RamDisk::new(BLOCK_SIZE, blocks)));
+    let counters = Arc::new(Mutex::new(Counters::default()));
+    let dev = CountingDisk {
+        disk: disk.clone(),
+        counters: counters.clone(),
+    };
+    (dev, disk, counters)
+}
+
+#[test_case]
+fn test_hits_avoid_device_reads() {
+    let (dev, _, counters) = counting_disk(16);
+    let mut cache = BlockCache::new(8 * BLOCK_SIZE);
+    let id = cache.register(Box::new(dev));
+    for _ in 0..10 {
+        let buf = cache.get(id, 3).unwrap();
+        assert_eq!(buf.read()[0], 0);
+    }
+    assert_eq!(counters.lock().reads, 1);
+    let stats = cache.stats();
+    assert_eq!((stats.hits, stats.misses), (9, 1));
+}
+
+#[test_case]
+fn test_write_back_on_flush() {
+    let (dev, disk, counters) = counting_disk(16);
+    let mut cache = BlockCache::new(8 * BLOCK_SIZE);
+    let id = cache.register(Box::new(dev));
+    {
+        let buf = cache.get(id, 5).unwrap();
+        buf.write()[0] = 42;
+        assert!(buf.is_dirty());
+    }
+    assert_eq!(counters.lock().writes, 0, "writes should be deferred");
+
+    cache.flush().unwrap();
+    assert_eq!(counters.lock().writes, 1);
+    let mut block = [0; BLOCK_SIZE];
+    disk.lock().read_block(5, &mut block).unwrap();
+    assert_eq!(block[0], 42);
+
+    // Clean buffers aren't written again
+    cache.flush().unwrap();
+    assert_eq!(counters.lock().writes, 1);
+}
+
+#[test_case]
+fn test_lru_eviction_respects_budget() {
+    let (dev, disk, _) = counting_disk(64);
+    let mut cache = BlockCache::new(4 * BLOCK_SIZE);
+    let id = cache.register(Box::new(dev));
+    cache.get(id, 0).unwrap().write()[0] = 7;
+    for block in 1..16 {
+        cache.get(id, block).unwrap();
+        assert!(cache.used() <= cache.budget());
+    }
+    let stats = cache.stats();
+    assert_eq!(stats.evictions, 12);
+    // Block 0 was least recently used and dirty, so eviction wrote it back
+    assert_eq!(stats.writebacks, 1);
+    let mut block = [0; BLOCK_SIZE];
+    disk.lock().read_block(0, &mut block).unwrap();
+    assert_eq!(block[0], 7);
+}
+
+#[test_case]
+fn test_referenced_buffers_are_not_evicted() {
+    let (dev, _, _) = counting_disk(64);
+    let mut cache = BlockCache::new(2 * BLOCK_SIZE);
+    let id = cache.register(Box::new(dev));
+    let held: Vec<_> = (0..6).map(|b| cache.get(id, b).unwrap()).collect();
+    assert_eq!(cache.used(), 6 * BLOCK_SIZE);
+    assert_eq!(cache.stats().evictions, 0);
+
+    drop(held);
+    cache.get(id, 10).unwrap();
+    assert_eq!(cache.used(), 2 * BLOCK_SIZE);
+}
+
+#[test_case]
+fn test_cached_device() {
+    let (dev, _, counters) = counting_disk(16);
+    let cache = Mutex::new(BlockCache::new(8 * BLOCK_SIZE));
+    let id = cache.lock().register(Box::new(dev));
+    let mut cached = CachedDevice::new(&cache, id);
+    let data = [0xab; BLOCK_SIZE];
+    cached.write_block(2, &data).unwrap();
+    let mut back = [0; BLOCK_SIZE];
+    cached.read_block(2, &mut back).unwrap();
+    assert_eq!(back[..], data[..]);
+    assert_eq!(counters.lock().writes, 0);
+    cache.lock().flush().unwrap();
+    assert_eq!(counters.lock().writes, 1);
+}
@@ -0,0 +1,138 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(firstos::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+use alloc::vec::Vec;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use firstos::block::RamDisk;
+use firstos::fs::ext2::{Error, Ext2, FileType, ROOT_INODE};
+
+// Regenerate with tests/images/mkimages.sh
+static IMAGE_1K: &[u8] = include_bytes!("images/ext2-1k.img");
+static IMAGE_4K: &[u8] = include_bytes!("images/ext2-4k.img");
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use firstos::allocator;
+    use firstos::memory::{self, ListFrameAllocator};
+    use x86_64::VirtAddr;
+
+    firstos::init();
+
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { ListFrameAllocator::init(&boot_info) };
+    allocator::init(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    firstos::test_panic_handler(info)
+}
+
+fn mount(image: &'static [u8]) -> Ext2<RamDisk> {
+    Ext2::mount(RamDisk::from_static(512, image)).expect("mount failed")
+}
+
+fn check_regular_file(image: &'static [u8]) {
+    let mut fs = mount(image);
+    let hello = fs.open("/hello.txt").unwrap();
+    assert_eq!(hello.file_type(), FileType::Regular);
+    assert_eq!(fs.read_to_vec(&hello).unwrap(), b"Hello from ext2!\n");
+    assert_eq!(fs.open("/missing").unwrap_err(), Error::NotFound);
+    // A size past the blocks the file has isn't believed
+    let mut huge = hello;
+    huge.size = 1 << 40;
+    assert_eq!(fs.read_to_vec(&huge), Err(Error::Corrupt));
+}
+
+fn check_directories(image: &'static [u8]) {
+    let mut fs = mount(image);
+    let root = fs.read_inode(ROOT_INODE).unwrap();
+    let names: Vec<_> = fs.read_dir(&root).unwrap().into_iter().map(|e| e.name).collect();
+    for name in &[".", "..", "hello.txt", "dir", "link", "longlink", "sparse.bin"] {
+        assert!(names.iter().any(|n| n == name), "missing {}", name);
+    }
+    // `dir` spans several blocks on the 1 KiB image
+    let dir = fs.open("/dir").unwrap();
+    assert_eq!(fs.read_dir(&dir).unwrap().len(), 2 + 2 + 60);
+}
+
+fn check_symlinks(image: &'static [u8]) {
+    let mut fs = mount(image);
+    let hello = fs.open("/hello.txt").unwrap();
+    let link = fs.open_nofollow("/link").unwrap();
+    assert_eq!(fs.read_link(&link).unwrap(), "hello.txt");
+    assert_eq!(fs.open("/link").unwrap().number, hello.number);
+    assert_eq!(fs.open("/dir/up").unwrap().number, hello.number);
+    // Longer than 60 bytes, so stored in a data block rather than the inode
+    let nested = fs.open("/longlink").unwrap();
+    assert_eq!(fs.read_to_vec(&nested).unwrap(), b"nested\n");
+}
+
+fn check_block_map(image: &'static [u8]) {
+    let mut fs = mount(image);
+    let bs = fs.block_size() as u64;
+    let per_block = bs / 4;
+    let sparse = fs.open("/sparse.bin").unwrap();
+    let markers: [(u64, &[u8]); 4] = [
+        (0, b"DIRECT"),
+        (12, b"INDIRECT"),
+        (12 + per_block, b"DOUBLE"),
+        (12 + per_block + per_block * per_block, b"TRIPLE"),
+    ];
+    for (block, marker) in markers.iter() {
+        let mut buf = [0; 8];
+        let n = fs.read(&sparse, block * bs, &mut buf[..marker.len()]).unwrap();
+        assert_eq!(&buf[..n], *marker);
+    }
+    // Holes read back as zeroes
+    let mut buf = [0xff; 16];
+    fs.read(&sparse, bs + 7, &mut buf).unwrap();
+    assert_eq!(buf, [0; 16]);
+}
+
+#[test_case]
+fn test_mount_block_sizes() {
+    assert_eq!(mount(IMAGE_1K).block_size(), 1024);
+    assert_eq!(mount(IMAGE_4K).block_size(), 4096);
+}
+
+#[test_case]
+fn test_regular_file() {
+    check_regular_file(IMAGE_1K);
+    check_regular_file(IMAGE_4K);
+}
+
+#[test_case]
+fn test_directories() {
+    check_directories(IMAGE_1K);
+    check_directories(IMAGE_4K);
+}
+
+#[test_case]
+fn test_symlinks() {
+    check_symlinks(IMAGE_1K);
+    check_symlinks(IMAGE_4K);
+}
+
+#[test_case]
+fn test_block_map() {
+    check_block_map(IMAGE_1K);
+    check_block_map(IMAGE_4K);
+}
+
+#[test_case]
+fn test_bad_magic() {
+    static ZEROES: [u8; 4096] = [0; 4096];
+    let fs = Ext2::mount(RamDisk::from_static(512, &ZEROES));
+    assert_eq!(fs.err(), Some(Error::BadMagic));
+}
@@ -0,0 +1,161 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(firstos::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+use alloc::vec::Vec;
+use bootloader::{entry_point, BootInfo};
+use core::alloc::{GlobalAlloc, Layout};
+use core::arch::x86_64::_rdtsc;
+use core::panic::PanicInfo;
+use core::ptr;
+use firstos::serial_println;
+use firstos_heap::linked_list::{Allocator, Policy};
+use firstos_heap::testing::Rng;
+use firstos_heap::Locked;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use firstos::allocator;
+    use firstos::memory::{self, ListFrameAllocator};
+    use x86_64::VirtAddr;
+
+    firstos::init();
+
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { ListFrameAllocator::init(&boot_info) };
+    allocator::init(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    firstos::test_panic_handler(info)
+}
+
+const ARENA_SIZE: usize = 256 * 1024;
+const OPERATIONS: usize = 10_000;
+const MAX_LIVE: usize = 512;
+
+#[repr(align(4096))]
+struct Arena([u8; ARENA_SIZE]);
+
+/// Shared by each run in turn; the tests run one at a time.
+static mut ARENA: Arena = Arena([0; ARENA_SIZE]);
+
+/// Mostly small objects, now and then a buffer of up to 16 KiB.
+fn object_size(rng: &mut Rng) -> usize {
+    match rng.below(16) {
+        0 => 1024 + rng.below(15 * 1024),
+        1..=4 => 128 + rng.below(896),
+        _ => 8 + rng.below(120),
+    }
+}
+
+#[derive(Default)]
+struct Report {
+    failures: usize,
+    in_place: usize,
+    moved: usize,
+    regions: usize,
+    largest: usize,
+    free: usize,
+    cycles: u64,
+}
+
+/// Run the workload with `policy`, free what's left and check it all merges back.
+fn run(policy: Policy) -> Report {
+    let alloc = Locked::new(Allocator::empty());
+    let start = unsafe { ptr::addr_of_mut!(ARENA) as usize };
+    unsafe { alloc.lock().init_with_policy(start, ARENA_SIZE, policy) };
+
+    // The same seed, so every policy sees the same workload
+    let mut rng = Rng::new(1);
+    let mut live: Vec<(*mut u8, Layout)> = Vec::with_capacity(MAX_LIVE);
+    let mut report = Report::default();
+    let begin = unsafe { _rdtsc() };
+    for _ in 0..OPERATIONS {
+        match rng.below(8) {
+            0..=3 if live.len() < MAX_LIVE => {
+                let layout = Layout::from_size_align(object_size(&mut rng), 8).unwrap();
+                let ptr = unsafe { alloc.alloc(layout) };
+                if ptr.is_null() {
+                    report.failures += 1;
+                } else {
+                    live.push((ptr, layout));
+                }
+            }
+            4 if !live.is_empty() => {
+                let (ptr, layout) = live[rng.below(live.len())];
+                let new_size = layout.size() + rng.below(layout.size() + 1);
+                let new_ptr = unsafe { alloc.realloc(ptr, layout, new_size) };
+                if new_ptr.is_null() {
+                    report.failures += 1;
+                    continue;
+                } else if new_ptr == ptr {
+                    report.in_place += 1;
+                } else {
+                    report.moved += 1;
+                }
+                let index = live.iter().position(|&(p, _)| p == ptr).unwrap();
+                live[index] = (new_ptr, Layout::from_size_align(new_size, 8).unwrap());
+            }
+            _ if !live.is_empty() => {
+                let (ptr, layout) = live.swap_remove(rng.below(live.len()));
+                unsafe { alloc.dealloc(ptr, layout) };
+            }
+            _ => {}
+        }
+    }
+    report.cycles = unsafe { _rdtsc() } - begin;
+
+    for (_, region) in alloc.lock().free_regions() {
+        report.regions += 1;
+        report.largest = report.largest.max(region);
+        report.free += region;
+    }
+
+    for (ptr, layout) in live {
+        unsafe { alloc.dealloc(ptr, layout) };
+    }
+    let regions: Vec<_> = alloc.lock().free_regions().collect();
+    assert_eq!(regions, [(start, ARENA_SIZE)]);
+    report
+}
+
+firstos::kernel_test! {
+    // Four runs of list walks, slow under emulation
+    #[timeout_ms = 20_000]
+    fn test_fragmentation_by_policy() {
+        serial_println!();
+        for &policy in &[
+            Policy::FirstFit,
+            Policy::BestFit,
+            Policy::NextFit,
+            Policy::WorstFit,
+        ] {
+            let r = run(policy);
+            // How much of the free memory is out of reach of the biggest request it could take
+            let fragmentation = 100 - r.largest * 100 / r.free.max(1);
+            serial_println!(
+                "{:?}: {:>4} failed, realloc {:>4} in place {:>4} moved, {:>4} free regions, \
+                 largest {:>6} of {:>6} free ({:>2}% fragmented), {:>10} cycles",
+                policy,
+                r.failures,
+                r.in_place,
+                r.moved,
+                r.regions,
+                r.largest,
+                r.free,
+                fragmentation,
+                r.cycles
+            );
+        }
+    }
+}
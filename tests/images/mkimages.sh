@@ -0,0 +1,44 @@
+#!/usr/bin/env bash
+# Regenerate the ext2 images used by tests/ext2.rs.
+set -euo pipefail
+
+cd "$(dirname "$0")"
+
+mkimage() {
+    local bs=$1 out=$2
+    local root
+    root=$(mktemp -d)
+    trap 'rm -rf "$root"' RETURN
+
+    mkdir -p "$root/dir"
+    printf 'Hello from ext2!\n' > "$root/hello.txt"
+    printf 'nested\n' > "$root/dir/nested.txt"
+    ln -s hello.txt "$root/link"
+    ln -s ../hello.txt "$root/dir/up"
+    # Too long to fit inside the inode
+    ln -s dir/../dir/../dir/../dir/../dir/../dir/../dir/../dir/nested.txt "$root/longlink"
+    # Enough entries to spill the directory over several 1 KiB blocks
+    for i in $(seq 1 60); do
+        touch "$root/dir/entry_with_a_longer_name_$i"
+    done
+
+    # A sparse file with a marker in the first block reached through each of the
+    # direct, indirect, double and triple indirect maps
+    python3 - "$root/sparse.bin" "$bs" <<'PY'
+import sys
+path, bs = sys.argv[1], int(sys.argv[2])
+ppb = bs // 4
+markers = [(0, b"DIRECT"), (12, b"INDIRECT"), (12 + ppb, b"DOUBLE"),
+           (12 + ppb + ppb * ppb, b"TRIPLE")]
+with open(path, "wb") as f:
+    for block, marker in markers:
+        f.seek(block * bs)
+        f.write(marker)
+PY
+
+    rm -f "$out"
+    mke2fs -q -t ext2 -b "$bs" -d "$root" -N 128 -m 0 "$out" 256K
+}
+
+mkimage 1024 ext2-1k.img
+mkimage 4096 ext2-4k.img
@@ -1,25 +1,31 @@
 #![no_std]
 #![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(firstos::test_runner)]
+#![reexport_test_harness_main = "test_main"]
 
 use core::panic::PanicInfo;
-use firstos::{qemu, serial_print, serial_println};
+use firstos::kernel_test;
 
 #[no_mangle]
 pub extern "C" fn _start() -> ! {
-    should_fail();
-    serial_println!("[test did not panic]");
-    qemu::exit(qemu::ExitCode::Failure);
+    test_main();
     loop {}
 }
 
-fn should_fail() {
-    serial_print!("should_panic::should_fail...\t");
-    assert_eq!(0, 1);
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    firstos::test_panic_handler(info)
 }
 
-#[panic_handler]
-fn panic(_info: &PanicInfo) -> ! {
-    serial_println!("[ok]");
-    qemu::exit(qemu::ExitCode::Success);
-    loop {}
+kernel_test! {
+    #[should_panic]
+    fn should_fail() {
+        assert_eq!(0, 1);
+    }
+}
+
+#[test_case]
+fn runs_after_a_panic() {
+    assert_eq!(1, 1);
 }